@@ -0,0 +1,13 @@
+extern crate cc;
+
+fn main() {
+    // native/udt4 holds the headers of the UDT sources libudt4-sys builds, which the fixed-rate
+    // congestion control is compiled against
+    cc::Build::new()
+        .cpp(true)
+        .include("native/udt4")
+        .file("native/fixed_rate.cpp")
+        .warnings(true)
+        .compile("petty_ccc");
+    println!("cargo:rerun-if-changed=native");
+}
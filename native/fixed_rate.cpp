@@ -0,0 +1,54 @@
+// Congestion control for UDT channels that send at a fixed rate.
+//
+// UDT takes custom congestion control as a C++ factory through the UDT_CC socket option, so the
+// controller lives here; src/transport/udt.rs calls the two functions below.
+
+#include "ccc.h"
+
+namespace {
+
+// Sends one packet every period, where the period is the time `bytes_per_sec` allows for a
+// full-sized packet, headers included. Losses and timeouts leave the rate alone.
+class FixedRateCC: public CCC
+{
+public:
+   explicit FixedRateCC(double bytes_per_sec): m_dBytesPerSec(bytes_per_sec) {}
+
+   virtual void init()
+   {
+      // Pacing alone limits sending; the window only needs to cover the peer's flow window
+      m_dCWndSize = m_dMaxCWndSize;
+      m_dPktSndPeriod = m_iMSS * 1000000.0 / m_dBytesPerSec;
+   }
+
+private:
+   double m_dBytesPerSec;
+};
+
+class FixedRateFactory: public CCCVirtualFactory
+{
+public:
+   explicit FixedRateFactory(double bytes_per_sec): m_dBytesPerSec(bytes_per_sec) {}
+
+   virtual CCC* create() {return new FixedRateCC(m_dBytesPerSec);}
+   virtual CCCVirtualFactory* clone() {return new FixedRateFactory(m_dBytesPerSec);}
+
+private:
+   double m_dBytesPerSec;
+};
+
+}
+
+// Both install a controller on a socket that hasn't started connecting, returning UDT's
+// setsockopt result. Sockets accepted by a listener inherit its controller.
+extern "C" int petty_udt_set_fixed_rate(UDTSOCKET u, double bytes_per_sec)
+{
+   FixedRateFactory factory(bytes_per_sec);
+   return UDT::setsockopt(u, 0, UDT_CC, &factory, sizeof(factory));
+}
+
+extern "C" int petty_udt_set_native_cc(UDTSOCKET u)
+{
+   CCCFactory<CUDTCC> factory;
+   return UDT::setsockopt(u, 0, UDT_CC, &factory, sizeof(factory));
+}
@@ -0,0 +1,521 @@
+use attribute::AttributeKey;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Bits of an IPv4-mapped IPv6 address ahead of the IPv4 one
+const MAPPED_PREFIX: u8 = 96;
+
+pub(crate) const PERMIT: AttributeKey<Permit> = AttributeKey::new("petty.accept.permit");
+
+/// Limits an acceptor places on the peers it accepts.
+///
+/// A peer over any limit is closed as soon as it is accepted, without being registered, and
+/// reported with `StateEvent::Rejected`. Peers count against the limits until they are
+/// deregistered.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct AcceptLimits {
+    // Peers of the acceptor open at once
+    pub max_connections: Option<usize>,
+    // Peers of the acceptor open at once from a single IP address
+    pub max_per_ip: Option<usize>,
+    pub rate: Option<AcceptRate>,
+}
+
+/// Accepts at most `accepts` peers per `per`, in bursts of up to `accepts`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AcceptRate {
+    pub accepts: u32,
+    pub per: Duration,
+}
+
+/// Allow and deny rules for the addresses of accepted peers.
+///
+/// A peer matching any deny rule is rejected. If there are allow rules, a peer must also match
+/// one of them. IPv4 peers reported as IPv4-mapped IPv6 addresses are matched as IPv4.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IpFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+/// A block of IPv4 or IPv6 addresses, such as `10.0.0.0/8` or `fe80::/10`.
+///
+/// Blocks of IPv4-mapped IPv6 addresses, such as `::ffff:10.0.0.0/104`, are kept in their IPv4
+/// form, since that is how mapped peers are matched.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// Why a CIDR block couldn't be built or parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CidrError {
+    /// The address is neither IPv4 nor IPv6.
+    Address(String),
+    /// The prefix length is not a number or is longer than the address.
+    Prefix(String),
+}
+
+/// Why an accepted peer was closed straight away.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The peer's address is not allowed by the acceptor's `IpFilter`.
+    Denied,
+    /// The acceptor already has `max_connections` peers open.
+    TooManyConnections,
+    /// The peer's address already has `max_per_ip` peers open.
+    TooManyFromIp,
+    /// Peers arrived faster than the accept rate allows.
+    RateLimited,
+}
+
+// Enforces an acceptor's limits; shared with the permits of the peers it let in
+#[derive(Debug, Clone)]
+pub(crate) struct AcceptGate {
+    limits: AcceptLimits,
+    state: Arc<Mutex<GateState>>,
+}
+
+#[derive(Debug)]
+struct GateState {
+    open: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    // Token bucket for the accept rate
+    tokens: f64,
+    refilled: Instant,
+}
+
+// Held in an accepted peer's attributes; gives its place back when the peer is deregistered
+pub(crate) struct Permit {
+    state: Arc<Mutex<GateState>>,
+    ip: IpAddr,
+}
+
+// impl AcceptLimits
+impl AcceptLimits {
+    pub fn new() -> Self {
+        AcceptLimits::default()
+    }
+
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn with_max_per_ip(mut self, max: usize) -> Self {
+        self.max_per_ip = Some(max);
+        self
+    }
+
+    pub fn with_rate(mut self, accepts: u32, per: Duration) -> Self {
+        self.rate = Some(AcceptRate { accepts, per });
+        self
+    }
+}
+
+// impl IpFilter
+impl IpFilter {
+    pub fn new() -> Self {
+        IpFilter::default()
+    }
+
+    pub fn with_allow(mut self, cidr: Cidr) -> Self {
+        self.allow.push(cidr);
+        self
+    }
+
+    pub fn with_deny(mut self, cidr: Cidr) -> Self {
+        self.deny.push(cidr);
+        self
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+// impl Cidr
+impl Cidr {
+    /// The block of addresses sharing the first `prefix` bits of `addr`.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, CidrError> {
+        match addr {
+            IpAddr::V4(v4) if prefix <= 32 => {
+                let addr = IpAddr::V4((u32::from(v4) & mask_v4(prefix)).into());
+                Ok(Cidr { addr, prefix })
+            }
+            IpAddr::V6(v6) if prefix <= 128 => {
+                let v6: Ipv6Addr = (u128::from(v6) & mask_v6(prefix)).into();
+                match v6.to_ipv4_mapped() {
+                    Some(v4) if prefix >= MAPPED_PREFIX => {
+                        Cidr::new(IpAddr::V4(v4), prefix - MAPPED_PREFIX)
+                    }
+                    _ => Ok(Cidr {
+                        addr: IpAddr::V6(v6),
+                        prefix,
+                    }),
+                }
+            }
+            _ => Err(CidrError::Prefix(format!("{}/{}", addr, prefix))),
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, unmapped(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                u32::from(ip) & mask_v4(self.prefix) == u32::from(net)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(ip) & mask_v6(self.prefix) == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses `addr/prefix`; a bare address is a block of just that address.
+impl FromStr for Cidr {
+    type Err = CidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.find('/') {
+            Some(slash) => (&s[..slash], Some(&s[slash + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| CidrError::Address(s.to_owned()))?;
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .map_err(|_| CidrError::Prefix(s.to_owned()))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// impl CidrError
+impl fmt::Display for CidrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CidrError::Address(ref s) => write!(f, "invalid address in CIDR block {:?}", s),
+            CidrError::Prefix(ref s) => write!(f, "invalid prefix length in CIDR block {:?}", s),
+        }
+    }
+}
+
+impl error::Error for CidrError {}
+
+// impl AcceptGate
+impl AcceptGate {
+    pub(crate) fn new(limits: AcceptLimits) -> Self {
+        AcceptGate::starting_at(limits, Instant::now())
+    }
+
+    // A gate whose token bucket is full as of `now`
+    fn starting_at(limits: AcceptLimits, now: Instant) -> Self {
+        let tokens = limits.rate.map_or(0.0, |rate| f64::from(rate.accepts));
+        let state = GateState {
+            open: 0,
+            per_ip: HashMap::new(),
+            tokens,
+            refilled: now,
+        };
+        AcceptGate {
+            limits,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Lets in a peer from `ip`, unless that would break one of the limits.
+    pub(crate) fn admit(&self, ip: IpAddr, now: Instant) -> Result<Permit, RejectReason> {
+        // Peers reaching an IPv6 acceptor over IPv4 share their IPv4 address's budget
+        let ip = unmapped(ip);
+        let mut state = self.state.lock().expect("Poisoned accept gate");
+        if self
+            .limits
+            .max_connections
+            .is_some_and(|max| state.open >= max)
+        {
+            return Err(RejectReason::TooManyConnections);
+        }
+        let from_ip = state.per_ip.get(&ip).cloned().unwrap_or(0);
+        if self.limits.max_per_ip.is_some_and(|max| from_ip >= max) {
+            return Err(RejectReason::TooManyFromIp);
+        }
+        if let Some(rate) = self.limits.rate {
+            state.refill(rate, now);
+            if state.tokens < 1.0 {
+                return Err(RejectReason::RateLimited);
+            }
+            state.tokens -= 1.0;
+        }
+        state.open += 1;
+        *state.per_ip.entry(ip).or_insert(0) += 1;
+        Ok(Permit {
+            state: self.state.clone(),
+            ip,
+        })
+    }
+}
+
+// impl GateState
+impl GateState {
+    fn refill(&mut self, rate: AcceptRate, now: Instant) {
+        let burst = f64::from(rate.accepts);
+        let elapsed = now.saturating_duration_since(self.refilled);
+        self.refilled = self.refilled.max(now);
+        if rate.per == Duration::from_secs(0) {
+            self.tokens = burst;
+            return;
+        }
+        let refill = burst * elapsed.as_secs_f64() / rate.per.as_secs_f64();
+        self.tokens = (self.tokens + refill).min(burst);
+    }
+}
+
+// impl Permit
+impl Drop for Permit {
+    fn drop(&mut self) {
+        // The peer is going away either way; if the gate is poisoned, its counts no longer
+        // matter enough to panic over
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        state.open = state.open.saturating_sub(1);
+        let remaining = match state.per_ip.get_mut(&self.ip) {
+            Some(n) => {
+                *n = n.saturating_sub(1);
+                *n
+            }
+            None => return,
+        };
+        if remaining == 0 {
+            state.per_ip.remove(&self.ip);
+        }
+    }
+}
+
+// The IPv4 address behind an IPv4-mapped IPv6 one, or `ip` itself
+fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn mask_v4(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+}
+
+fn mask_v6(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().expect("valid CIDR block")
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().expect("valid address")
+    }
+
+    #[test]
+    fn blocks_parse_without_host_bits() {
+        let v4 = cidr("10.1.2.3/8");
+        assert_eq!((v4.addr(), v4.prefix()), (ip("10.0.0.0"), 8));
+        assert_eq!(v4.to_string(), "10.0.0.0/8");
+        let v6 = cidr("fe80::1:2/10");
+        assert_eq!((v6.addr(), v6.prefix()), (ip("fe80::"), 10));
+        assert_eq!(v6.to_string(), "fe80::/10");
+        assert_eq!(cidr(&v6.to_string()), v6);
+
+        // Bare addresses are blocks of one
+        assert_eq!(cidr("192.168.0.1"), cidr("192.168.0.1/32"));
+        assert_eq!(cidr("::1"), cidr("::1/128"));
+        assert_eq!(cidr("0.0.0.0/0").addr(), ip("0.0.0.0"));
+    }
+
+    #[test]
+    fn bad_blocks_are_rejected() {
+        for bad in &[
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/x",
+            "10.0.0.0/-1",
+        ] {
+            let err = bad.parse::<Cidr>().err();
+            assert_eq!(err, Some(CidrError::Prefix(bad.to_string())), "{}", bad);
+        }
+        for bad in &["", "10.0.0/8", "10.0.0.256", "fe80:::1/10", "host/8"] {
+            let err = bad.parse::<Cidr>().err();
+            assert_eq!(err, Some(CidrError::Address(bad.to_string())), "{}", bad);
+        }
+        assert!(Cidr::new(ip("10.0.0.0"), 33).is_err());
+    }
+
+    #[test]
+    fn mapped_blocks_are_kept_as_ipv4() {
+        assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+        assert_eq!(cidr("::ffff:10.1.2.3"), cidr("10.1.2.3/32"));
+        assert_eq!(cidr("::ffff:0:0/96"), cidr("0.0.0.0/0"));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("::ffff:10.9.9.9")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.9.9.9")));
+        // Shorter prefixes reach past the mapped range and stay IPv6
+        assert!(cidr("::ffff:0:0/95").addr().is_ipv6());
+    }
+
+    #[test]
+    fn blocks_contain_their_addresses_only() {
+        let v4 = cidr("10.0.0.0/8");
+        assert!(v4.contains(ip("10.0.0.0")));
+        assert!(v4.contains(ip("10.255.255.255")));
+        assert!(!v4.contains(ip("11.0.0.0")));
+        assert!(!v4.contains(ip("9.255.255.255")));
+        assert!(!v4.contains(ip("a00::")));
+
+        let one = cidr("192.168.1.1");
+        assert!(one.contains(ip("192.168.1.1")));
+        assert!(!one.contains(ip("192.168.1.2")));
+
+        let any = cidr("0.0.0.0/0");
+        assert!(any.contains(ip("255.255.255.255")));
+        assert!(!any.contains(ip("::1")));
+
+        let v6 = cidr("fe80::/10");
+        assert!(v6.contains(ip("fe80::1")));
+        assert!(v6.contains(ip("febf:ffff::")));
+        assert!(!v6.contains(ip("fec0::")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn deny_rules_win_over_allow_rules() {
+        assert!(IpFilter::new().is_empty());
+        assert!(IpFilter::new().allows(ip("203.0.113.7")));
+
+        let filter = IpFilter::new()
+            .with_allow(cidr("10.0.0.0/8"))
+            .with_deny(cidr("10.0.0.0/24"));
+        assert!(!filter.is_empty());
+        assert!(filter.allows(ip("10.1.0.1")));
+        assert!(filter.allows(ip("::ffff:10.1.0.1")));
+        assert!(!filter.allows(ip("10.0.0.1")));
+        // With allow rules, anything they don't match is rejected
+        assert!(!filter.allows(ip("192.168.0.1")));
+        assert!(!filter.allows(ip("fe80::1")));
+
+        let deny_only = IpFilter::new().with_deny(cidr("192.168.0.0/16"));
+        assert!(deny_only.allows(ip("10.0.0.1")));
+        assert!(!deny_only.allows(ip("192.168.3.4")));
+    }
+
+    #[test]
+    fn connections_are_limited_until_permits_are_dropped() {
+        let now = Instant::now();
+        let gate = AcceptGate::starting_at(AcceptLimits::new().with_max_connections(2), now);
+        let first = gate.admit(ip("10.0.0.1"), now).expect("first peer");
+        let second = gate.admit(ip("10.0.0.2"), now).expect("second peer");
+        let refused = gate.admit(ip("10.0.0.3"), now).err();
+        assert_eq!(refused, Some(RejectReason::TooManyConnections));
+
+        drop(first);
+        let third = gate.admit(ip("10.0.0.3"), now).expect("place given back");
+        assert!(gate.admit(ip("10.0.0.4"), now).is_err());
+        drop((second, third));
+        assert!(gate.admit(ip("10.0.0.4"), now).is_ok());
+    }
+
+    #[test]
+    fn each_address_has_its_own_budget() {
+        let now = Instant::now();
+        let limits = AcceptLimits::new()
+            .with_max_per_ip(2)
+            .with_max_connections(3);
+        let gate = AcceptGate::starting_at(limits, now);
+        let a1 = gate.admit(ip("10.0.0.1"), now).expect("first from a");
+        let _a2 = gate.admit(ip("10.0.0.1"), now).expect("second from a");
+        let refused = gate.admit(ip("10.0.0.1"), now).err();
+        assert_eq!(refused, Some(RejectReason::TooManyFromIp));
+        let _b = gate.admit(ip("10.0.0.2"), now).expect("other address");
+        // The overall limit is checked first
+        let refused = gate.admit(ip("10.0.0.3"), now).err();
+        assert_eq!(refused, Some(RejectReason::TooManyConnections));
+
+        drop(a1);
+        assert!(gate.admit(ip("10.0.0.1"), now).is_ok());
+    }
+
+    #[test]
+    fn accept_rate_refills_over_time() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let limits = AcceptLimits::new().with_rate(2, Duration::from_millis(100));
+        let gate = AcceptGate::starting_at(limits, start);
+
+        // A full bucket allows a burst, and rejected peers don't take a place
+        let permits: Vec<_> = (0..2)
+            .map(|_| gate.admit(ip("10.0.0.1"), at(0)).expect("burst"))
+            .collect();
+        let refused = gate.admit(ip("10.0.0.1"), at(0)).err();
+        assert_eq!(refused, Some(RejectReason::RateLimited));
+        drop(permits);
+        assert!(gate.admit(ip("10.0.0.1"), at(49)).is_err());
+        assert!(gate.admit(ip("10.0.0.1"), at(50)).is_ok());
+        assert!(gate.admit(ip("10.0.0.1"), at(50)).is_err());
+
+        // The bucket never holds more than a burst, however long it is left alone
+        for _ in 0..2 {
+            assert!(gate.admit(ip("10.0.0.1"), at(10_000)).is_ok());
+        }
+        assert!(gate.admit(ip("10.0.0.1"), at(10_000)).is_err());
+        // Earlier times neither refill it nor hold back the next refill
+        assert!(gate.admit(ip("10.0.0.1"), at(5_000)).is_err());
+        assert!(gate.admit(ip("10.0.0.1"), at(10_049)).is_err());
+        assert!(gate.admit(ip("10.0.0.1"), at(10_050)).is_ok());
+    }
+
+    #[test]
+    fn mapped_addresses_share_the_ipv4_budget() {
+        let gate = AcceptGate::new(AcceptLimits::new().with_max_per_ip(1));
+        let v4: IpAddr = "10.0.0.1".parse().unwrap();
+        let mapped: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        let permit = gate.admit(v4, Instant::now()).expect("first peer");
+        let refused = gate.admit(mapped, Instant::now()).err();
+        assert_eq!(refused, Some(RejectReason::TooManyFromIp));
+        drop(permit);
+        assert!(gate.admit(mapped, Instant::now()).is_ok());
+    }
+}
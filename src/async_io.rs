@@ -0,0 +1,963 @@
+use attribute::AttributeKey;
+use bytes::{Buf, Bytes};
+use channel::{ChExt, ChWrite, ChannelError, ErrorEvent, RWEvent, RegistrationEvent};
+use ev_loop::{events, EventReceiver, EventSender, TaskSender, Trigger, Work};
+use futures::task::{self, Task};
+use futures::{Async, Poll, Stream};
+use metrics::ErrorKind;
+use selector::{Selector, SelectorKey};
+use std::cell::Cell;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::io::{self, Cursor, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+type Routes<R> = Arc<Mutex<HashMap<R, Weak<Inbound>>>>;
+
+// Flushes waiting for a channel to send the writes it holds
+const FLUSH_WAITERS: AttributeKey<Vec<Ack>> = AttributeKey::new("petty.async_io.flush_waiters");
+// Set on a channel to be closed once it has sent the writes it holds
+const CLOSE_WHEN_FLUSHED: AttributeKey<()> = AttributeKey::new("petty.async_io.close_when_flushed");
+
+const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+const DEFAULT_MAX_BUFFERED: usize = 64 * 1024;
+
+/// Splits a loop's event stream into byte streams for the channels opened through its
+/// `StreamOpener`.
+///
+/// Data read on an opened channel goes to its `ChannelStream`; every other event is passed on by
+/// this stream unchanged. A channel's stream ends once it disconnects, fails to connect or is
+/// closed.
+pub struct ChannelStreams<K>
+where
+    K: SelectorKey<Inbound = Bytes>,
+    K::Resource: Clone,
+{
+    events: EventReceiver<K>,
+    routes: Routes<K::Resource>,
+}
+
+/// Opens `ChannelStream`s; cheap to clone and usable while the `ChannelStreams` is being polled.
+pub struct StreamOpener<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey<Inbound = Bytes, Outbound = Bytes>,
+    K::Resource: Clone,
+{
+    routes: Routes<K::Resource>,
+    tasks: TaskSender<S, K>,
+}
+
+/// A channel as an async byte stream.
+///
+/// Reads are served from the channel's inbound data; writes are handed to the loop as tasks.
+/// Once too many writes are waiting for the loop to run them, further writes are not ready until
+/// it catches up. Flushing waits for every write to have run and for the channel to have handed
+/// all of them to its transport. A write the loop failed is reported by the next write or flush,
+/// and once the channel is gone they fail with `BrokenPipe`. Shutting down flushes, then closes
+/// the channel and is ready once it is closed.
+///
+/// Data read on the channel waits in the stream until it is read from it. Once too much is
+/// waiting, the channel's auto-read is turned off until half of it has been read.
+pub struct ChannelStream<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey<Inbound = Bytes, Outbound = Bytes>,
+    K::Resource: Clone,
+{
+    resource: K::Resource,
+    inbound: Arc<Inbound>,
+    // The rest of a chunk that didn't fit the last read
+    buffered: Cursor<Bytes>,
+    tasks: TaskSender<S, K>,
+    in_flight: Arc<InFlight>,
+    max_in_flight: usize,
+    // Whether the flush being waited for has been handed to the loop
+    flushing: bool,
+    // Whether shutting down has flushed and handed the close to the loop
+    closing: bool,
+}
+
+// Writes handed to a loop and not yet run, shared with the tasks running them
+#[derive(Debug, Default)]
+struct InFlight {
+    state: Mutex<InFlightState>,
+}
+
+// Data routed to a stream and not yet read from it
+struct Inbound {
+    state: Mutex<InboundState>,
+}
+
+struct InboundState {
+    chunks: VecDeque<Bytes>,
+    // Bytes in `chunks`
+    len: usize,
+    max_len: usize,
+    // Set once nothing more will be routed; the stream ends once the rest is read
+    ended: bool,
+    // Whether the channel's auto-read was turned off because too much was waiting
+    paused: bool,
+    set_auto_read: Box<dyn Fn(bool) + Send>,
+    // Waiting to read
+    task: Option<Task>,
+}
+
+// One write, flush or close in flight. Finished with the outcome it is completed with, or as
+// closed if it is dropped first, such as along with the attributes of a deregistered channel.
+#[derive(Debug)]
+struct Ack {
+    in_flight: Option<Arc<InFlight>>,
+}
+
+#[derive(Debug, Default)]
+struct InFlightState {
+    count: usize,
+    // The first failure since the last one was reported
+    failed: Option<io::Error>,
+    // Set once a write found the channel gone; every later write fails
+    closed: bool,
+    // Waiting for a write to finish
+    task: Option<Task>,
+}
+
+// impl ChannelStreams
+impl<K> ChannelStreams<K>
+where
+    K: SelectorKey<Inbound = Bytes>,
+    K::Resource: Clone,
+{
+    pub fn new<S>(events: EventReceiver<K>, tasks: TaskSender<S, K>) -> (Self, StreamOpener<S, K>)
+    where
+        S: Selector<K>,
+        K: SelectorKey<Outbound = Bytes>,
+    {
+        let routes = Arc::new(Mutex::new(HashMap::new()));
+        let streams = ChannelStreams {
+            events,
+            routes: routes.clone(),
+        };
+        (streams, StreamOpener { routes, tasks })
+    }
+
+    // Returns the event back if no opened stream takes it
+    fn route(&self, ev: Trigger<K>) -> Option<Trigger<K>> {
+        let mut routes = self.routes.lock().expect("Poisoned stream routes");
+        match ev {
+            Trigger::Read(events::ReadEvent::Data(resource, data)) => {
+                let route = match routes.get(&resource) {
+                    Some(route) => route.upgrade(),
+                    None => return Some(Trigger::Read(events::ReadEvent::Data(resource, data))),
+                };
+                match route {
+                    Some(inbound) => inbound.push(data),
+                    // Dropped without shutting down; nobody is left to read
+                    None => {
+                        routes.remove(&resource);
+                    }
+                }
+                None
+            }
+            Trigger::State(events::StateEvent::Disconnected(resource, addr)) => {
+                end_route(&mut routes, &resource);
+                Some(Trigger::State(events::StateEvent::Disconnected(
+                    resource, addr,
+                )))
+            }
+            Trigger::State(events::StateEvent::ConnectionError(resource, addr, why)) => {
+                end_route(&mut routes, &resource);
+                Some(Trigger::State(events::StateEvent::ConnectionError(
+                    resource, addr, why,
+                )))
+            }
+            Trigger::State(events::StateEvent::Closed(resource)) => {
+                end_route(&mut routes, &resource);
+                Some(Trigger::State(events::StateEvent::Closed(resource)))
+            }
+            ev => Some(ev),
+        }
+    }
+}
+
+impl<K> Stream for ChannelStreams<K>
+where
+    K: SelectorKey<Inbound = Bytes>,
+    K::Resource: Clone,
+{
+    type Item = Trigger<K>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.events.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(ev)) => {
+                    if let Some(ev) = self.route(ev) {
+                        return Ok(Async::Ready(Some(ev)));
+                    }
+                }
+                Async::Ready(None) => {
+                    // Ends every opened stream along with the event stream
+                    let mut routes = self.routes.lock().expect("Poisoned stream routes");
+                    for (_, route) in routes.drain() {
+                        if let Some(inbound) = route.upgrade() {
+                            inbound.end();
+                        }
+                    }
+                    return Ok(Async::Ready(None));
+                }
+            }
+        }
+    }
+}
+
+// Ends the stream `resource` is routed to, if any
+fn end_route<R: Hash + Eq>(routes: &mut HashMap<R, Weak<Inbound>>, resource: &R) {
+    if let Some(inbound) = routes.remove(resource).and_then(|route| route.upgrade()) {
+        inbound.end();
+    }
+}
+
+impl<K> fmt::Debug for ChannelStreams<K>
+where
+    K: SelectorKey<Inbound = Bytes>,
+    K::Resource: Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChannelStreams")
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+// impl StreamOpener
+impl<S, K> StreamOpener<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey<Inbound = Bytes, Outbound = Bytes>,
+    K::Resource: Clone + Send,
+{
+    /// Routes data read on `resource` from now on to the returned stream.
+    ///
+    /// Data already passed on as events is not replayed. Opening a channel again replaces its
+    /// previous stream, which then ends.
+    pub fn open(&self, resource: K::Resource) -> ChannelStream<S, K>
+    where
+        S: 'static,
+        K: 'static,
+    {
+        let tasks = self.tasks.clone();
+        let target = resource.clone();
+        let set_auto_read = move |auto_read: bool| {
+            let resource = target.clone();
+            // Nothing left to pause once the loop has stopped
+            let _ = tasks.send(Box::new(move |sys: &mut S, _: EventSender<K>| {
+                sys.set_auto_read(&resource, auto_read)
+            }));
+        };
+        let inbound = Arc::new(Inbound::new(Box::new(set_auto_read)));
+        let replaced = self
+            .routes
+            .lock()
+            .expect("Poisoned stream routes")
+            .insert(resource.clone(), Arc::downgrade(&inbound));
+        if let Some(inbound) = replaced.and_then(|route| route.upgrade()) {
+            inbound.end();
+        }
+        ChannelStream {
+            resource,
+            inbound,
+            buffered: Cursor::new(Bytes::new()),
+            tasks: self.tasks.clone(),
+            in_flight: Arc::new(InFlight::default()),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            flushing: false,
+            closing: false,
+        }
+    }
+}
+
+impl<S, K> Clone for StreamOpener<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey<Inbound = Bytes, Outbound = Bytes>,
+    K::Resource: Clone,
+{
+    fn clone(&self) -> Self {
+        StreamOpener {
+            routes: self.routes.clone(),
+            tasks: self.tasks.clone(),
+        }
+    }
+}
+
+// impl ChannelStream
+impl<S, K> ChannelStream<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey<Inbound = Bytes, Outbound = Bytes>,
+    K::Resource: Clone + Send + 'static,
+{
+    /// How many bytes read on the channel may wait in the stream before the channel stops
+    /// reading.
+    pub fn with_max_buffered(self, max_buffered: usize) -> Self {
+        self.inbound.set_max_len(cmp::max(max_buffered, 1));
+        self
+    }
+
+    /// How many writes may wait for the loop before further writes are not ready.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = cmp::max(max_in_flight, 1);
+        self
+    }
+
+    pub fn resource(&self) -> &K::Resource {
+        &self.resource
+    }
+
+    fn submit(&self, work: Work<'static, S, K>) -> io::Result<()> {
+        self.tasks
+            .send(work)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "event loop has stopped"))
+    }
+
+    // Hands `f` to the loop as an in-flight write, acknowledged once it has run
+    fn submit_write<F>(&self, f: F) -> io::Result<()>
+    where
+        F: Fn(&mut Vec<RWEvent<K>>, &mut K) + Send + 'static,
+    {
+        let resource = self.resource.clone();
+        let ack = Ack::new(&self.in_flight);
+        self.submit(Box::new(move |sys: &mut S, events: EventSender<K>| {
+            ack.complete(with_key(sys, events, &resource, f));
+        }))
+    }
+
+    // Hands the loop a flush, acknowledged once the channel holds no more writes
+    fn submit_flush(&self) -> io::Result<()> {
+        let resource = self.resource.clone();
+        let ack = Ack::new(&self.in_flight);
+        self.submit(Box::new(move |sys: &mut S, events: EventSender<K>| {
+            let ack = Cell::new(Some(ack));
+            let res = with_key(sys, events, &resource, |ev, key| {
+                key.io().flush(ev);
+                if key.io().pending_writes() > 0 {
+                    if let Some(ack) = ack.take() {
+                        key.attributes_mut()
+                            .get_or_insert_with(&FLUSH_WAITERS, Vec::new)
+                            .push(ack);
+                    }
+                }
+            });
+            // Flushed already, or failed
+            if let Some(ack) = ack.take() {
+                ack.complete(res);
+            }
+        }))
+    }
+
+    fn submit_close(&self) -> io::Result<()> {
+        let resource = self.resource.clone();
+        let ack = Ack::new(&self.in_flight);
+        self.submit(Box::new(move |sys: &mut S, events: EventSender<K>| {
+            let res = match close(sys, &events, &resource) {
+                Some(_) => Ok(()),
+                None => Err(ChannelError::Closed),
+            };
+            ack.complete(res);
+        }))
+    }
+}
+
+// impl Inbound
+impl Inbound {
+    fn new(set_auto_read: Box<dyn Fn(bool) + Send>) -> Self {
+        Inbound {
+            state: Mutex::new(InboundState {
+                chunks: VecDeque::new(),
+                len: 0,
+                max_len: DEFAULT_MAX_BUFFERED,
+                ended: false,
+                paused: false,
+                set_auto_read,
+                task: None,
+            }),
+        }
+    }
+
+    fn set_max_len(&self, max_len: usize) {
+        self.lock().max_len = max_len;
+    }
+
+    // Queues `data` for the reader, pausing reads on the channel once too much is waiting
+    fn push(&self, data: Bytes) {
+        let task = {
+            let mut state = self.lock();
+            state.len += data.len();
+            state.chunks.push_back(data);
+            if !state.paused && state.len >= state.max_len {
+                state.paused = true;
+                (state.set_auto_read)(false);
+            }
+            state.task.take()
+        };
+        if let Some(task) = task {
+            task.notify();
+        }
+    }
+
+    fn end(&self) {
+        let task = {
+            let mut state = self.lock();
+            state.ended = true;
+            state.task.take()
+        };
+        if let Some(task) = task {
+            task.notify();
+        }
+    }
+
+    // Takes the next chunk, resuming reads on the channel once enough has been taken
+    fn poll_next(&self) -> Async<Option<Bytes>> {
+        let mut state = self.lock();
+        let data = match state.chunks.pop_front() {
+            Some(data) => data,
+            None if state.ended => return Async::Ready(None),
+            None => {
+                state.task = Some(task::current());
+                return Async::NotReady;
+            }
+        };
+        state.len -= data.len();
+        if state.paused && state.len <= state.max_len / 2 {
+            state.paused = false;
+            (state.set_auto_read)(true);
+        }
+        Async::Ready(Some(data))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, InboundState> {
+        self.state.lock().expect("Poisoned stream inbound")
+    }
+}
+
+impl Drop for Inbound {
+    fn drop(&mut self) {
+        // Nobody is left to read, but the channel may still be
+        let state = self.state.get_mut().expect("Poisoned stream inbound");
+        if state.paused {
+            (state.set_auto_read)(true);
+        }
+    }
+}
+
+// impl Ack
+impl Ack {
+    fn new(in_flight: &Arc<InFlight>) -> Self {
+        in_flight.start();
+        Ack {
+            in_flight: Some(in_flight.clone()),
+        }
+    }
+
+    fn complete(mut self, res: Result<(), ChannelError>) {
+        let failed = match res {
+            Ok(()) => None,
+            Err(ChannelError::Closed) => Some(closed()),
+            Err(why) => Some(io::Error::other(why)),
+        };
+        if let Some(in_flight) = self.in_flight.take() {
+            in_flight.finish(failed);
+        }
+    }
+}
+
+impl Drop for Ack {
+    fn drop(&mut self) {
+        if let Some(in_flight) = self.in_flight.take() {
+            in_flight.finish(Some(closed()));
+        }
+    }
+}
+
+// impl InFlight
+impl InFlight {
+    // Ready once fewer than `max` writes are in flight; failures are reported first
+    fn poll_below(&self, max: usize) -> io::Result<()> {
+        let mut state = self.state.lock().expect("Poisoned in-flight writes");
+        if let Some(why) = state.failed.take() {
+            return Err(why);
+        }
+        if state.closed {
+            return Err(closed());
+        }
+        if state.count >= max {
+            state.task = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(())
+    }
+
+    fn start(&self) {
+        self.state.lock().expect("Poisoned in-flight writes").count += 1;
+    }
+
+    fn finish(&self, failed: Option<io::Error>) {
+        let task = {
+            let mut state = self.state.lock().expect("Poisoned in-flight writes");
+            state.count -= 1;
+            match failed {
+                Some(ref why) if why.kind() == io::ErrorKind::BrokenPipe => state.closed = true,
+                Some(why) if state.failed.is_none() => state.failed = Some(why),
+                _ => {}
+            }
+            state.task.take()
+        };
+        if let Some(task) = task {
+            task.notify();
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "channel is closed")
+}
+
+impl<S, K> Read for ChannelStream<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey<Inbound = Bytes, Outbound = Bytes>,
+    K::Resource: Clone + Send + 'static,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.buffered.has_remaining() {
+            match self.inbound.poll_next() {
+                Async::Ready(Some(data)) => self.buffered = Cursor::new(data),
+                // The channel is gone: end of stream
+                Async::Ready(None) => return Ok(0),
+                Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+        self.buffered.read(buf)
+    }
+}
+
+impl<S, K> AsyncRead for ChannelStream<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey<Inbound = Bytes, Outbound = Bytes>,
+    K::Resource: Clone + Send + 'static,
+{
+}
+
+impl<S, K> Write for ChannelStream<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey<Inbound = Bytes, Outbound = Bytes>,
+    K::Resource: Clone + Send + 'static,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.in_flight.poll_below(self.max_in_flight)?;
+        let data = Cell::new(Some(Bytes::from(buf)));
+        self.submit_write(move |ev, key| {
+            if let Some(data) = data.take() {
+                key.io().write(data, ev);
+            }
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.flushing {
+            self.submit_flush()?;
+            self.flushing = true;
+        }
+        match self.in_flight.poll_below(1) {
+            Err(ref why) if why.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            res => {
+                self.flushing = false;
+                res
+            }
+        }
+    }
+}
+
+impl<S, K> AsyncWrite for ChannelStream<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey<Inbound = Bytes, Outbound = Bytes>,
+    K::Resource: Clone + Send + 'static,
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if !self.closing {
+            match self.flush() {
+                Ok(()) => {}
+                Err(ref why) if why.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                // Closed already
+                Err(ref why) if why.kind() == io::ErrorKind::BrokenPipe => {
+                    return Ok(Async::Ready(()))
+                }
+                Err(why) => return Err(why),
+            }
+            self.submit_close()?;
+            self.closing = true;
+        }
+        match self.in_flight.poll_below(1) {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref why) if why.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(ref why) if why.kind() == io::ErrorKind::BrokenPipe => Ok(Async::Ready(())),
+            Err(why) => Err(why),
+        }
+    }
+}
+
+impl<S, K> fmt::Debug for ChannelStream<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey<Inbound = Bytes, Outbound = Bytes>,
+    K::Resource: Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChannelStream")
+            .field("resource", &self.resource)
+            .field("buffered", &self.buffered.remaining())
+            .finish()
+    }
+}
+
+// Completes the flushes waiting on `resource` with `res`, and closes it if that was asked for.
+// A failed send leaves nothing to wait for either.
+pub(crate) fn flushed<S, K>(
+    sys: &mut S,
+    events: &EventSender<K>,
+    resource: &K::Resource,
+    res: Result<(), ChannelError>,
+) where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    let (waiting, closing) = match sys.key_mut(resource) {
+        Some(key) => {
+            let attributes = key.attributes_mut();
+            let waiting = attributes.remove(&FLUSH_WAITERS);
+            (waiting, attributes.remove(&CLOSE_WHEN_FLUSHED).is_some())
+        }
+        None => return,
+    };
+    for ack in waiting.into_iter().flatten() {
+        ack.complete(res.clone());
+    }
+    if closing {
+        close(sys, events, resource);
+    }
+}
+
+// Closes the key for `resource` once it has handed the writes it holds to its transport, or
+// right away if it holds none
+pub(crate) fn close_when_flushed<S, K>(sys: &mut S, events: &EventSender<K>, resource: &K::Resource)
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    let key = match sys.key_mut(resource) {
+        Some(key) => key,
+        None => return,
+    };
+    if key.io().pending_writes() > 0 {
+        trace!("{:?} closing once flushed", resource);
+        key.attributes_mut().set(&CLOSE_WHEN_FLUSHED, ());
+    } else {
+        close(sys, events, resource);
+    }
+}
+
+// Deregisters and closes the key for `resource`, reporting it as closed
+pub(crate) fn close<S, K>(sys: &mut S, events: &EventSender<K>, resource: &K::Resource) -> Option<K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    let key = close_key(sys, resource)?;
+    let _ = events.send(Trigger::State(events::StateEvent::Closed(key.resource())));
+    Some(key)
+}
+
+// Deregisters and closes the key for `resource` without reporting it, for callers that report
+// why it went away themselves
+pub(crate) fn close_key<S, K>(sys: &mut S, resource: &K::Resource) -> Option<K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    let mut key = sys.deregister(resource)?;
+    key.io().close();
+    sys.metrics().record_closed();
+    Some(key)
+}
+
+// Runs `f` against the key for `resource`, applies the registration updates it asks for and
+// reports the errors it produces. Fails with the first error, or `Closed` if the key isn't
+// registered.
+pub(crate) fn with_key<S, K, F>(
+    sys: &mut S,
+    events: EventSender<K>,
+    resource: &K::Resource,
+    f: F,
+) -> Result<(), ChannelError>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+    K::Resource: Hash,
+    F: Fn(&mut Vec<RWEvent<K>>, &mut K),
+{
+    let mut coll = Vec::new();
+    let found = Cell::new(false);
+    sys.on_resource(resource, &mut coll, |ev, key| {
+        found.set(true);
+        f(ev, key);
+    });
+    let mut res = if found.get() {
+        Ok(())
+    } else {
+        Err(ChannelError::Closed)
+    };
+    for ev in coll {
+        let ev = match ev {
+            RWEvent::Registration(RegistrationEvent::Update(resource, ops)) => {
+                sys.update_registration(resource, ops);
+                continue;
+            }
+            RWEvent::Registration(RegistrationEvent::Flushed(resource)) => {
+                flushed(sys, &events, &resource, Ok(()));
+                continue;
+            }
+            RWEvent::Error(ErrorEvent::Codec(resource, why)) => {
+                sys.metrics().record_error(ErrorKind::Codec);
+                res = res.and(Err(ChannelError::Codec(why.clone())));
+                events::ErrorEvent::Codec(resource, why)
+            }
+            RWEvent::Error(ErrorEvent::WriteRejected(resource)) => {
+                res = res.and(Err(ChannelError::WriteRejected));
+                events::ErrorEvent::WriteRejected(resource)
+            }
+            RWEvent::Error(ErrorEvent::Send(resource, why)) => {
+                flushed(
+                    sys,
+                    &events,
+                    &resource,
+                    Err(ChannelError::Send(why.clone())),
+                );
+                res = res.and(Err(ChannelError::Send(why.clone())));
+                events::ErrorEvent::Send(resource, why)
+            }
+            _ => continue,
+        };
+        let _ = events.send(Trigger::Error(ev));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel::ChannelId;
+    use codec::BytesCodec;
+    use ev_loop::SelectorEventLoop;
+    use futures::future::{self, Future};
+    use ops::Ops;
+    use std::time::Duration;
+    use transport::embedded::EmbeddedChannel;
+    use transport::mock::{MockSelector, SelectorCall};
+
+    type Key = EmbeddedChannel<BytesCodec>;
+
+    const NOW: Duration = Duration::from_millis(0);
+
+    // Runs `f` as a task, with a loop serving a single open stream
+    fn with_stream<F>(f: F)
+    where
+        F: FnOnce(
+            &mut SelectorEventLoop<MockSelector<Key>, Key>,
+            ChannelStream<MockSelector<Key>, Key>,
+        ),
+    {
+        let (mut ev_loop, tasks, events) = SelectorEventLoop::new(MockSelector::new());
+        let ch = EmbeddedChannel::new(BytesCodec);
+        let id = ch.id();
+        ev_loop.register(ch, Ops::READ);
+        let (_streams, opener) = ChannelStreams::new(events, tasks);
+        let stream = opener.open(id).with_max_in_flight(2);
+        future::lazy(move || {
+            f(&mut ev_loop, stream);
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .expect("task");
+    }
+
+    fn key<'a>(
+        ev_loop: &'a mut SelectorEventLoop<MockSelector<Key>, Key>,
+        id: &ChannelId,
+    ) -> &'a mut Key {
+        ev_loop.selector_mut().key_mut(id).expect("registered")
+    }
+
+    #[test]
+    fn writes_wait_for_the_loop_once_enough_are_in_flight() {
+        with_stream(|ev_loop, mut stream| {
+            stream.write_all(b"one").expect("write");
+            stream.write_all(b"two").expect("write");
+            let blocked = stream.write(b"three").expect_err("in flight");
+            assert_eq!(blocked.kind(), io::ErrorKind::WouldBlock);
+            ev_loop.run_once(NOW);
+            stream.write_all(b"three").expect("write");
+
+            // Flushing waits for the loop to have run every write
+            let blocked = stream.flush().expect_err("in flight");
+            assert_eq!(blocked.kind(), io::ErrorKind::WouldBlock);
+            ev_loop.run_once(NOW);
+            stream.flush().expect("flushed");
+            let id = *stream.resource();
+            let key = ev_loop.selector_mut().key_mut(&id).expect("registered");
+            let written: Vec<Bytes> = (0..3).filter_map(|_| key.read_outbound()).collect();
+            assert_eq!(written, vec!["one", "two", "three"]);
+        });
+    }
+
+    #[test]
+    fn flushing_waits_for_the_channel_to_send_held_writes() {
+        with_stream(|ev_loop, mut stream| {
+            let id = *stream.resource();
+            key(ev_loop, &id).set_writable(false);
+            stream.write_all(b"held").expect("write");
+            for _ in 0..2 {
+                let blocked = stream.flush().expect_err("held");
+                assert_eq!(blocked.kind(), io::ErrorKind::WouldBlock);
+                ev_loop.run_once(NOW);
+            }
+            assert!(ev_loop
+                .selector()
+                .interest(&id)
+                .expect("registered")
+                .has_write());
+
+            key(ev_loop, &id).set_writable(true);
+            ev_loop.selector_mut().push_ready(vec![(id, Ops::WRITE)]);
+            ev_loop.run_once(NOW);
+            stream.flush().expect("flushed");
+            assert_eq!(key(ev_loop, &id).read_outbound().expect("sent"), "held");
+        });
+    }
+
+    #[test]
+    fn flushes_fail_if_the_channel_closes_first() {
+        with_stream(|ev_loop, mut stream| {
+            let id = *stream.resource();
+            key(ev_loop, &id).set_writable(false);
+            stream.write_all(b"held").expect("write");
+            stream.flush().expect_err("held");
+            ev_loop.run_once(NOW);
+            ev_loop.close(&id);
+            let why = stream.flush().expect_err("closed");
+            assert_eq!(why.kind(), io::ErrorKind::BrokenPipe);
+        });
+    }
+
+    #[test]
+    fn shutting_down_flushes_then_closes() {
+        with_stream(|ev_loop, mut stream| {
+            let id = *stream.resource();
+            key(ev_loop, &id).set_writable(false);
+            stream.write_all(b"last").expect("write");
+            for _ in 0..2 {
+                assert_eq!(stream.shutdown().expect("shutting down"), Async::NotReady);
+                ev_loop.run_once(NOW);
+            }
+            assert!(ev_loop.selector().key(&id).is_some());
+
+            key(ev_loop, &id).set_writable(true);
+            ev_loop.selector_mut().push_ready(vec![(id, Ops::WRITE)]);
+            ev_loop.run_once(NOW);
+            assert_eq!(stream.shutdown().expect("closing"), Async::NotReady);
+            ev_loop.run_once(NOW);
+            assert_eq!(stream.shutdown().expect("closed"), Async::Ready(()));
+            assert!(ev_loop.selector().key(&id).is_none());
+            assert_eq!(ev_loop.selector_mut().metrics().closed, 1);
+            assert_eq!(ev_loop.selector().snapshot().bytes_written(), 4);
+        });
+    }
+
+    #[test]
+    fn channels_stop_reading_while_their_stream_is_full() {
+        let (mut ev_loop, tasks, events) = SelectorEventLoop::new(MockSelector::new());
+        let mut ch = EmbeddedChannel::new(BytesCodec);
+        ch.push_inbound(&b"abcd"[..]);
+        ch.push_inbound(&b"ef"[..]);
+        let id = ch.id();
+        ev_loop.register(ch, Ops::READ);
+        let (mut streams, opener) = ChannelStreams::new(events, tasks);
+        let mut stream = opener.open(id).with_max_buffered(4);
+        ev_loop.selector_mut().push_ready(vec![(id, Ops::READ)]);
+        ev_loop.run_once(NOW);
+        future::lazy(move || {
+            assert!(streams.poll().expect("events").is_not_ready());
+            ev_loop.run_once(NOW);
+            let paused = SelectorCall::SetAutoRead(id, false);
+            assert_eq!(ev_loop.selector_mut().take_calls().last(), Some(&paused));
+
+            // Reads resume once no more than half is left
+            let mut buf = [0; 4];
+            assert_eq!(stream.read(&mut buf).expect("read"), 4);
+            ev_loop.run_once(NOW);
+            let resumed = SelectorCall::SetAutoRead(id, true);
+            assert!(ev_loop.selector().calls().contains(&resumed));
+            assert_eq!(stream.read(&mut buf).expect("read"), 2);
+            assert_eq!(&buf[..2], b"ef");
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .expect("task");
+    }
+
+    #[test]
+    fn closing_a_channel_ends_its_stream() {
+        let (mut ev_loop, tasks, events) = SelectorEventLoop::new(MockSelector::new());
+        let ch = EmbeddedChannel::new(BytesCodec);
+        let id = ch.id();
+        ev_loop.register(ch, Ops::READ);
+        let (streams, opener) = ChannelStreams::new(events, tasks);
+        let mut stream = opener.open(id);
+        ev_loop.close(&id);
+
+        // Taken off the routes as the event goes by, while the loop is still running
+        match streams.wait().next() {
+            Some(Ok(Trigger::State(events::StateEvent::Closed(r)))) => assert_eq!(r, id),
+            other => panic!("expected the channel to be closed, got {:?}", other),
+        }
+        assert!(opener.routes.lock().expect("routes").is_empty());
+        let mut buf = [0; 4];
+        assert_eq!(stream.read(&mut buf).expect("end of stream"), 0);
+    }
+
+    #[test]
+    fn writes_to_a_closed_channel_are_broken_pipes() {
+        with_stream(|ev_loop, mut stream| {
+            ev_loop.close(stream.resource());
+            stream.write_all(b"lost").expect("queued");
+            ev_loop.run_once(NOW);
+            for _ in 0..2 {
+                let why = stream.write(b"lost").expect_err("closed");
+                assert_eq!(why.kind(), io::ErrorKind::BrokenPipe);
+            }
+            let why = stream.flush().expect_err("closed");
+            assert_eq!(why.kind(), io::ErrorKind::BrokenPipe);
+        });
+    }
+}
@@ -0,0 +1,230 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Names a typed value stored on a channel.
+///
+/// Keys are told apart by name and value type, so they can be declared as constants and shared
+/// between the code that sets a value and the code that reads it.
+pub struct AttributeKey<T> {
+    name: &'static str,
+    value: PhantomData<fn() -> T>,
+}
+
+/// Per-channel storage for values of any type, keyed by `AttributeKey`.
+///
+/// Everything stored is dropped when the channel is deregistered, except when its loop
+/// re-registers it to re-dial its remote: connectors keep their attributes across reconnects.
+#[derive(Default)]
+pub struct Attributes {
+    values: HashMap<(&'static str, TypeId), Box<dyn Any + Send>>,
+}
+
+// impl AttributeKey
+impl<T: Any + Send> AttributeKey<T> {
+    pub const fn new(name: &'static str) -> Self {
+        AttributeKey {
+            name,
+            value: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn id(&self) -> (&'static str, TypeId) {
+        (self.name, TypeId::of::<T>())
+    }
+}
+
+impl<T> Clone for AttributeKey<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for AttributeKey<T> {}
+
+impl<T> fmt::Debug for AttributeKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AttributeKey({:?})", self.name)
+    }
+}
+
+// impl Attributes
+impl Attributes {
+    pub fn new() -> Self {
+        Attributes::default()
+    }
+
+    pub fn get<T: Any + Send>(&self, key: &AttributeKey<T>) -> Option<&T> {
+        self.values
+            .get(&key.id())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: Any + Send>(&mut self, key: &AttributeKey<T>) -> Option<&mut T> {
+        self.values
+            .get_mut(&key.id())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    /// Stores `value`, returning the one it replaces.
+    pub fn set<T: Any + Send>(&mut self, key: &AttributeKey<T>, value: T) -> Option<T> {
+        self.values
+            .insert(key.id(), Box::new(value))
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    /// The value for `key`, storing the one made by `init` first if there is none.
+    pub fn get_or_insert_with<T, F>(&mut self, key: &AttributeKey<T>, init: F) -> &mut T
+    where
+        T: Any + Send,
+        F: FnOnce() -> T,
+    {
+        self.values
+            .entry(key.id())
+            .or_insert_with(|| Box::new(init()))
+            .downcast_mut()
+            .expect("Attribute stored under another type")
+    }
+
+    pub fn remove<T: Any + Send>(&mut self, key: &AttributeKey<T>) -> Option<T> {
+        self.values
+            .remove(&key.id())
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    pub fn contains<T: Any + Send>(&self, key: &AttributeKey<T>) -> bool {
+        self.values.contains_key(&key.id())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
+
+impl fmt::Debug for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set()
+            .entries(self.values.keys().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::BytesCodec;
+    use ev_loop::SelectorEventLoop;
+    use ops::Ops;
+    use reconnect::Backoff;
+    use selector::{Selector, SelectorKey};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use transport::embedded::EmbeddedChannel;
+    use transport::mock::MockSelector;
+
+    const COUNT: AttributeKey<u32> = AttributeKey::new("test.count");
+    const COUNT_TEXT: AttributeKey<String> = AttributeKey::new("test.count");
+    const DROPS: AttributeKey<CountsDrop> = AttributeKey::new("test.drops");
+
+    // Counts how many times it has been dropped
+    struct CountsDrop(Arc<AtomicUsize>);
+
+    impl Drop for CountsDrop {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn values_are_set_read_and_removed() {
+        let mut attrs = Attributes::new();
+        assert!(attrs.is_empty());
+        assert_eq!(attrs.get(&COUNT), None);
+        assert_eq!(attrs.set(&COUNT, 1), None);
+        assert_eq!(attrs.set(&COUNT, 2), Some(1));
+        *attrs.get_mut(&COUNT).expect("set") += 1;
+        assert_eq!(attrs.get(&COUNT), Some(&3));
+        assert!(attrs.contains(&COUNT));
+        assert_eq!(attrs.len(), 1);
+
+        assert_eq!(attrs.remove(&COUNT), Some(3));
+        assert_eq!(attrs.remove(&COUNT), None);
+        assert!(!attrs.contains(&COUNT));
+        assert!(attrs.is_empty());
+    }
+
+    #[test]
+    fn keys_sharing_a_name_hold_different_types() {
+        let mut attrs = Attributes::new();
+        attrs.set(&COUNT, 7);
+        attrs.set(&COUNT_TEXT, "seven".to_owned());
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs.get(&COUNT), Some(&7));
+        assert_eq!(attrs.get(&COUNT_TEXT).map(String::as_str), Some("seven"));
+        attrs.remove(&COUNT_TEXT);
+        assert_eq!(attrs.get(&COUNT), Some(&7));
+    }
+
+    #[test]
+    fn get_or_insert_with_only_inits_missing_values() {
+        let mut attrs = Attributes::new();
+        *attrs.get_or_insert_with(&COUNT, || 10) += 1;
+        let value = attrs.get_or_insert_with(&COUNT, || panic!("already set"));
+        assert_eq!(*value, 11);
+    }
+
+    #[test]
+    fn values_are_dropped_on_deregister() {
+        let mut sys = MockSelector::new();
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut ch = EmbeddedChannel::new(BytesCodec);
+        ch.attributes_mut().set(&DROPS, CountsDrop(drops.clone()));
+        let id = ch.id();
+        sys.register(ch, Ops::READ);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+        let key = sys.deregister(&id).expect("registered");
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert!(key.attributes().is_empty());
+    }
+
+    #[test]
+    fn values_survive_redials() {
+        let (mut ev_loop, _tasks, _events) = SelectorEventLoop::new(MockSelector::new());
+        let drops = Arc::new(AtomicUsize::new(0));
+        let backoff =
+            Backoff::new(Duration::from_millis(1), Duration::from_millis(1)).with_jitter_percent(0);
+        let mut ch = EmbeddedChannel::new(BytesCodec).with_reconnect(backoff);
+        ch.attributes_mut().set(&DROPS, CountsDrop(drops.clone()));
+        ch.disconnect();
+        let id = ch.id();
+        ev_loop.register(ch, Ops::READ);
+        ev_loop.selector_mut().push_ready(vec![(id, Ops::READ)]);
+        ev_loop.run_for(Duration::from_millis(10));
+        ev_loop.selector_mut().push_ready(vec![(id, Ops::CONNECT)]);
+        ev_loop.run_once(Duration::from_millis(0));
+
+        let key = ev_loop.selector().key(&id).expect("still registered");
+        assert_eq!(key.redials(), 1);
+        assert!(key.attributes().contains(&DROPS));
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        ev_loop.close(&id);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+}
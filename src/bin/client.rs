@@ -1,4 +1,8 @@
 extern crate bytes;
+extern crate env_logger;
+extern crate futures;
+#[macro_use]
+extern crate log;
 extern crate petty;
 extern crate udt;
 
@@ -10,12 +14,17 @@ fn client() {
     use std;
     use std::net::{SocketAddr, SocketAddrV4};
     use std::str::FromStr;
+    use std::time::Duration;
     use udt::*;
 
+    use futures::Stream;
+    use petty::ev_loop::EventSender;
     use petty::ev_loop::SelectorEventLoop;
     use petty::ev_loop::Trigger;
     use petty::ops::Ops;
+    use petty::reconnect::{Backoff, WritePolicy};
     use petty::transport::udt::ChannelKind;
+    use petty::transport::udt::ChannelOptions;
     use petty::transport::udt::UdtChannel;
     use petty::transport::udt::UdtKey;
     use petty::transport::udt::UdtSelector;
@@ -32,7 +41,7 @@ fn client() {
 
         tx.send((events, tasks))
             .expect("Unable to return events receiver to listener.");
-        println!("spawning event loop.run");
+        info!("spawning event loop.run");
 
         event_loop.run();
     });
@@ -40,79 +49,110 @@ fn client() {
 
     tasks
         .send(Box::new(
-            |sys: &mut UdtSelector, events: mpsc::Sender<Trigger<UdtKey>>| {
+            |sys: &mut UdtSelector, events: EventSender<UdtKey>| {
                 use petty::selector::Selector;
                 let localhost = std::net::Ipv4Addr::from_str("127.0.0.1").unwrap();
                 let target = SocketAddrV4::new(localhost, 8080);
                 let remote = SocketAddr::V4(target);
                 let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
-                let ch = UdtChannel::new(sock, ChannelKind::Connector { remote });
-                {
-                    let key = UdtKey::new(ch.clone());
-                    sys.register(key, Ops::with_error());
-                }
-                let key = UdtKey::new(ch);
+                let timeout = Duration::from_secs(5);
+                let options = ChannelOptions::default()
+                    .with_connect_timeout(timeout)
+                    .with_reconnect(Backoff::default().with_max_attempts(10))
+                    .with_write_policy(WritePolicy::Buffer { max_messages: 1024 });
+                let mut ch =
+                    UdtChannel::with_options(sock, ChannelKind::Connector { remote }, options)
+                        .expect("UDT err creating connector");
 
-                let res = sock.connect(remote);
-                println!("res: {:?}", res);
-                if let Err(_) = res {
+                let id = ch.id();
+                let res = ch.connect();
+                debug!("{} connect to {:?}: {:?}", id, remote, res);
+                if let Err(why) = res {
+                    let _ = sock.close();
                     events
                         .send(Trigger::State(events::StateEvent::ConnectionError(
-                            remote.clone(),
-                        ))).expect("Err delivering ConnError");
-                    // TODO handle cleanup of ch
+                            id, remote, why,
+                        )))
+                        .expect("Err delivering ConnError");
+                    return;
                 }
-                if ch.is_connected() {
-                    println!("Quickly connected, yaa");
-                    use petty::selector::SelectorKey;
+                let key = UdtKey::new(ch);
+                if key.ch.is_connected() {
+                    info!("{} connected immediately", id);
+                    sys.register(key, Ops::READ | Ops::ERROR);
                     events
-                        .send(Trigger::State(events::StateEvent::Connected(
-                            key.resource(),
-                            remote.clone(),
-                        ))).expect("Err delivering Connected event");
+                        .send(Trigger::State(events::StateEvent::Connected(id, remote)))
+                        .expect("Err delivering Connected event");
                 } else {
-                    println!("not connected, yaa");
-                    let mut ops = Ops::with_error();
-                    ops.apply(Ops::CONNECT);
-                    sys.register(key, ops);
+                    debug!("{} connect in progress", id);
+                    sys.register(key, Ops::CONNECT | Ops::ERROR);
                 }
             },
-        )).expect("Error sending Connect I/O tasks");
+        ))
+        .expect("Error sending Connect I/O tasks");
 
-    loop {
-        let ev: Trigger<UdtKey> = events.recv().unwrap();
+    for ev in events.wait() {
+        let ev: Trigger<UdtKey> = ev.unwrap();
         match ev {
             Trigger::Read(_) => {
-                println!("received READ event");
+                trace!("received READ event");
             }
             Trigger::Write(_) => {
-                println!("received WRITE event");
+                debug!("received WRITE event");
             }
             Trigger::Error(_) => {
-                println!("received ERROR event");
+                warn!("received ERROR event");
             }
             Trigger::State(state) => {
-                println!("received STATE event {:?}", state);
+                debug!("received STATE event {:?}", state);
 
                 match state {
-                    StateEvent::ConnectionError(addr) => {
-                        println!("Received conn error on {:?}", addr);
+                    StateEvent::ConnectionError(resource, addr, why) => {
+                        error!("{:?} failed to connect to {:?}: {}", resource, addr, why);
                     }
                     StateEvent::Connected(resource, peer) => {
-                        println!("connected to {:?}", peer);
+                        info!("{:?} connected to {:?}", resource, peer);
                         let mut count = 0;
                         loop {
                             count += 1;
                             tasks
                                 .send(Box::new(move |sys: &mut UdtSelector, _| {
-                                    let key = sys.registered.get_mut(&resource).unwrap();
                                     use petty::channel::ChWrite;
+                                    let key = match sys.registered.get_mut(&resource) {
+                                        Some(key) => key,
+                                        None => {
+                                            warn!("{:?} closed; dropping msg {}", resource, count);
+                                            return;
+                                        }
+                                    };
                                     let payload = format!("msg {:?}", count);
-                                    key.ch.io.write(&Bytes::from(payload), &mut vec![]);
-                                })).expect("Err dispatching Write task to event loop");
+                                    key.ch.write(Bytes::from(payload), &mut vec![]);
+                                }))
+                                .expect("Err dispatching Write task to event loop");
                         }
                     }
-                    StateEvent::Disconnected(_) => {}
+                    StateEvent::Disconnected(resource, peer) => {
+                        warn!("{:?} disconnected from {:?}", resource, peer);
+                    }
+                    StateEvent::Reconnecting {
+                        resource,
+                        remote,
+                        attempt,
+                        delay,
+                        ..
+                    } => {
+                        info!(
+                            "{:?} re-dialing {:?} in {:?} (attempt {})",
+                            resource, remote, delay, attempt
+                        );
+                    }
+                    StateEvent::Reconnected(resource, peer) => {
+                        info!("{:?} reconnected to {:?}", resource, peer);
+                    }
+                    StateEvent::Closed(resource) => {
+                        info!("{:?} closed", resource);
+                    }
+                    StateEvent::Idle(..) | StateEvent::Rejected(..) => {}
                 }
             }
         }
@@ -120,5 +160,6 @@ fn client() {
 }
 
 fn main() {
+    env_logger::init();
     client();
 }
@@ -1,4 +1,7 @@
+extern crate env_logger;
 extern crate futures;
+#[macro_use]
+extern crate log;
 extern crate petty;
 extern crate udt;
 
@@ -8,6 +11,7 @@ fn server() {
     use std::str::FromStr;
     use udt::*;
 
+    use futures::Stream;
     use petty::ev_loop::SelectorEventLoop;
     use petty::ev_loop::Trigger;
     use petty::ops::Ops;
@@ -28,7 +32,7 @@ fn server() {
 
         tx.send((events, tasks))
             .expect("Unable to return events receiver to listener.");
-        println!("spawning event loop.run");
+        info!("spawning event loop.run");
 
         let localhost = std::net::Ipv4Addr::from_str("127.0.0.1").unwrap();
 
@@ -36,10 +40,10 @@ fn server() {
         sock.bind(SocketAddr::V4(SocketAddrV4::new(localhost, 8080)))
             .unwrap();
         let my_addr = sock.getsockname().unwrap();
-        println!("Server bound to {:?}", my_addr);
+        info!("Server bound to {:?}", my_addr);
         sock.listen(5).unwrap();
 
-        let ch = UdtChannel::new(sock, ChannelKind::Acceptor);
+        let ch = UdtChannel::new(sock, ChannelKind::Acceptor).expect("UDT err creating acceptor");
         let key = UdtKey::new(ch);
 
         let ops = Ops::with_accept();
@@ -47,25 +51,26 @@ fn server() {
         event_loop.run();
     });
     let (events, _tasks) = rx.recv().unwrap();
-    loop {
-        let ev: Trigger<UdtKey> = events.recv().unwrap();
+    for ev in events.wait() {
+        let ev: Trigger<UdtKey> = ev.unwrap();
         match ev {
-            Trigger::Read(data) => {
-                //                println!("received READ event: {:?}", data);
+            Trigger::Read(_data) => {
+                trace!("received READ event: {:?}", _data);
             }
             Trigger::Write(_) => {
-                println!("received WRITE event");
+                debug!("received WRITE event");
             }
             Trigger::Error(_) => {
-                println!("received ERROR event");
+                warn!("received ERROR event");
             }
             Trigger::State(state) => {
-                println!("received STATE event {:?}", state);
+                info!("received STATE event {:?}", state);
             }
         }
     }
 }
 
 fn main() {
+    env_logger::init();
     server();
 }
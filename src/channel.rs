@@ -1,7 +1,17 @@
-use bytes::Bytes;
+use accept::RejectReason;
+use codec::CodecError;
 use ops::Ops;
 use selector::SelectorKey;
+use std::error;
+use std::fmt;
 use std::net::SocketAddr;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static NEXT_CHANNEL: AtomicUsize = AtomicUsize::new(1);
 
 // TODO should really be implemented for whatever's inside SelectorKey's Resource
 pub trait ChRead<K: SelectorKey> {
@@ -9,34 +19,250 @@ pub trait ChRead<K: SelectorKey> {
 }
 
 pub trait ChWrite<K: SelectorKey> {
-    fn write(&mut self, data: &Bytes, collector: &mut Vec<RWEvent<K>>);
+    fn write(&mut self, msg: K::Outbound, collector: &mut Vec<RWEvent<K>>);
     fn flush(&mut self, collector: &mut Vec<RWEvent<K>>);
+    // Writes taken but not yet handed to the transport; once they all are, the channel reports
+    // `RegistrationEvent::Flushed`
+    fn pending_writes(&self) -> usize;
 }
 
 pub trait ChExt<K: SelectorKey> {
+    // Starts connecting a connector, reporting the outcome like `finish_connect`
+    fn connect(&mut self, collector: &mut Vec<RWEvent<K>>);
     fn finish_connect(&mut self, collector: &mut Vec<RWEvent<K>>);
+    fn close(&mut self);
+}
+
+/// Identifies a channel for as long as it lives, whatever its transport.
+///
+/// Ids are assigned when a channel is created and never reused. The short text form is unique
+/// within the process; the long form adds a token for the process instance, which keeps it unique
+/// across processes and restarts.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChannelId {
+    instance: u64,
+    seq: u64,
 }
 
+/// Text that isn't either form of a `ChannelId`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelIdError(String);
+
 #[derive(Debug)]
 pub enum RWEvent<K: SelectorKey> {
     Registration(RegistrationEvent<K>),
     Read(ReadEvent<K>),
     State(StateEvent<K>),
-    Error(/*TODO*/),
+    Error(ErrorEvent<K>),
 }
 
 #[derive(Debug)]
 pub enum ReadEvent<K: SelectorKey> {
     NewPeer(K, SocketAddr),
-    Data(Bytes),
+    Data(K::Resource, K::Inbound),
 }
 
 #[derive(Debug)]
 pub enum RegistrationEvent<K: SelectorKey> {
     Update(K::Resource, Ops),
+    // Writes that had to wait have all been handed to the transport
+    Flushed(K::Resource),
 }
 
 #[derive(Debug)]
 pub enum StateEvent<K: SelectorKey> {
     ConnectedPeer(K::Resource, SocketAddr),
+    ConnectFailed(K::Resource, SocketAddr, ConnectError),
+    Disconnected(K::Resource, SocketAddr),
+    // An acceptor closed a peer it accepted instead of handing it over
+    Rejected(K::Resource, SocketAddr, RejectReason),
+}
+
+#[derive(Debug)]
+pub enum ErrorEvent<K: SelectorKey> {
+    Codec(K::Resource, CodecError),
+    // A write the channel couldn't send straight away was dropped per its write policy
+    WriteRejected(K::Resource),
+    // An acceptor failed to accept or set up a peer; the acceptor itself stays registered
+    Accept(K::Resource, String),
+    // The transport refused a write
+    Send(K::Resource, String),
+}
+
+/// Why a connect attempt was abandoned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectError {
+    /// No connection was established within the channel's connect timeout.
+    TimedOut,
+    /// The socket broke before the connection was established.
+    Broken,
+    /// The connect call itself failed.
+    Failed(String),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnectError::TimedOut => write!(f, "connect timed out"),
+            ConnectError::Broken => write!(f, "socket broke while connecting"),
+            ConnectError::Failed(ref why) => write!(f, "connect failed: {}", why),
+        }
+    }
+}
+
+impl error::Error for ConnectError {}
+
+/// Why work handed to a channel's loop, such as a write, failed on that channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelError {
+    /// The channel was no longer registered, or its loop has stopped.
+    Closed,
+    /// The channel couldn't send the write straight away and its write policy dropped it.
+    WriteRejected,
+    /// The message couldn't be encoded.
+    Codec(CodecError),
+    /// The transport failed to send the write.
+    Send(String),
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChannelError::Closed => write!(f, "channel is closed"),
+            ChannelError::WriteRejected => write!(f, "write rejected by the write policy"),
+            ChannelError::Codec(ref why) => write!(f, "codec error: {}", why),
+            ChannelError::Send(ref why) => write!(f, "send failed: {}", why),
+        }
+    }
+}
+
+impl error::Error for ChannelError {}
+
+// impl ChannelId
+impl ChannelId {
+    /// A fresh id, never handed out before in this process.
+    pub fn generate() -> Self {
+        ChannelId {
+            instance: instance(),
+            seq: NEXT_CHANNEL.fetch_add(1, Ordering::Relaxed) as u64,
+        }
+    }
+
+    pub fn as_short_text(&self) -> String {
+        format!("{:08x}", self.seq)
+    }
+
+    pub fn as_long_text(&self) -> String {
+        format!("{:016x}-{:08x}", self.instance, self.seq)
+    }
+}
+
+impl fmt::Debug for ChannelId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ChannelId({})", self.as_short_text())
+    }
+}
+
+/// Writes the short form, or the long form with `{:#}`.
+impl fmt::Display for ChannelId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            f.write_str(&self.as_long_text())
+        } else {
+            f.write_str(&self.as_short_text())
+        }
+    }
+}
+
+/// Parses either text form; a short form names a channel of this process.
+impl FromStr for ChannelId {
+    type Err = ChannelIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex =
+            |text: &str| u64::from_str_radix(text, 16).map_err(|_| ChannelIdError(s.to_owned()));
+        let (instance, seq) = match s.find('-') {
+            Some(dash) => (hex(&s[..dash])?, hex(&s[dash + 1..])?),
+            None => (instance(), hex(s)?),
+        };
+        Ok(ChannelId { instance, seq })
+    }
+}
+
+// impl ChannelIdError
+impl fmt::Display for ChannelIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid channel id {:?}", self.0)
+    }
+}
+
+impl error::Error for ChannelIdError {}
+
+// Distinguishes this process from others on the host and from earlier runs
+fn instance() -> u64 {
+    static INSTANCE: OnceLock<u64> = OnceLock::new();
+    *INSTANCE.get_or_init(|| {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() << 20 ^ u64::from(d.subsec_micros()))
+            .unwrap_or(0);
+        u64::from(process::id()) << 44 ^ started
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    fn ids_are_never_handed_out_twice() {
+        let threads: Vec<_> = (0..4)
+            .map(|_| thread::spawn(|| (0..1000).map(|_| ChannelId::generate()).collect::<Vec<_>>()))
+            .collect();
+        let mut seen = HashSet::new();
+        for t in threads {
+            for id in t.join().expect("generating thread") {
+                assert!(seen.insert(id), "{:#} handed out twice", id);
+            }
+        }
+        let shorts: HashSet<_> = seen.iter().map(ChannelId::as_short_text).collect();
+        assert_eq!(shorts.len(), seen.len());
+    }
+
+    #[test]
+    fn ids_display_in_short_and_long_form() {
+        let id = ChannelId::generate();
+        let short = id.to_string();
+        assert_eq!(short, id.as_short_text());
+        assert_eq!(short.len(), 8);
+        assert!(short.chars().all(|c| c.is_ascii_hexdigit()));
+
+        let long = format!("{:#}", id);
+        assert_eq!(long, id.as_long_text());
+        assert_eq!(long.len(), 16 + 1 + 8);
+        assert!(long.ends_with(&format!("-{}", short)));
+        assert_eq!(format!("{:?}", id), format!("ChannelId({})", short));
+    }
+
+    #[test]
+    fn ids_parse_back_from_either_form() {
+        let id = ChannelId::generate();
+        assert_eq!(id.to_string().parse(), Ok(id));
+        assert_eq!(format!("{:#}", id).parse(), Ok(id));
+
+        // Only the long form can name a channel of another process
+        let elsewhere = ChannelId {
+            instance: instance() ^ 1,
+            seq: id.seq,
+        };
+        assert_eq!(elsewhere.as_long_text().parse(), Ok(elsewhere));
+        assert_eq!(elsewhere.as_short_text().parse(), Ok(id));
+
+        for bad in &["", "-", "xyz", "0000000000000001-", "-00000001", "1-2-3"] {
+            let err = bad.parse::<ChannelId>().err();
+            assert_eq!(err, Some(ChannelIdError(bad.to_string())), "{:?}", bad);
+        }
+    }
 }
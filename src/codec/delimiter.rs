@@ -0,0 +1,337 @@
+use bytes::{Bytes, BytesMut};
+use codec::{find, CodecError, Decoder};
+
+/// Splits a byte stream on `\n` or `\r\n`.
+///
+/// Lines longer than `max_length` are reported as soon as the limit is passed, and the rest of the
+/// line is discarded up to and including its delimiter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineBasedFrameDecoder {
+    max_length: usize,
+    strip_delimiter: bool,
+    discarding: bool,
+    // Bytes already scanned for a delimiter on earlier calls
+    scanned: usize,
+}
+
+/// Splits a byte stream on one or more arbitrary delimiters.
+///
+/// When several delimiters match, the one producing the shortest frame wins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DelimiterBasedFrameDecoder {
+    max_frame_length: usize,
+    strip_delimiter: bool,
+    delimiters: Vec<Bytes>,
+    discarding: bool,
+    // Bytes already scanned for a delimiter on earlier calls
+    scanned: usize,
+}
+
+// impl LineBasedFrameDecoder
+impl LineBasedFrameDecoder {
+    pub fn new(max_length: usize) -> Self {
+        LineBasedFrameDecoder {
+            max_length,
+            strip_delimiter: true,
+            discarding: false,
+            scanned: 0,
+        }
+    }
+
+    pub fn with_strip_delimiter(mut self, strip: bool) -> Self {
+        self.strip_delimiter = strip;
+        self
+    }
+}
+
+impl Decoder for LineBasedFrameDecoder {
+    type Item = Bytes;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>, CodecError> {
+        loop {
+            let eol = buf[self.scanned..]
+                .iter()
+                .position(|b| *b == b'\n')
+                .map(|idx| idx + self.scanned);
+            let eol = match eol {
+                Some(eol) => eol,
+                None => {
+                    // A trailing '\r' may turn out to be half of the delimiter
+                    let pending = match buf.last() {
+                        Some(b'\r') => buf.len() - 1,
+                        _ => buf.len(),
+                    };
+                    if self.discarding {
+                        buf.clear();
+                        self.scanned = 0;
+                    } else if pending > self.max_length {
+                        let length = buf.len() as u64;
+                        buf.clear();
+                        self.scanned = 0;
+                        self.discarding = true;
+                        return Err(CodecError::FrameTooLong {
+                            length,
+                            max: self.max_length,
+                        });
+                    } else {
+                        self.scanned = buf.len();
+                    }
+                    return Ok(None);
+                }
+            };
+            self.scanned = 0;
+
+            if self.discarding {
+                // Drop the tail of an over-long line and look for the next one
+                buf.advance(eol + 1);
+                self.discarding = false;
+                continue;
+            }
+
+            let delim_len = if eol > 0 && buf[eol - 1] == b'\r' {
+                2
+            } else {
+                1
+            };
+            let line_len = eol + 1 - delim_len;
+            if line_len > self.max_length {
+                buf.advance(eol + 1);
+                return Err(CodecError::FrameTooLong {
+                    length: line_len as u64,
+                    max: self.max_length,
+                });
+            }
+
+            let mut frame = buf.split_to(eol + 1);
+            if self.strip_delimiter {
+                frame.truncate(line_len);
+            }
+            return Ok(Some(frame.freeze()));
+        }
+    }
+}
+
+// impl DelimiterBasedFrameDecoder
+impl DelimiterBasedFrameDecoder {
+    pub fn new(max_frame_length: usize, delimiters: Vec<Bytes>) -> Self {
+        assert!(!delimiters.is_empty(), "at least one delimiter is required");
+        assert!(
+            delimiters.iter().all(|d| !d.is_empty()),
+            "delimiters must not be empty"
+        );
+        DelimiterBasedFrameDecoder {
+            max_frame_length,
+            strip_delimiter: true,
+            delimiters,
+            discarding: false,
+            scanned: 0,
+        }
+    }
+
+    pub fn with_strip_delimiter(mut self, strip: bool) -> Self {
+        self.strip_delimiter = strip;
+        self
+    }
+
+    /// `\r\n` and `\n`.
+    pub fn line_delimiters() -> Vec<Bytes> {
+        vec![Bytes::from_static(b"\r\n"), Bytes::from_static(b"\n")]
+    }
+
+    /// A single NUL byte, as used by Flash XML sockets and STOMP.
+    pub fn nul_delimiter() -> Vec<Bytes> {
+        vec![Bytes::from_static(b"\0")]
+    }
+
+    fn longest_delimiter(&self) -> usize {
+        self.delimiters.iter().map(|d| d.len()).max().unwrap_or(1)
+    }
+}
+
+impl Decoder for DelimiterBasedFrameDecoder {
+    type Item = Bytes;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>, CodecError> {
+        loop {
+            // A delimiter may straddle the end of what was scanned before
+            let keep = self.longest_delimiter() - 1;
+            let from = self.scanned.saturating_sub(keep);
+            // Shortest frame wins; ties go to the longer delimiter so `\r\n` beats `\n`
+            let found = self
+                .delimiters
+                .iter()
+                .filter_map(|delim| find(&buf[from..], delim).map(|idx| (from + idx, delim.len())))
+                .min_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+            let (frame_len, delim_len) = match found {
+                Some(found) => found,
+                None => {
+                    if self.discarding {
+                        // Keep a potential partial delimiter at the end of the buffer
+                        let drop = buf.len().saturating_sub(keep);
+                        buf.advance(drop);
+                    } else if buf.len() > self.max_frame_length {
+                        let length = buf.len() as u64;
+                        let drop = buf.len().saturating_sub(keep);
+                        buf.advance(drop);
+                        self.scanned = buf.len();
+                        self.discarding = true;
+                        return Err(CodecError::FrameTooLong {
+                            length,
+                            max: self.max_frame_length,
+                        });
+                    }
+                    self.scanned = buf.len();
+                    return Ok(None);
+                }
+            };
+            self.scanned = 0;
+
+            if self.discarding {
+                buf.advance(frame_len + delim_len);
+                self.discarding = false;
+                continue;
+            }
+
+            if frame_len > self.max_frame_length {
+                buf.advance(frame_len + delim_len);
+                return Err(CodecError::FrameTooLong {
+                    length: frame_len as u64,
+                    max: self.max_frame_length,
+                });
+            }
+
+            let mut frame = buf.split_to(frame_len + delim_len);
+            if self.strip_delimiter {
+                frame.truncate(frame_len);
+            }
+            return Ok(Some(frame.freeze()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::{BytesCodec, Encoder};
+    use transport::embedded::EmbeddedChannel;
+
+    // Frames inbound bytes with a decoder under test and writes outbound bytes as they are
+    #[derive(Debug, Clone)]
+    struct Framed<D>(D);
+
+    impl<D: Decoder> Decoder for Framed<D> {
+        type Item = D::Item;
+
+        fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<D::Item>, CodecError> {
+            self.0.decode(buf)
+        }
+    }
+
+    impl<D> Encoder for Framed<D> {
+        type Item = Bytes;
+
+        fn encode(&mut self, msg: Bytes, dst: &mut BytesMut) -> Result<(), CodecError> {
+            BytesCodec.encode(msg, dst)
+        }
+    }
+
+    fn lines(max_length: usize) -> EmbeddedChannel<Framed<LineBasedFrameDecoder>> {
+        EmbeddedChannel::new(Framed(LineBasedFrameDecoder::new(max_length)))
+    }
+
+    fn delimited(
+        max_frame_length: usize,
+        delimiters: Vec<Bytes>,
+    ) -> EmbeddedChannel<Framed<DelimiterBasedFrameDecoder>> {
+        EmbeddedChannel::new(Framed(DelimiterBasedFrameDecoder::new(
+            max_frame_length,
+            delimiters,
+        )))
+    }
+
+    #[test]
+    fn lines_split_across_reads() {
+        let mut ch = lines(16);
+        ch.write_inbound(&b"hel"[..]);
+        ch.write_inbound(&b"lo\r"[..]);
+        assert_eq!(ch.read_inbound(), None);
+        ch.write_inbound(&b"\nworld\nand"[..]);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("hello")));
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("world")));
+        assert_eq!(ch.read_inbound(), None);
+    }
+
+    #[test]
+    fn lines_keep_delimiter_when_asked() {
+        let decoder = LineBasedFrameDecoder::new(16).with_strip_delimiter(false);
+        let mut ch = EmbeddedChannel::new(Framed(decoder));
+        ch.write_inbound(&b"a\r\nb\n"[..]);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("a\r\n")));
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("b\n")));
+    }
+
+    #[test]
+    fn long_lines_are_reported_once_and_skipped() {
+        let mut ch = lines(4);
+        ch.write_inbound(&b"toolong"[..]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::FrameTooLong { length: 7, max: 4 })
+        );
+        ch.write_inbound(&b"stillgoing\nok\n"[..]);
+        assert_eq!(ch.read_codec_error(), None);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("ok")));
+    }
+
+    #[test]
+    fn carriage_returns_wait_for_the_next_byte() {
+        let mut ch = lines(5);
+        ch.write_inbound(&b"hello\r"[..]);
+        assert_eq!(ch.read_codec_error(), None);
+        ch.write_inbound(&b"\n"[..]);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("hello")));
+
+        // Not followed by '\n', it counts like any other byte
+        ch.write_inbound(&b"hello\r"[..]);
+        ch.write_inbound(&b"!"[..]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::FrameTooLong { length: 7, max: 5 })
+        );
+    }
+
+    #[test]
+    fn shortest_frame_wins() {
+        let delimiters = vec![Bytes::from_static(b"||"), Bytes::from_static(b";")];
+        let mut ch = delimited(16, delimiters);
+        ch.write_inbound(&b"a;b||c"[..]);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("a")));
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("b")));
+        assert_eq!(ch.read_inbound(), None);
+    }
+
+    #[test]
+    fn delimiter_straddling_reads() {
+        let mut ch = delimited(64, vec![Bytes::from_static(b"\r\n\r\n")]);
+        ch.write_inbound(&b"head\r\n"[..]);
+        ch.write_inbound(&b"\r"[..]);
+        assert_eq!(ch.read_inbound(), None);
+        ch.write_inbound(&b"\nnext"[..]);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("head")));
+        assert_eq!(ch.read_inbound(), None);
+    }
+
+    #[test]
+    fn long_frames_are_discarded_up_to_the_delimiter() {
+        let mut ch = delimited(4, DelimiterBasedFrameDecoder::nul_delimiter());
+        ch.write_inbound(&b"0123456"[..]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::FrameTooLong { length: 7, max: 4 })
+        );
+        ch.write_inbound(&b"789\0ok\0"[..]);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("ok")));
+        assert_eq!(ch.read_codec_error(), None);
+    }
+}
@@ -0,0 +1,709 @@
+use bytes::{Bytes, BytesMut};
+use codec::{find, CodecError, Decoder, Encoder};
+use std::fmt::Write;
+use std::mem;
+use std::str;
+
+// Longest chunk-size or trailer line accepted in a chunked body
+const MAX_CHUNK_LINE: usize = 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+/// A fully received HTTP request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub uri: String,
+    pub version: Version,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Empty,
+    /// Sent with a `Content-Length` header.
+    Full(Bytes),
+    /// Sent with `Transfer-Encoding: chunked`, one chunk per element; HTTP/1.0 peers receive the
+    /// chunks concatenated instead.
+    Chunked(Vec<Bytes>),
+}
+
+/// An HTTP response. `Content-Length`, `Transfer-Encoding` and `Connection` are set by the
+/// encoder and should not be added by hand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub version: Version,
+    pub keep_alive: bool,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+/// Decodes HTTP/1.x requests and encodes responses.
+///
+/// Requests are delivered once their body is complete. After a request that does not keep the
+/// connection alive, or after any decoding error, the rest of the stream is discarded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpServerCodec {
+    max_header_size: usize,
+    max_content_length: usize,
+    state: State,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    Head,
+    Body {
+        request: Request,
+        remaining: usize,
+    },
+    Chunked {
+        request: Request,
+        body: BytesMut,
+        chunk: Chunk,
+    },
+    Closed,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Chunk {
+    Size,
+    Data(usize),
+    Trailers,
+}
+
+// impl Version
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+// impl Request
+impl Request {
+    /// First value of the named header; names are matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the client expects the connection to stay open after the response.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("connection").unwrap_or("");
+        match self.version {
+            Version::Http10 => has_token(connection, "keep-alive"),
+            Version::Http11 => !has_token(connection, "close"),
+        }
+    }
+}
+
+// impl Response
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            reason: reason_phrase(status).to_owned(),
+            version: Version::Http11,
+            keep_alive: true,
+            headers: Vec::new(),
+            body: Body::Empty,
+        }
+    }
+
+    /// A response whose version and connection handling match `request`.
+    pub fn for_request(request: &Request, status: u16) -> Self {
+        let mut response = Response::new(status);
+        response.version = request.version;
+        response.keep_alive = request.keep_alive();
+        response
+    }
+
+    /// The error response to a request that couldn't be decoded; the connection is closed after it.
+    pub fn for_error(why: &CodecError) -> Self {
+        let status = match *why {
+            CodecError::HeaderTooLarge { .. } => 431,
+            CodecError::FrameTooLong { .. } => 413,
+            _ => 400,
+        };
+        Response::new(status).with_keep_alive(false)
+    }
+
+    pub fn with_reason(mut self, reason: &str) -> Self {
+        self.reason = reason.to_owned();
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn with_body<B: Into<Bytes>>(mut self, body: B) -> Self {
+        self.body = Body::Full(body.into());
+        self
+    }
+
+    pub fn with_chunks(mut self, chunks: Vec<Bytes>) -> Self {
+        self.body = Body::Chunked(chunks);
+        self
+    }
+
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+}
+
+// impl HttpServerCodec
+impl HttpServerCodec {
+    pub fn new(max_header_size: usize, max_content_length: usize) -> Self {
+        HttpServerCodec {
+            max_header_size,
+            max_content_length,
+            state: State::Head,
+        }
+    }
+
+    fn fail(&mut self, buf: &mut BytesMut, why: CodecError) -> Result<Option<Request>, CodecError> {
+        self.state = State::Closed;
+        buf.clear();
+        Err(why)
+    }
+
+    fn complete(&mut self, request: Request) -> Result<Option<Request>, CodecError> {
+        self.state = if request.keep_alive() {
+            State::Head
+        } else {
+            State::Closed
+        };
+        Ok(Some(request))
+    }
+
+    fn decode_head(&mut self, buf: &mut BytesMut) -> Result<Option<Request>, CodecError> {
+        let scan = buf.len().min(self.max_header_size + 4);
+        let end = match find(&buf[..scan], b"\r\n\r\n") {
+            Some(end) => end,
+            None => {
+                if buf.len() > self.max_header_size {
+                    let max = self.max_header_size;
+                    return self.fail(buf, CodecError::HeaderTooLarge { max });
+                }
+                return Ok(None);
+            }
+        };
+        let head = buf.split_to(end + 4);
+        let request = match parse_head(&head[..end]) {
+            Ok(request) => request,
+            Err(why) => return self.fail(buf, why),
+        };
+
+        let chunked = request
+            .header("transfer-encoding")
+            .is_some_and(|te| has_token(te, "chunked"));
+        if chunked && request.header("content-length").is_some() {
+            let why = CodecError::MalformedHttp("Content-Length with chunked body");
+            return self.fail(buf, why);
+        }
+        if chunked {
+            self.state = State::Chunked {
+                request,
+                body: BytesMut::new(),
+                chunk: Chunk::Size,
+            };
+            return Ok(None);
+        }
+
+        let length = match content_length(&request) {
+            Ok(length) => length,
+            Err(why) => return self.fail(buf, why),
+        };
+        if length > self.max_content_length as u64 {
+            let max = self.max_content_length;
+            return self.fail(buf, CodecError::FrameTooLong { length, max });
+        }
+        if length == 0 {
+            return self.complete(request);
+        }
+        self.state = State::Body {
+            request,
+            remaining: length as usize,
+        };
+        Ok(None)
+    }
+
+    // Advances a chunked body as far as `buf` allows; returns the request once the body is complete
+    fn decode_chunked(
+        &mut self,
+        buf: &mut BytesMut,
+        mut request: Request,
+        mut body: BytesMut,
+        mut chunk: Chunk,
+    ) -> Result<Option<Request>, CodecError> {
+        loop {
+            match chunk {
+                Chunk::Size | Chunk::Trailers => {
+                    let scan = buf.len().min(MAX_CHUNK_LINE + 2);
+                    let eol = match find(&buf[..scan], b"\r\n") {
+                        Some(eol) => eol,
+                        None if buf.len() > MAX_CHUNK_LINE => {
+                            return self
+                                .fail(buf, CodecError::MalformedHttp("chunk line too long"));
+                        }
+                        None => break,
+                    };
+                    let line = buf.split_to(eol + 2);
+                    let line = &line[..eol];
+                    if chunk == Chunk::Trailers {
+                        // Trailers are skipped; an empty line ends the body
+                        if line.is_empty() {
+                            request.body = body.freeze();
+                            return self.complete(request);
+                        }
+                        continue;
+                    }
+                    let size = match parse_chunk_size(line) {
+                        Some(size) => size,
+                        None => {
+                            return self.fail(buf, CodecError::MalformedHttp("invalid chunk size"))
+                        }
+                    };
+                    let length = body.len() as u64 + size;
+                    if length > self.max_content_length as u64 {
+                        let max = self.max_content_length;
+                        return self.fail(buf, CodecError::FrameTooLong { length, max });
+                    }
+                    chunk = if size == 0 {
+                        Chunk::Trailers
+                    } else {
+                        Chunk::Data(size as usize)
+                    };
+                }
+                Chunk::Data(size) => {
+                    if buf.len() < size + 2 {
+                        break;
+                    }
+                    if &buf[size..size + 2] != b"\r\n" {
+                        let why = CodecError::MalformedHttp("missing CRLF after chunk");
+                        return self.fail(buf, why);
+                    }
+                    body.extend_from_slice(&buf[..size]);
+                    buf.advance(size + 2);
+                    chunk = Chunk::Size;
+                }
+            }
+        }
+        self.state = State::Chunked {
+            request,
+            body,
+            chunk,
+        };
+        Ok(None)
+    }
+}
+
+impl Decoder for HttpServerCodec {
+    type Item = Request;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Request>, CodecError> {
+        loop {
+            match mem::replace(&mut self.state, State::Head) {
+                State::Head => {
+                    if buf.is_empty() {
+                        return Ok(None);
+                    }
+                    if let Some(request) = self.decode_head(buf)? {
+                        return Ok(Some(request));
+                    }
+                    if self.state == State::Head {
+                        return Ok(None);
+                    }
+                }
+                State::Body {
+                    mut request,
+                    remaining,
+                } => {
+                    if buf.len() < remaining {
+                        buf.reserve(remaining - buf.len());
+                        self.state = State::Body { request, remaining };
+                        return Ok(None);
+                    }
+                    request.body = buf.split_to(remaining).freeze();
+                    return self.complete(request);
+                }
+                State::Chunked {
+                    request,
+                    body,
+                    chunk,
+                } => return self.decode_chunked(buf, request, body, chunk),
+                State::Closed => {
+                    self.state = State::Closed;
+                    buf.clear();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+impl Encoder for HttpServerCodec {
+    type Item = Response;
+
+    fn encode(&mut self, msg: Response, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let mut head = String::new();
+        let _ = write!(
+            head,
+            "{} {} {}\r\n",
+            msg.version.as_str(),
+            msg.status,
+            msg.reason
+        );
+        for (name, value) in &msg.headers {
+            if has_line_break(name) || has_line_break(value) {
+                return Err(CodecError::MalformedHttp("header contains CR or LF"));
+            }
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        }
+        if has_line_break(&msg.reason) {
+            return Err(CodecError::MalformedHttp("reason phrase contains CR or LF"));
+        }
+        match (msg.version, msg.keep_alive) {
+            (Version::Http11, false) => head.push_str("Connection: close\r\n"),
+            (Version::Http10, true) => head.push_str("Connection: keep-alive\r\n"),
+            _ => {}
+        }
+
+        // Informational, 204 and 304 responses must not carry a body or announce one
+        let bodiless = msg.status < 200 || msg.status == 204 || msg.status == 304;
+        let body = match msg.body {
+            _ if bodiless => Bytes::new(),
+            Body::Chunked(ref chunks) if msg.version == Version::Http11 => {
+                head.push_str("Transfer-Encoding: chunked\r\n\r\n");
+                dst.extend_from_slice(head.as_bytes());
+                for chunk in chunks.iter().filter(|c| !c.is_empty()) {
+                    dst.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                    dst.extend_from_slice(chunk);
+                    dst.extend_from_slice(b"\r\n");
+                }
+                dst.extend_from_slice(b"0\r\n\r\n");
+                return Ok(());
+            }
+            Body::Chunked(chunks) => chunks
+                .iter()
+                .fold(BytesMut::new(), |mut acc, c| {
+                    acc.extend_from_slice(c);
+                    acc
+                })
+                .freeze(),
+            Body::Full(body) => body,
+            Body::Empty => Bytes::new(),
+        };
+        if !bodiless {
+            let _ = write!(head, "Content-Length: {}\r\n", body.len());
+        }
+        head.push_str("\r\n");
+        dst.reserve(head.len() + body.len());
+        dst.extend_from_slice(head.as_bytes());
+        dst.extend_from_slice(&body);
+        Ok(())
+    }
+}
+
+fn parse_head(head: &[u8]) -> Result<Request, CodecError> {
+    let head = str::from_utf8(head).map_err(|_| CodecError::MalformedHttp("non UTF-8 header"))?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (method, uri, version) = match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some(method), Some(uri), Some(version), None) if !method.is_empty() && !uri.is_empty() => {
+            (method, uri, version)
+        }
+        _ => return Err(CodecError::MalformedHttp("invalid request line")),
+    };
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        _ => return Err(CodecError::MalformedHttp("unsupported HTTP version")),
+    };
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let colon = line
+            .find(':')
+            .ok_or(CodecError::MalformedHttp("header without colon"))?;
+        let name = &line[..colon];
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace()) {
+            return Err(CodecError::MalformedHttp("invalid header name"));
+        }
+        headers.push((name.to_owned(), line[colon + 1..].trim().to_owned()));
+    }
+
+    Ok(Request {
+        method: method.to_owned(),
+        uri: uri.to_owned(),
+        version,
+        headers,
+        body: Bytes::new(),
+    })
+}
+
+// Length of the body announced by `Content-Length`, or 0 without one; repeated headers are
+// rejected rather than guessing which one the peer meant
+fn content_length(request: &Request) -> Result<u64, CodecError> {
+    let mut values = request
+        .headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+        .map(|(_, v)| v);
+    let value = match (values.next(), values.next()) {
+        (None, _) => return Ok(0),
+        (Some(value), None) => value,
+        (Some(_), Some(_)) => return Err(CodecError::MalformedHttp("multiple Content-Length")),
+    };
+    // `parse` alone would accept a leading `+`
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(CodecError::MalformedHttp("invalid Content-Length"));
+    }
+    value
+        .parse()
+        .map_err(|_| CodecError::MalformedHttp("invalid Content-Length"))
+}
+
+fn parse_chunk_size(line: &[u8]) -> Option<u64> {
+    let line = str::from_utf8(line).ok()?;
+    // Chunk extensions follow a `;` and are ignored
+    let size = line.split(';').next().unwrap_or("").trim();
+    if size.is_empty() || size.len() > 16 {
+        return None;
+    }
+    u64::from_str_radix(size, 16).ok()
+}
+
+fn has_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+fn has_line_break(s: &str) -> bool {
+    s.contains(['\r', '\n'])
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::embedded::EmbeddedChannel;
+
+    fn channel() -> EmbeddedChannel<HttpServerCodec> {
+        EmbeddedChannel::new(HttpServerCodec::new(256, 64))
+    }
+
+    #[test]
+    fn request_split_across_reads() {
+        let mut ch = channel();
+        ch.write_inbound(&b"POST /submit HTTP/1.1\r\nContent-"[..]);
+        ch.write_inbound(&b"Length: 5\r\nHost: x\r\n\r\nhel"[..]);
+        assert_eq!(ch.read_inbound(), None);
+        ch.write_inbound(&b"loGET / HTTP/1.1\r\n\r\n"[..]);
+
+        let first = ch.read_inbound().expect("first request");
+        assert_eq!(first.method, "POST");
+        assert_eq!(first.uri, "/submit");
+        assert_eq!(first.header("host"), Some("x"));
+        assert_eq!(first.body, Bytes::from("hello"));
+        assert!(first.keep_alive());
+        let second = ch.read_inbound().expect("pipelined request");
+        assert_eq!(second.method, "GET");
+        assert!(second.body.is_empty());
+    }
+
+    #[test]
+    fn chunked_body() {
+        let mut ch = channel();
+        ch.write_inbound(&b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki"[..]);
+        ch.write_inbound(&b"\r\n5;ext=1\r\npedia\r\n0\r\nX-Trailer: y\r\n"[..]);
+        assert_eq!(ch.read_inbound(), None);
+        ch.write_inbound(&b"\r\n"[..]);
+        let request = ch.read_inbound().expect("request");
+        assert_eq!(request.body, Bytes::from("Wikipedia"));
+    }
+
+    #[test]
+    fn chunked_body_over_max_content_length() {
+        let mut ch = channel();
+        ch.write_inbound(&b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n41\r\n"[..]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::FrameTooLong {
+                length: 65,
+                max: 64
+            })
+        );
+    }
+
+    #[test]
+    fn oversized_head_and_content_length() {
+        let mut ch = channel();
+        ch.write_inbound(vec![b'a'; 300]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::HeaderTooLarge { max: 256 })
+        );
+
+        let mut ch = channel();
+        ch.write_inbound(&b"POST / HTTP/1.1\r\nContent-Length: 65\r\n\r\n"[..]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::FrameTooLong {
+                length: 65,
+                max: 64
+            })
+        );
+    }
+
+    #[test]
+    fn nothing_is_decoded_after_a_closing_request() {
+        let mut ch = channel();
+        ch.write_inbound(&b"GET / HTTP/1.0\r\n\r\nGET /again HTTP/1.0\r\n\r\n"[..]);
+        let request = ch.read_inbound().expect("request");
+        assert!(!request.keep_alive());
+        assert_eq!(ch.read_inbound(), None);
+    }
+
+    #[test]
+    fn malformed_request_line() {
+        let mut ch = channel();
+        ch.write_inbound(&b"GET /\r\n\r\n"[..]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::MalformedHttp("invalid request line"))
+        );
+    }
+
+    #[test]
+    fn encodes_chunked_responses_for_http_11_only() {
+        let chunks = vec![Bytes::from("ab"), Bytes::new(), Bytes::from("c")];
+        let mut ch = channel();
+        ch.write_outbound(Response::new(200).with_chunks(chunks.clone()));
+        let wire = ch.read_outbound().expect("response");
+        assert_eq!(
+            &wire[..],
+            &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n1\r\nc\r\n0\r\n\r\n"
+                [..]
+        );
+
+        let mut response = Response::new(200)
+            .with_chunks(chunks)
+            .with_keep_alive(false);
+        response.version = Version::Http10;
+        ch.write_outbound(response);
+        let wire = ch.read_outbound().expect("response");
+        assert_eq!(
+            &wire[..],
+            &b"HTTP/1.0 200 OK\r\nContent-Length: 3\r\n\r\nabc"[..]
+        );
+    }
+
+    #[test]
+    fn encodes_connection_handling() {
+        let mut ch = channel();
+        ch.write_outbound(Response::new(204).with_keep_alive(false));
+        let wire = ch.read_outbound().expect("response");
+        assert_eq!(
+            &wire[..],
+            &b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn repeated_or_conflicting_lengths_are_rejected() {
+        let heads: [&[u8]; 4] = [
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            b"POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 2\r\n\r\nab",
+            b"POST / HTTP/1.1\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: +2\r\n\r\nab",
+        ];
+        let errors = [
+            "multiple Content-Length",
+            "multiple Content-Length",
+            "Content-Length with chunked body",
+            "invalid Content-Length",
+        ];
+        for (head, why) in heads.iter().zip(errors.iter()) {
+            let mut ch = channel();
+            ch.write_inbound(*head);
+            assert_eq!(ch.read_inbound(), None);
+            assert_eq!(ch.read_codec_error(), Some(CodecError::MalformedHttp(why)));
+        }
+        let why = CodecError::MalformedHttp("multiple Content-Length");
+        assert_eq!(Response::for_error(&why).status, 400);
+    }
+
+    #[test]
+    fn bodiless_statuses_carry_no_length_or_body() {
+        let mut ch = channel();
+        for status in &[101, 204, 304] {
+            ch.write_outbound(Response::new(*status).with_body("ignored"));
+            let wire = ch.read_outbound().expect("response");
+            let wire = str::from_utf8(&wire).expect("ascii");
+            assert!(wire.ends_with("\r\n\r\n"), "{:?}", wire);
+            assert!(!wire.contains("Content-Length"), "{:?}", wire);
+        }
+        ch.write_outbound(Response::new(304).with_chunks(vec![Bytes::from("x")]));
+        let wire = ch.read_outbound().expect("response");
+        assert_eq!(&wire[..], &b"HTTP/1.1 304 Not Modified\r\n\r\n"[..]);
+    }
+
+    #[test]
+    fn rejects_header_injection() {
+        let mut ch = channel();
+        ch.write_outbound(Response::new(200).with_header("X-Evil", "a\r\nSet-Cookie: b"));
+        assert_eq!(ch.read_outbound(), None);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::MalformedHttp("header contains CR or LF"))
+        );
+    }
+}
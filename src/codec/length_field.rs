@@ -0,0 +1,520 @@
+use bytes::{Bytes, BytesMut};
+use codec::{CodecError, Decoder, Encoder};
+use std::cmp;
+
+// A u64 needs at most 10 groups of 7 bits
+const MAX_VARINT_LEN: usize = 10;
+
+/// Width of a length field.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LengthField {
+    U8,
+    U16,
+    U32,
+    U64,
+    /// Unsigned LEB128, as used by protobuf.
+    Varint,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ByteOrder {
+    BigEndian,
+    LittleEndian,
+}
+
+/// Describes where a frame's length lives and how to interpret it.
+///
+/// The frame length is computed like Netty's: `length + length_adjustment + length_field_end`,
+/// where `length_field_end` is the offset of the first byte after the length field.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LengthFieldConfig {
+    pub max_frame_length: usize,
+    pub length_field_offset: usize,
+    pub length_field: LengthField,
+    pub byte_order: ByteOrder,
+    pub length_adjustment: i64,
+    pub initial_bytes_to_strip: usize,
+    // Strips everything up to the end of the length field, whatever its encoded width
+    pub strip_length_field: bool,
+}
+
+/// Splits a byte stream into frames according to a `LengthFieldConfig`.
+///
+/// Frames longer than `max_frame_length` are reported once and then discarded as their bytes
+/// arrive, after which decoding resumes with the next frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LengthFieldBasedFrameDecoder {
+    config: LengthFieldConfig,
+    bytes_to_discard: usize,
+}
+
+/// Prepends a length field to outgoing messages.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LengthFieldPrepender {
+    length_field: LengthField,
+    byte_order: ByteOrder,
+    length_adjustment: i64,
+    length_includes_length_field: bool,
+}
+
+/// Length-prefixed framing in both directions, for use as a channel codec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LengthFieldCodec {
+    decoder: LengthFieldBasedFrameDecoder,
+    prepender: LengthFieldPrepender,
+}
+
+/// Matching decoder and prepender configuration for a length-prefixed channel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LengthFraming {
+    pub decoder: LengthFieldConfig,
+    pub prepender: LengthFieldPrepender,
+}
+
+// impl LengthField
+impl LengthField {
+    pub fn width(&self) -> Option<usize> {
+        match *self {
+            LengthField::U8 => Some(1),
+            LengthField::U16 => Some(2),
+            LengthField::U32 => Some(4),
+            LengthField::U64 => Some(8),
+            LengthField::Varint => None,
+        }
+    }
+
+    pub fn max_value(&self) -> u64 {
+        match *self {
+            LengthField::U8 => u64::from(u8::MAX),
+            LengthField::U16 => u64::from(u16::MAX),
+            LengthField::U32 => u64::from(u32::MAX),
+            LengthField::U64 | LengthField::Varint => u64::MAX,
+        }
+    }
+
+    // Returns the length and the number of bytes it occupied, or `None` if `buf` is too short
+    fn read(&self, buf: &[u8], order: ByteOrder) -> Result<Option<(u64, usize)>, CodecError> {
+        match self.width() {
+            Some(width) => {
+                if buf.len() < width {
+                    return Ok(None);
+                }
+                let field = &buf[..width];
+                let value = match order {
+                    ByteOrder::BigEndian => {
+                        field.iter().fold(0u64, |acc, b| acc << 8 | u64::from(*b))
+                    }
+                    ByteOrder::LittleEndian => field
+                        .iter()
+                        .rev()
+                        .fold(0u64, |acc, b| acc << 8 | u64::from(*b)),
+                };
+                Ok(Some((value, width)))
+            }
+            None => {
+                let mut value = 0u64;
+                for (idx, b) in buf.iter().enumerate().take(MAX_VARINT_LEN) {
+                    // The last byte only holds the top bit of a u64 and never continues
+                    if idx == MAX_VARINT_LEN - 1 && *b > 1 {
+                        return Err(CodecError::CorruptedLengthField);
+                    }
+                    value |= u64::from(b & 0x7f) << (7 * idx);
+                    if b & 0x80 == 0 {
+                        return Ok(Some((value, idx + 1)));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn write(&self, value: u64, order: ByteOrder, dst: &mut BytesMut) {
+        match self.width() {
+            Some(width) => {
+                let bytes = match order {
+                    ByteOrder::BigEndian => value.to_be_bytes(),
+                    ByteOrder::LittleEndian => value.to_le_bytes(),
+                };
+                match order {
+                    ByteOrder::BigEndian => dst.extend_from_slice(&bytes[8 - width..]),
+                    ByteOrder::LittleEndian => dst.extend_from_slice(&bytes[..width]),
+                }
+            }
+            None => {
+                let mut value = value;
+                let mut field = [0u8; MAX_VARINT_LEN];
+                let mut len = 0;
+                loop {
+                    let b = (value & 0x7f) as u8;
+                    value >>= 7;
+                    if value == 0 {
+                        field[len] = b;
+                        len += 1;
+                        break;
+                    }
+                    field[len] = b | 0x80;
+                    len += 1;
+                }
+                dst.extend_from_slice(&field[..len]);
+            }
+        }
+    }
+
+    fn encoded_len(&self, value: u64) -> usize {
+        match self.width() {
+            Some(width) => width,
+            None => cmp::max(1, (64 - value.leading_zeros() as usize).div_ceil(7)),
+        }
+    }
+}
+
+// impl LengthFieldConfig
+impl LengthFieldConfig {
+    pub fn new(length_field: LengthField, max_frame_length: usize) -> Self {
+        LengthFieldConfig {
+            max_frame_length,
+            length_field_offset: 0,
+            length_field,
+            byte_order: ByteOrder::BigEndian,
+            length_adjustment: 0,
+            initial_bytes_to_strip: 0,
+            strip_length_field: false,
+        }
+    }
+
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.length_field_offset = offset;
+        self
+    }
+
+    pub fn with_byte_order(mut self, order: ByteOrder) -> Self {
+        self.byte_order = order;
+        self
+    }
+
+    pub fn with_adjustment(mut self, adjustment: i64) -> Self {
+        self.length_adjustment = adjustment;
+        self
+    }
+
+    pub fn with_strip(mut self, initial_bytes_to_strip: usize) -> Self {
+        self.initial_bytes_to_strip = initial_bytes_to_strip;
+        self
+    }
+
+    /// Strips the offset bytes and the length field itself; required for stripping varint headers.
+    pub fn with_length_field_stripped(mut self) -> Self {
+        self.strip_length_field = true;
+        self
+    }
+}
+
+// impl LengthFieldBasedFrameDecoder
+impl LengthFieldBasedFrameDecoder {
+    pub fn new(config: LengthFieldConfig) -> Self {
+        LengthFieldBasedFrameDecoder {
+            config,
+            bytes_to_discard: 0,
+        }
+    }
+
+    pub fn config(&self) -> &LengthFieldConfig {
+        &self.config
+    }
+}
+
+impl Decoder for LengthFieldBasedFrameDecoder {
+    type Item = Bytes;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>, CodecError> {
+        if self.bytes_to_discard > 0 {
+            let discard = cmp::min(self.bytes_to_discard, buf.len());
+            buf.advance(discard);
+            self.bytes_to_discard -= discard;
+            if self.bytes_to_discard > 0 {
+                return Ok(None);
+            }
+        }
+
+        let offset = self.config.length_field_offset;
+        if buf.len() <= offset {
+            return Ok(None);
+        }
+        let (length, field_len) = match self
+            .config
+            .length_field
+            .read(&buf[offset..], self.config.byte_order)
+        {
+            Ok(Some(field)) => field,
+            Ok(None) => return Ok(None),
+            Err(why) => {
+                // No way to find the next frame boundary in a corrupted stream
+                buf.clear();
+                return Err(why);
+            }
+        };
+
+        let header_len = offset + field_len;
+        let frame_len =
+            (length as i128) + i128::from(self.config.length_adjustment) + header_len as i128;
+        if frame_len < header_len as i128 {
+            buf.advance(header_len);
+            return Err(CodecError::InvalidFrameLength(frame_len as i64));
+        }
+
+        let max = self.config.max_frame_length;
+        if frame_len > max as i128 {
+            let frame_len = cmp::min(frame_len, u64::MAX as i128) as u64;
+            if frame_len <= buf.len() as u64 {
+                buf.advance(frame_len as usize);
+            } else {
+                self.bytes_to_discard = cmp::min(frame_len, usize::MAX as u64) as usize - buf.len();
+                buf.clear();
+            }
+            return Err(CodecError::FrameTooLong {
+                length: frame_len,
+                max,
+            });
+        }
+
+        let frame_len = frame_len as usize;
+        if buf.len() < frame_len {
+            buf.reserve(frame_len - buf.len());
+            return Ok(None);
+        }
+
+        let strip = if self.config.strip_length_field {
+            header_len
+        } else {
+            self.config.initial_bytes_to_strip
+        };
+        if strip > frame_len {
+            buf.advance(frame_len);
+            return Err(CodecError::InvalidFrameLength(frame_len as i64));
+        }
+        let mut frame = buf.split_to(frame_len);
+        frame.advance(strip);
+        Ok(Some(frame.freeze()))
+    }
+}
+
+// impl LengthFieldPrepender
+impl LengthFieldPrepender {
+    pub fn new(length_field: LengthField) -> Self {
+        LengthFieldPrepender {
+            length_field,
+            byte_order: ByteOrder::BigEndian,
+            length_adjustment: 0,
+            length_includes_length_field: false,
+        }
+    }
+
+    pub fn with_byte_order(mut self, order: ByteOrder) -> Self {
+        self.byte_order = order;
+        self
+    }
+
+    pub fn with_adjustment(mut self, adjustment: i64) -> Self {
+        self.length_adjustment = adjustment;
+        self
+    }
+
+    pub fn with_length_includes_length_field(mut self, includes: bool) -> Self {
+        self.length_includes_length_field = includes;
+        self
+    }
+
+    /// Writes the length field followed by `msg` into `dst`.
+    pub fn prepend(&self, msg: &[u8], dst: &mut BytesMut) -> Result<(), CodecError> {
+        let mut length = msg.len() as i128 + i128::from(self.length_adjustment);
+        if self.length_includes_length_field {
+            // Counting a varint's own width can widen it, so settle on a fixed point
+            let body = cmp::max(length, 0) as u64;
+            let mut field_len = self.length_field.encoded_len(body);
+            loop {
+                let widened = self.length_field.encoded_len(body + field_len as u64);
+                if widened == field_len {
+                    break;
+                }
+                field_len = widened;
+            }
+            length += field_len as i128;
+        }
+        if length < 0 {
+            return Err(CodecError::InvalidFrameLength(length as i64));
+        }
+        let max = self.length_field.max_value();
+        if length > i128::from(max) {
+            return Err(CodecError::MessageTooLong {
+                length: cmp::min(length, u64::MAX as i128) as u64,
+                max,
+            });
+        }
+
+        let length = length as u64;
+        dst.reserve(self.length_field.encoded_len(length) + msg.len());
+        self.length_field.write(length, self.byte_order, dst);
+        dst.extend_from_slice(msg);
+        Ok(())
+    }
+}
+
+impl Encoder for LengthFieldPrepender {
+    type Item = Bytes;
+
+    fn encode(&mut self, msg: Bytes, dst: &mut BytesMut) -> Result<(), CodecError> {
+        self.prepend(&msg, dst)
+    }
+}
+
+// impl LengthFraming
+impl LengthFraming {
+    /// Symmetric framing: a plain length prefix of the given width, no offset or adjustment.
+    pub fn new(length_field: LengthField, max_frame_length: usize) -> Self {
+        LengthFraming {
+            decoder: LengthFieldConfig::new(length_field, max_frame_length)
+                .with_length_field_stripped(),
+            prepender: LengthFieldPrepender::new(length_field),
+        }
+    }
+}
+
+// impl LengthFieldCodec
+impl LengthFieldCodec {
+    pub fn new(framing: LengthFraming) -> Self {
+        LengthFieldCodec {
+            decoder: LengthFieldBasedFrameDecoder::new(framing.decoder),
+            prepender: framing.prepender,
+        }
+    }
+}
+
+impl Decoder for LengthFieldCodec {
+    type Item = Bytes;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>, CodecError> {
+        self.decoder.decode(buf)
+    }
+}
+
+impl Encoder for LengthFieldCodec {
+    type Item = Bytes;
+
+    fn encode(&mut self, msg: Bytes, dst: &mut BytesMut) -> Result<(), CodecError> {
+        self.prepender.prepend(&msg, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::embedded::EmbeddedChannel;
+
+    fn channel(length_field: LengthField, max: usize) -> EmbeddedChannel<LengthFieldCodec> {
+        EmbeddedChannel::new(LengthFieldCodec::new(LengthFraming::new(length_field, max)))
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        let mut ch = channel(LengthField::U16, 64);
+        ch.write_inbound(&b"\x00\x05he"[..]);
+        assert_eq!(ch.read_inbound(), None);
+        ch.write_inbound(&b"llo\x00\x01x\x00"[..]);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("hello")));
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("x")));
+        assert_eq!(ch.read_inbound(), None);
+        ch.write_inbound(&b"\x02ok"[..]);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("ok")));
+    }
+
+    #[test]
+    fn little_endian_offset_and_adjustment() {
+        // A 2-byte tag, then a length that counts the whole frame
+        let config = LengthFieldConfig::new(LengthField::U16, 64)
+            .with_offset(2)
+            .with_byte_order(ByteOrder::LittleEndian)
+            .with_adjustment(-4)
+            .with_strip(4);
+        let framing = LengthFraming {
+            decoder: config,
+            prepender: LengthFieldPrepender::new(LengthField::U16),
+        };
+        let mut ch = EmbeddedChannel::new(LengthFieldCodec::new(framing));
+        ch.write_inbound(&b"\xca\xfe\x07\x00abc"[..]);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("abc")));
+    }
+
+    #[test]
+    fn frames_over_max_length_are_discarded() {
+        let mut ch = channel(LengthField::U8, 4);
+        ch.write_inbound(&b"\x0a01234"[..]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::FrameTooLong { length: 11, max: 4 })
+        );
+        // The rest of the long frame is dropped as it arrives; the next frame decodes
+        ch.write_inbound(&b"56789\x01z"[..]);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("z")));
+        assert_eq!(ch.read_codec_error(), None);
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        let mut ch = channel(LengthField::Varint, 1024);
+        let msg = Bytes::from(vec![7u8; 300]);
+        ch.write_outbound(msg.clone());
+        let wire = ch.read_outbound().expect("encoded frame");
+        assert_eq!(&wire[..2], &[0xac, 0x02]);
+        assert_eq!(wire.len(), 302);
+
+        ch.write_inbound(&wire[..1]);
+        assert_eq!(ch.read_inbound(), None);
+        ch.write_inbound(&wire[1..]);
+        assert_eq!(ch.read_inbound(), Some(msg));
+    }
+
+    #[test]
+    fn varint_past_u64_is_corrupted() {
+        let mut overflowing = vec![0xffu8; 9];
+        overflowing.push(0x02);
+        let mut ch = channel(LengthField::Varint, 1024);
+        ch.write_inbound(overflowing);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::CorruptedLengthField)
+        );
+
+        let mut continuing = vec![0x80u8; 10];
+        continuing.push(0x00);
+        let mut ch = channel(LengthField::Varint, 1024);
+        ch.write_inbound(continuing);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::CorruptedLengthField)
+        );
+
+        // u64::MAX itself is well-formed, just too long
+        let mut max = vec![0xffu8; 9];
+        max.push(0x01);
+        let mut ch = channel(LengthField::Varint, 1024);
+        ch.write_inbound(max);
+        match ch.read_codec_error() {
+            Some(CodecError::FrameTooLong { max: 1024, .. }) => {}
+            other => panic!("expected FrameTooLong, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn prepender_rejects_messages_the_field_cannot_describe() {
+        let mut ch = channel(LengthField::U8, 1024);
+        ch.write_outbound(Bytes::from(vec![0u8; 256]));
+        assert_eq!(ch.read_outbound(), None);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::MessageTooLong {
+                length: 256,
+                max: 255
+            })
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use bytes::{Bytes, BytesMut};
+use codec::{CodecError, Decoder, Encoder};
+
+/// Cumulates bytes across reads and decodes as many messages as they hold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteToMessageDecoder<D> {
+    decoder: D,
+    cumulation: BytesMut,
+}
+
+/// Turns typed messages into `Bytes` ready for the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageToByteEncoder<E> {
+    encoder: E,
+}
+
+/// Pass-through codec: every read is delivered as-is and writes are sent unchanged.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct BytesCodec;
+
+// impl ByteToMessageDecoder
+impl<D: Decoder> ByteToMessageDecoder<D> {
+    pub fn new(decoder: D) -> Self {
+        ByteToMessageDecoder {
+            decoder,
+            cumulation: BytesMut::new(),
+        }
+    }
+
+    pub fn decoder(&self) -> &D {
+        &self.decoder
+    }
+
+    /// Bytes received but not yet part of a complete message.
+    pub fn buffered(&self) -> usize {
+        self.cumulation.len()
+    }
+
+    /// Appends `data` and decodes until the cumulation holds no further complete message.
+    ///
+    /// Decoding goes on past an error if the decoder consumed input, so that messages behind a
+    /// malformed one are not held back. An error that consumed nothing ends this call; whatever
+    /// remains is retried on the next one.
+    pub fn decode<F>(&mut self, data: &[u8], mut emit: F)
+    where
+        F: FnMut(Result<D::Item, CodecError>),
+    {
+        self.cumulation.extend_from_slice(data);
+        loop {
+            let before = self.cumulation.len();
+            match self.decoder.decode(&mut self.cumulation) {
+                Ok(Some(msg)) => {
+                    emit(Ok(msg));
+                    // Guards against decoders that produce messages without consuming input
+                    if self.cumulation.is_empty() || self.cumulation.len() == before {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(why) => {
+                    emit(Err(why));
+                    if self.cumulation.is_empty() || self.cumulation.len() == before {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// impl MessageToByteEncoder
+impl<E: Encoder> MessageToByteEncoder<E> {
+    pub fn new(encoder: E) -> Self {
+        MessageToByteEncoder { encoder }
+    }
+
+    pub fn encoder(&self) -> &E {
+        &self.encoder
+    }
+
+    pub fn encode(&mut self, msg: E::Item) -> Result<Bytes, CodecError> {
+        let mut dst = BytesMut::new();
+        self.encoder.encode(msg, &mut dst)?;
+        Ok(dst.freeze())
+    }
+}
+
+// impl BytesCodec
+impl Decoder for BytesCodec {
+    type Item = Bytes;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>, CodecError> {
+        if buf.is_empty() {
+            Ok(None)
+        } else {
+            let len = buf.len();
+            Ok(Some(buf.split_to(len).freeze()))
+        }
+    }
+}
+
+impl Encoder for BytesCodec {
+    type Item = Bytes;
+
+    fn encode(&mut self, msg: Bytes, dst: &mut BytesMut) -> Result<(), CodecError> {
+        dst.extend_from_slice(&msg);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::{LengthField, LengthFieldCodec, LengthFraming, LineCodec};
+    use transport::embedded::EmbeddedChannel;
+
+    #[test]
+    fn bytes_codec_passes_data_through() {
+        let mut ch = EmbeddedChannel::new(BytesCodec);
+        ch.write_inbound(&b"abc"[..]);
+        ch.write_inbound(&b"def"[..]);
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("abc")));
+        assert_eq!(ch.read_inbound(), Some(Bytes::from("def")));
+        ch.write_outbound(Bytes::from("xyz"));
+        assert_eq!(ch.read_outbound(), Some(Bytes::from("xyz")));
+    }
+
+    #[test]
+    fn cumulates_until_a_message_is_complete() {
+        let framing = LengthFraming::new(LengthField::U8, 16);
+        let mut decoder = ByteToMessageDecoder::new(LengthFieldCodec::new(framing));
+        let mut msgs = Vec::new();
+        decoder.decode(b"\x03a", |msg| msgs.push(msg));
+        assert!(msgs.is_empty());
+        assert_eq!(decoder.buffered(), 2);
+        decoder.decode(b"bc\x01d\x02", |msg| msgs.push(msg));
+        assert_eq!(msgs, vec![Ok(Bytes::from("abc")), Ok(Bytes::from("d"))]);
+        assert_eq!(decoder.buffered(), 1);
+    }
+
+    #[test]
+    fn decoding_goes_on_past_errors_that_consume_input() {
+        let mut ch = EmbeddedChannel::new(LineCodec::new(64));
+        ch.write_inbound(&b"\xff\nok\n"[..]);
+        assert!(ch.read_codec_error().is_some());
+        assert_eq!(ch.read_inbound(), Some("ok".to_owned()));
+    }
+}
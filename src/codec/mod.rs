@@ -0,0 +1,111 @@
+use bytes::BytesMut;
+use std::error;
+use std::fmt;
+use std::fmt::Debug;
+
+pub mod delimiter;
+pub mod http;
+pub mod length_field;
+pub mod message;
+pub mod resp;
+pub mod string;
+pub mod typed;
+pub mod websocket;
+
+pub use self::delimiter::{DelimiterBasedFrameDecoder, LineBasedFrameDecoder};
+pub use self::http::HttpServerCodec;
+pub use self::length_field::{
+    ByteOrder, LengthField, LengthFieldBasedFrameDecoder, LengthFieldCodec, LengthFieldConfig,
+    LengthFieldPrepender, LengthFraming,
+};
+pub use self::message::{ByteToMessageDecoder, BytesCodec, MessageToByteEncoder};
+pub use self::resp::RespCodec;
+pub use self::string::{LineCodec, StringDecoder, StringEncoder};
+pub use self::typed::{Format, TypedCodec};
+pub use self::websocket::{WebSocketCodec, WebSocketServerCodec};
+
+/// Decodes messages off the front of a byte buffer.
+pub trait Decoder {
+    type Item: Debug;
+
+    /// Consumes one message from `buf`, or returns `None` without consuming anything if `buf`
+    /// does not yet hold a complete message.
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, CodecError>;
+}
+
+/// Encodes messages into a byte buffer.
+pub trait Encoder {
+    type Item;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), CodecError>;
+}
+
+/// A decoder and encoder pair that can be cloned for every new channel.
+pub trait Codec: Decoder + Encoder + Clone + Debug {}
+
+impl<T: Decoder + Encoder + Clone + Debug> Codec for T {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// The frame announced by a length field exceeds the configured maximum.
+    FrameTooLong { length: u64, max: usize },
+    /// The length field, once adjusted, describes a frame shorter than its own header.
+    InvalidFrameLength(i64),
+    /// A varint length field ran past the 10 bytes needed to encode a `u64`.
+    CorruptedLengthField,
+    /// An outgoing message is too long to be described by the length field.
+    MessageTooLong { length: u64, max: u64 },
+    /// A frame decoded as text was not valid UTF-8.
+    InvalidUtf8 { valid_up_to: usize },
+    /// An outgoing message could not be serialized.
+    Serialize(String),
+    /// A frame could not be deserialized into the expected type.
+    Deserialize(String),
+    /// An HTTP request head grew past the configured maximum without terminating.
+    HeaderTooLarge { max: usize },
+    /// An HTTP message violated the protocol.
+    MalformedHttp(&'static str),
+    /// A WebSocket frame violated RFC 6455.
+    WebSocketProtocol(&'static str),
+    /// A RESP value violated the protocol.
+    MalformedResp(&'static str),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CodecError::FrameTooLong { length, max } => {
+                write!(f, "frame length {} exceeds maximum of {}", length, max)
+            }
+            CodecError::InvalidFrameLength(len) => write!(f, "invalid frame length {}", len),
+            CodecError::CorruptedLengthField => write!(f, "corrupted varint length field"),
+            CodecError::MessageTooLong { length, max } => {
+                write!(
+                    f,
+                    "message length {} exceeds length field maximum of {}",
+                    length, max
+                )
+            }
+            CodecError::InvalidUtf8 { valid_up_to } => {
+                write!(f, "invalid UTF-8 after {} valid bytes", valid_up_to)
+            }
+            CodecError::Serialize(ref why) => write!(f, "serialization failed: {}", why),
+            CodecError::Deserialize(ref why) => write!(f, "deserialization failed: {}", why),
+            CodecError::HeaderTooLarge { max } => {
+                write!(f, "HTTP header exceeds maximum of {} bytes", max)
+            }
+            CodecError::MalformedHttp(why) => write!(f, "malformed HTTP message: {}", why),
+            CodecError::WebSocketProtocol(why) => write!(f, "WebSocket protocol error: {}", why),
+            CodecError::MalformedResp(why) => write!(f, "malformed RESP value: {}", why),
+        }
+    }
+}
+
+impl error::Error for CodecError {}
+
+// Offset of the first occurrence of `needle` in `haystack`
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
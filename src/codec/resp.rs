@@ -0,0 +1,739 @@
+use bytes::{Bytes, BytesMut};
+use codec::{CodecError, Decoder, Encoder};
+use std::cmp;
+use std::str;
+
+// Redis' own limit for a single bulk string
+const DEFAULT_MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+const DEFAULT_MAX_DEPTH: usize = 64;
+// Longest simple string, error or header line accepted, without its CRLF
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// RESP version used when encoding; both are always accepted when decoding.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+/// A RESP value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    BulkString(Bytes),
+    /// Also produced for RESP3 sets.
+    Array(Vec<Value>),
+    /// RESP2 null bulk string or array, or RESP3 null.
+    Null,
+    /// RESP3 only; sent as a flat array of keys and values over RESP2.
+    Map(Vec<(Value, Value)>),
+    /// RESP3 only; sent as the integer 0 or 1 over RESP2.
+    Boolean(bool),
+    /// RESP3 only; sent as a bulk string over RESP2.
+    Double(f64),
+    /// RESP3 only; sent as a bulk string over RESP2.
+    BigNumber(String),
+    /// RESP3 only; `format` is three bytes such as `txt` or `mkd`. Sent as a bulk string of just
+    /// `text` over RESP2.
+    Verbatim {
+        format: String,
+        text: Bytes,
+    },
+    /// RESP3 only; sent as a simple error over RESP2.
+    BlobError(Bytes),
+    /// RESP3 only; sent as an array over RESP2.
+    Push(Vec<Value>),
+    /// RESP3 only; attributes that came ahead of `value`. Only `value` is sent over RESP2.
+    Attribute {
+        attributes: Vec<(Value, Value)>,
+        value: Box<Value>,
+    },
+}
+
+/// Decodes and encodes RESP values, for Redis clients and Redis-protocol servers alike.
+///
+/// Decoding resumes where the previous read left off, so a large aggregate arriving over many
+/// reads is parsed once. A malformed value is reported once and skipped; values pipelined after
+/// it are still decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RespCodec {
+    protocol: Protocol,
+    max_bulk_length: usize,
+    max_depth: usize,
+    // Aggregates whose elements are still arriving, outermost first
+    stack: Vec<Partial>,
+    // Bytes of the current line already searched for its LF
+    scanned: usize,
+    // Payload bytes of a rejected bulk string still to be dropped
+    discard: usize,
+    // Dropping the rest of an over-long line
+    discard_line: bool,
+    // Elements of a rejected aggregate still to be dropped
+    skip: usize,
+}
+
+// A value, or the header of an aggregate whose elements follow it
+enum Element {
+    Value(Value),
+    Open(Aggregate, usize),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Aggregate {
+    Array,
+    Map,
+    Push,
+    Attribute,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Partial {
+    kind: Aggregate,
+    // Elements expected in all, counting map keys and values separately
+    len: usize,
+    items: Vec<Value>,
+}
+
+// impl Value
+impl Value {
+    /// A command as sent by clients: an array of bulk strings.
+    pub fn command<I, A>(args: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<Bytes>,
+    {
+        Value::Array(
+            args.into_iter()
+                .map(|a| Value::BulkString(a.into()))
+                .collect(),
+        )
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Value::Null
+    }
+
+    /// The bytes of a simple or bulk string.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Value::SimpleString(ref s) => Some(s.as_bytes()),
+            Value::BulkString(ref b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+// impl RespCodec
+impl RespCodec {
+    pub fn new(protocol: Protocol) -> Self {
+        RespCodec {
+            protocol,
+            max_bulk_length: DEFAULT_MAX_BULK_LENGTH,
+            max_depth: DEFAULT_MAX_DEPTH,
+            stack: Vec::new(),
+            scanned: 0,
+            discard: 0,
+            discard_line: false,
+            skip: 0,
+        }
+    }
+
+    pub fn with_max_bulk_length(mut self, max_bulk_length: usize) -> Self {
+        self.max_bulk_length = max_bulk_length;
+        self
+    }
+
+    /// Limits how deeply aggregates may nest.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    // Drops what is left of a rejected line or bulk string; false while more is to come
+    fn drain(&mut self, buf: &mut BytesMut) -> bool {
+        if self.discard > 0 {
+            let len = cmp::min(self.discard, buf.len());
+            buf.advance(len);
+            self.discard -= len;
+            if self.discard > 0 {
+                return false;
+            }
+        }
+        if self.discard_line {
+            match buf.iter().position(|b| *b == b'\n') {
+                Some(eol) => {
+                    buf.advance(eol + 1);
+                    self.discard_line = false;
+                }
+                None => {
+                    buf.clear();
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    // Takes the next element off the front of `buf`. A malformed element is consumed before its
+    // error is returned.
+    fn next_element(&mut self, buf: &mut BytesMut) -> Result<Option<Element>, CodecError> {
+        let limit = cmp::min(buf.len(), MAX_LINE_LENGTH + 2);
+        let eol = match buf[self.scanned..limit].iter().position(|b| *b == b'\n') {
+            Some(idx) => self.scanned + idx,
+            None if limit == MAX_LINE_LENGTH + 2 => {
+                buf.advance(limit);
+                self.scanned = 0;
+                self.discard_line = true;
+                return Err(CodecError::MalformedResp("line too long"));
+            }
+            None => {
+                self.scanned = limit;
+                return Ok(None);
+            }
+        };
+        if eol == 0 || buf[eol - 1] != b'\r' {
+            buf.advance(eol + 1);
+            self.scanned = 0;
+            return Err(CodecError::MalformedResp("line not terminated by CRLF"));
+        }
+        match self.parse_element(buf, eol) {
+            Ok(Some((element, len))) => {
+                buf.advance(len);
+                self.scanned = 0;
+                Ok(Some(element))
+            }
+            Ok(None) => Ok(None),
+            Err(why) => {
+                buf.advance(eol + 1);
+                self.scanned = 0;
+                Err(why)
+            }
+        }
+    }
+
+    // Parses the element whose first line ends with the LF at `eol`; returns it with its length,
+    // or `None` if its payload has yet to arrive
+    fn parse_element(
+        &mut self,
+        buf: &[u8],
+        eol: usize,
+    ) -> Result<Option<(Element, usize)>, CodecError> {
+        let line = &buf[..eol - 1];
+        let next = eol + 1;
+        if line.is_empty() {
+            return Err(CodecError::MalformedResp("empty line"));
+        }
+        let body = &line[1..];
+        let value = match line[0] {
+            b'+' => Value::SimpleString(utf8(body)?.to_owned()),
+            b'-' => Value::Error(utf8(body)?.to_owned()),
+            b':' => Value::Integer(parse_int(body)?),
+            b'_' => {
+                if !body.is_empty() {
+                    return Err(CodecError::MalformedResp("invalid null"));
+                }
+                Value::Null
+            }
+            b'#' => match body {
+                b"t" => Value::Boolean(true),
+                b"f" => Value::Boolean(false),
+                _ => return Err(CodecError::MalformedResp("invalid boolean")),
+            },
+            b',' => Value::Double(parse_double(body)?),
+            b'(' => Value::BigNumber(parse_big_number(body)?),
+            kind @ b'$' | kind @ b'=' | kind @ b'!' => {
+                let len = parse_int(body)?;
+                if len == -1 && kind == b'$' {
+                    return Ok(Some((Element::Value(Value::Null), next)));
+                }
+                let len = self.check_length(len)?;
+                if len as u64 > self.max_bulk_length as u64 {
+                    // Skips the payload as it arrives rather than parsing it as lines
+                    self.discard = len.saturating_add(2);
+                    return Err(CodecError::FrameTooLong {
+                        length: len as u64,
+                        max: self.max_bulk_length,
+                    });
+                }
+                let end = next + len + 2;
+                if buf.len() < end {
+                    return Ok(None);
+                }
+                let value = if &buf[end - 2..end] != b"\r\n" {
+                    Err(CodecError::MalformedResp("missing CRLF after bulk string"))
+                } else {
+                    blob(kind, &buf[next..end - 2])
+                };
+                return match value {
+                    Ok(value) => Ok(Some((Element::Value(value), end))),
+                    Err(why) => {
+                        self.discard = len + 2;
+                        Err(why)
+                    }
+                };
+            }
+            kind @ b'*' | kind @ b'~' | kind @ b'%' | kind @ b'>' | kind @ b'|' => {
+                let len = parse_int(body)?;
+                if len == -1 && kind == b'*' {
+                    return Ok(Some((Element::Value(Value::Null), next)));
+                }
+                let len = self.check_length(len)?;
+                let kind = match kind {
+                    b'%' => Aggregate::Map,
+                    b'>' => Aggregate::Push,
+                    b'|' => Aggregate::Attribute,
+                    _ => Aggregate::Array,
+                };
+                return Ok(Some((Element::Open(kind, len), next)));
+            }
+            _ => return Err(CodecError::MalformedResp("unknown type byte")),
+        };
+        Ok(Some((Element::Value(value), next)))
+    }
+
+    // Gives up on the value being decoded after one of its elements failed; the elements it
+    // still expects, plus `children` of the failed one, are skipped as they arrive
+    fn abandon(&mut self, children: usize) {
+        let remaining = self
+            .stack
+            .drain(..)
+            .fold(0usize, |acc, p| acc.saturating_add(p.len - p.items.len()));
+        // The failed element was one of them
+        self.skip = remaining.saturating_sub(1).saturating_add(children);
+    }
+
+    fn check_length(&self, len: i64) -> Result<usize, CodecError> {
+        if len < 0 {
+            return Err(CodecError::MalformedResp("negative length"));
+        }
+        Ok(len as usize)
+    }
+
+    fn write(&self, value: &Value, dst: &mut BytesMut) -> Result<(), CodecError> {
+        match *value {
+            Value::SimpleString(ref s) => write_line(b'+', s, dst)?,
+            Value::Error(ref s) => write_line(b'-', s, dst)?,
+            Value::Integer(n) => write_header(b':', n, dst),
+            Value::BulkString(ref data) => write_bulk(data, dst),
+            Value::Array(ref items) => {
+                write_header(b'*', items.len() as i64, dst);
+                for item in items {
+                    self.write(item, dst)?;
+                }
+            }
+            Value::Null => match self.protocol {
+                Protocol::Resp2 => dst.extend_from_slice(b"$-1\r\n"),
+                Protocol::Resp3 => dst.extend_from_slice(b"_\r\n"),
+            },
+            Value::Map(ref entries) => {
+                match self.protocol {
+                    Protocol::Resp2 => write_header(b'*', 2 * entries.len() as i64, dst),
+                    Protocol::Resp3 => write_header(b'%', entries.len() as i64, dst),
+                }
+                self.write_pairs(entries, dst)?;
+            }
+            Value::Boolean(b) => match self.protocol {
+                Protocol::Resp2 => write_header(b':', i64::from(b), dst),
+                Protocol::Resp3 => dst.extend_from_slice(if b { b"#t\r\n" } else { b"#f\r\n" }),
+            },
+            Value::Double(d) => {
+                let text = format_double(d);
+                match self.protocol {
+                    Protocol::Resp2 => write_bulk(text.as_bytes(), dst),
+                    Protocol::Resp3 => write_line(b',', &text, dst)?,
+                }
+            }
+            Value::BigNumber(ref n) => match self.protocol {
+                Protocol::Resp2 => write_bulk(n.as_bytes(), dst),
+                Protocol::Resp3 => write_line(b'(', n, dst)?,
+            },
+            Value::Verbatim {
+                ref format,
+                ref text,
+            } => match self.protocol {
+                Protocol::Resp2 => write_bulk(text, dst),
+                Protocol::Resp3 => {
+                    if format.len() != 3 {
+                        return Err(CodecError::MalformedResp("verbatim format not 3 bytes"));
+                    }
+                    write_header(b'=', text.len() as i64 + 4, dst);
+                    dst.reserve(text.len() + 6);
+                    dst.extend_from_slice(format.as_bytes());
+                    dst.extend_from_slice(b":");
+                    dst.extend_from_slice(text);
+                    dst.extend_from_slice(b"\r\n");
+                }
+            },
+            Value::BlobError(ref data) => match self.protocol {
+                Protocol::Resp2 => write_line(b'-', &String::from_utf8_lossy(data), dst)?,
+                Protocol::Resp3 => write_blob(b'!', data, dst),
+            },
+            Value::Push(ref items) => {
+                match self.protocol {
+                    Protocol::Resp2 => write_header(b'*', items.len() as i64, dst),
+                    Protocol::Resp3 => write_header(b'>', items.len() as i64, dst),
+                }
+                for item in items {
+                    self.write(item, dst)?;
+                }
+            }
+            Value::Attribute {
+                ref attributes,
+                ref value,
+            } => {
+                if self.protocol == Protocol::Resp3 {
+                    write_header(b'|', attributes.len() as i64, dst);
+                    self.write_pairs(attributes, dst)?;
+                }
+                self.write(value, dst)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_pairs(&self, pairs: &[(Value, Value)], dst: &mut BytesMut) -> Result<(), CodecError> {
+        for (key, value) in pairs {
+            self.write(key, dst)?;
+            self.write(value, dst)?;
+        }
+        Ok(())
+    }
+}
+
+// impl Aggregate
+impl Aggregate {
+    // Elements following a header announcing `len` entries
+    fn elements(self, len: usize) -> usize {
+        match self {
+            Aggregate::Array | Aggregate::Push => len,
+            Aggregate::Map => len.saturating_mul(2),
+            // The attributed value follows the attributes
+            Aggregate::Attribute => len.saturating_mul(2).saturating_add(1),
+        }
+    }
+
+    fn finish(self, mut items: Vec<Value>) -> Value {
+        match self {
+            Aggregate::Array => Value::Array(items),
+            Aggregate::Push => Value::Push(items),
+            Aggregate::Map => Value::Map(pairs(items)),
+            Aggregate::Attribute => {
+                let value = items.pop().unwrap_or(Value::Null);
+                Value::Attribute {
+                    attributes: pairs(items),
+                    value: Box::new(value),
+                }
+            }
+        }
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Value;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Value>, CodecError> {
+        'elements: loop {
+            if !self.drain(buf) {
+                return Ok(None);
+            }
+            let element = match self.next_element(buf) {
+                Ok(Some(element)) => element,
+                Ok(None) => return Ok(None),
+                // Errors within a value already reported don't count twice
+                Err(_) if self.skip > 0 => {
+                    self.skip -= 1;
+                    continue;
+                }
+                Err(why) => {
+                    self.abandon(0);
+                    return Err(why);
+                }
+            };
+            if self.skip > 0 {
+                self.skip -= 1;
+                if let Element::Open(kind, len) = element {
+                    self.skip = self.skip.saturating_add(kind.elements(len));
+                }
+                continue;
+            }
+            let mut value = match element {
+                Element::Value(value) => value,
+                Element::Open(kind, len) if kind.elements(len) == 0 => kind.finish(Vec::new()),
+                Element::Open(kind, len) => {
+                    let elements = kind.elements(len);
+                    if self.stack.len() >= self.max_depth {
+                        self.abandon(elements);
+                        return Err(CodecError::MalformedResp("aggregate nested too deeply"));
+                    }
+                    self.stack.push(Partial {
+                        kind,
+                        len: elements,
+                        // A bogus count must not reserve memory up front
+                        items: Vec::with_capacity(cmp::min(elements, 1024)),
+                    });
+                    continue;
+                }
+            };
+            while let Some(mut top) = self.stack.pop() {
+                top.items.push(value);
+                if top.items.len() < top.len {
+                    self.stack.push(top);
+                    continue 'elements;
+                }
+                value = top.kind.finish(top.items);
+            }
+            return Ok(Some(value));
+        }
+    }
+}
+
+impl Encoder for RespCodec {
+    type Item = Value;
+
+    fn encode(&mut self, msg: Value, dst: &mut BytesMut) -> Result<(), CodecError> {
+        self.write(&msg, dst)
+    }
+}
+
+fn utf8(data: &[u8]) -> Result<&str, CodecError> {
+    str::from_utf8(data).map_err(|why| CodecError::InvalidUtf8 {
+        valid_up_to: why.valid_up_to(),
+    })
+}
+
+fn parse_int(data: &[u8]) -> Result<i64, CodecError> {
+    utf8(data)?
+        .parse()
+        .map_err(|_| CodecError::MalformedResp("invalid integer"))
+}
+
+fn parse_big_number(data: &[u8]) -> Result<String, CodecError> {
+    let digits = match data.first() {
+        Some(b'-') | Some(b'+') => &data[1..],
+        _ => data,
+    };
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return Err(CodecError::MalformedResp("invalid big number"));
+    }
+    Ok(utf8(data)?.to_owned())
+}
+
+// The value of a bulk string, verbatim string or blob error with payload `data`
+fn blob(kind: u8, data: &[u8]) -> Result<Value, CodecError> {
+    match kind {
+        b'=' => {
+            if data.len() < 4 || data[3] != b':' {
+                return Err(CodecError::MalformedResp("invalid verbatim string"));
+            }
+            Ok(Value::Verbatim {
+                format: utf8(&data[..3])?.to_owned(),
+                text: Bytes::from(&data[4..]),
+            })
+        }
+        b'!' => Ok(Value::BlobError(Bytes::from(data))),
+        _ => Ok(Value::BulkString(Bytes::from(data))),
+    }
+}
+
+fn pairs(items: Vec<Value>) -> Vec<(Value, Value)> {
+    let mut pairs = Vec::with_capacity(items.len() / 2);
+    let mut items = items.into_iter();
+    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+        pairs.push((key, value));
+    }
+    pairs
+}
+
+fn parse_double(data: &[u8]) -> Result<f64, CodecError> {
+    match utf8(data)? {
+        "inf" => Ok(f64::INFINITY),
+        "-inf" => Ok(f64::NEG_INFINITY),
+        "nan" => Ok(f64::NAN),
+        text => text
+            .parse()
+            .map_err(|_| CodecError::MalformedResp("invalid double")),
+    }
+}
+
+fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_owned()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf" } else { "-inf" }.to_owned()
+    } else {
+        d.to_string()
+    }
+}
+
+fn write_line(kind: u8, text: &str, dst: &mut BytesMut) -> Result<(), CodecError> {
+    if text.contains(['\r', '\n']) {
+        return Err(CodecError::MalformedResp("simple string contains CR or LF"));
+    }
+    dst.reserve(text.len() + 3);
+    dst.extend_from_slice(&[kind]);
+    dst.extend_from_slice(text.as_bytes());
+    dst.extend_from_slice(b"\r\n");
+    Ok(())
+}
+
+fn write_header(kind: u8, n: i64, dst: &mut BytesMut) {
+    dst.extend_from_slice(format!("{}{}\r\n", kind as char, n).as_bytes());
+}
+
+fn write_bulk(data: &[u8], dst: &mut BytesMut) {
+    write_blob(b'$', data, dst)
+}
+
+fn write_blob(kind: u8, data: &[u8], dst: &mut BytesMut) {
+    write_header(kind, data.len() as i64, dst);
+    dst.reserve(data.len() + 2);
+    dst.extend_from_slice(data);
+    dst.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::embedded::EmbeddedChannel;
+
+    fn channel(protocol: Protocol) -> EmbeddedChannel<RespCodec> {
+        EmbeddedChannel::new(RespCodec::new(protocol).with_max_bulk_length(16))
+    }
+
+    fn bulk(data: &'static str) -> Value {
+        Value::BulkString(Bytes::from(data))
+    }
+
+    #[test]
+    fn aggregate_split_across_reads() {
+        let mut ch = channel(Protocol::Resp2);
+        let wire = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nhello\r\n:1\r\n";
+        for byte in wire.chunks(1) {
+            ch.write_inbound(byte);
+        }
+        assert_eq!(
+            ch.read_inbound(),
+            Some(Value::command(vec!["SET", "k", "hello"]))
+        );
+        assert_eq!(ch.read_inbound(), Some(Value::Integer(1)));
+        assert_eq!(ch.read_inbound(), None);
+    }
+
+    #[test]
+    fn resp3_types_round_trip() {
+        let values = vec![
+            Value::Null,
+            Value::Boolean(true),
+            Value::Double(1.5),
+            Value::BigNumber("-3492890328409238509324850943850943825024385".to_owned()),
+            Value::Verbatim {
+                format: "txt".to_owned(),
+                text: Bytes::from("Some string"),
+            },
+            Value::BlobError(Bytes::from("ERR\r\nsyntax")),
+            Value::Push(vec![bulk("message"), bulk("news"), bulk("hi")]),
+            Value::Map(vec![(bulk("a"), Value::Integer(1))]),
+            Value::Attribute {
+                attributes: vec![(Value::SimpleString("ttl".to_owned()), Value::Integer(3))],
+                value: Box::new(Value::Array(vec![Value::Integer(1), Value::Integer(2)])),
+            },
+        ];
+        let mut ch = channel(Protocol::Resp3);
+        for value in values.clone() {
+            ch.write_outbound(value);
+        }
+        while let Some(wire) = ch.read_outbound() {
+            ch.write_inbound(wire);
+        }
+        for value in values {
+            assert_eq!(ch.read_inbound(), Some(value));
+        }
+    }
+
+    #[test]
+    fn resp3_types_downgrade_to_resp2() {
+        let mut ch = channel(Protocol::Resp2);
+        ch.write_outbound(Value::Verbatim {
+            format: "txt".to_owned(),
+            text: Bytes::from("hi"),
+        });
+        ch.write_outbound(Value::Push(vec![Value::Integer(1)]));
+        ch.write_outbound(Value::Attribute {
+            attributes: vec![(bulk("a"), bulk("b"))],
+            value: Box::new(Value::Integer(2)),
+        });
+        let wire: Vec<u8> = (0..3)
+            .flat_map(|_| ch.read_outbound().expect("encoded"))
+            .collect();
+        assert_eq!(wire, b"$2\r\nhi\r\n*1\r\n:1\r\n:2\r\n".to_vec());
+    }
+
+    #[test]
+    fn malformed_values_do_not_discard_pipelined_ones() {
+        let mut ch = channel(Protocol::Resp3);
+        ch.write_inbound(&b"?bogus\r\n+OK\r\n*3\r\n:1\r\n#x\r\n*1\r\n:2\r\n:3\r\n"[..]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::MalformedResp("unknown type byte"))
+        );
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::MalformedResp("invalid boolean"))
+        );
+        assert_eq!(ch.read_codec_error(), None);
+        assert_eq!(
+            ch.read_inbound(),
+            Some(Value::SimpleString("OK".to_owned()))
+        );
+        assert_eq!(ch.read_inbound(), Some(Value::Integer(3)));
+        assert_eq!(ch.read_inbound(), None);
+    }
+
+    #[test]
+    fn oversized_bulk_strings_are_skipped() {
+        let mut ch = channel(Protocol::Resp2);
+        ch.write_inbound(&b"$20\r\n0123456789"[..]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::FrameTooLong {
+                length: 20,
+                max: 16
+            })
+        );
+        ch.write_inbound(&b"0123456789\r\n:7\r\n"[..]);
+        assert_eq!(ch.read_inbound(), Some(Value::Integer(7)));
+    }
+
+    #[test]
+    fn lines_are_capped() {
+        let mut ch = channel(Protocol::Resp2);
+        let mut line = vec![b'+'];
+        line.resize(MAX_LINE_LENGTH + 8, b'a');
+        ch.write_inbound(line);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::MalformedResp("line too long"))
+        );
+        ch.write_inbound(&b"aaaa\r\n:5\r\n"[..]);
+        assert_eq!(ch.read_inbound(), Some(Value::Integer(5)));
+        assert_eq!(ch.read_codec_error(), None);
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let mut ch = EmbeddedChannel::new(RespCodec::new(Protocol::Resp2).with_max_depth(1));
+        ch.write_inbound(&b"*1\r\n*1\r\n*1\r\n:1\r\n*1\r\n*0\r\n"[..]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::MalformedResp("aggregate nested too deeply"))
+        );
+        let nested = Value::Array(vec![Value::Array(Vec::new())]);
+        assert_eq!(ch.read_inbound(), Some(nested));
+    }
+}
@@ -0,0 +1,125 @@
+use bytes::{Bytes, BytesMut};
+use codec::{CodecError, Decoder, Encoder, LineBasedFrameDecoder};
+use std::str;
+
+/// Decodes whole frames as UTF-8 text; pair it with a frame decoder.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct StringDecoder;
+
+/// Newline-terminated UTF-8 text in both directions, for line-oriented protocols.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineCodec {
+    decoder: LineBasedFrameDecoder,
+    encoder: StringEncoder,
+}
+
+/// Encodes text as UTF-8, optionally terminating each message with a delimiter.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StringEncoder {
+    delimiter: Option<Bytes>,
+}
+
+// impl StringDecoder
+impl StringDecoder {
+    pub fn new() -> Self {
+        StringDecoder
+    }
+
+    pub fn decode(&self, frame: &Bytes) -> Result<String, CodecError> {
+        str::from_utf8(frame)
+            .map(|s| s.to_owned())
+            .map_err(|why| CodecError::InvalidUtf8 {
+                valid_up_to: why.valid_up_to(),
+            })
+    }
+}
+
+// impl StringEncoder
+impl StringEncoder {
+    pub fn new() -> Self {
+        StringEncoder { delimiter: None }
+    }
+
+    /// Terminates every message with `\n`, for use with a `LineBasedFrameDecoder` on the peer.
+    pub fn lines() -> Self {
+        StringEncoder::new().with_delimiter(Bytes::from_static(b"\n"))
+    }
+
+    pub fn with_delimiter(mut self, delimiter: Bytes) -> Self {
+        self.delimiter = Some(delimiter);
+        self
+    }
+}
+
+impl Encoder for StringEncoder {
+    type Item = String;
+
+    fn encode(&mut self, msg: String, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let delim_len = self.delimiter.as_ref().map_or(0, |d| d.len());
+        dst.reserve(msg.len() + delim_len);
+        dst.extend_from_slice(msg.as_bytes());
+        if let Some(ref delimiter) = self.delimiter {
+            dst.extend_from_slice(delimiter);
+        }
+        Ok(())
+    }
+}
+
+// impl LineCodec
+impl LineCodec {
+    pub fn new(max_length: usize) -> Self {
+        LineCodec {
+            decoder: LineBasedFrameDecoder::new(max_length),
+            encoder: StringEncoder::lines(),
+        }
+    }
+}
+
+impl Decoder for LineCodec {
+    type Item = String;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, CodecError> {
+        match self.decoder.decode(buf)? {
+            Some(line) => StringDecoder.decode(&line).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder for LineCodec {
+    type Item = String;
+
+    fn encode(&mut self, msg: String, dst: &mut BytesMut) -> Result<(), CodecError> {
+        self.encoder.encode(msg, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::embedded::EmbeddedChannel;
+
+    #[test]
+    fn line_codec_round_trip() {
+        let mut ch = EmbeddedChannel::new(LineCodec::new(64));
+        ch.write_outbound("héllo".to_owned());
+        let wire = ch.read_outbound().expect("encoded line");
+        assert_eq!(&wire[..], "héllo\n".as_bytes());
+        ch.write_inbound(&wire[..3]);
+        ch.write_inbound(&wire[3..]);
+        assert_eq!(ch.read_inbound(), Some("héllo".to_owned()));
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported() {
+        let mut ch = EmbeddedChannel::new(LineCodec::new(64));
+        ch.write_inbound(&b"ok\xff\nfine\n"[..]);
+        assert_eq!(
+            ch.read_codec_error(),
+            Some(CodecError::InvalidUtf8 { valid_up_to: 2 })
+        );
+        // Only the bad line is lost; the rest of the same read still decodes
+        assert_eq!(ch.read_inbound(), Some("fine".to_owned()));
+        assert_eq!(ch.read_codec_error(), None);
+    }
+}
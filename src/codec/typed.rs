@@ -0,0 +1,163 @@
+use bincode;
+use bytes::{Bytes, BytesMut};
+use codec::{CodecError, Decoder, Encoder, LengthFieldCodec, LengthFraming};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Wire format used by a `TypedCodec`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Bincode,
+    Json,
+}
+
+/// Sends and receives serde types, one value per length-prefixed frame.
+pub struct TypedCodec<T> {
+    format: Format,
+    framing: LengthFieldCodec,
+    // fn() -> T keeps the codec Send + Sync regardless of T
+    _marker: PhantomData<fn() -> T>,
+}
+
+// impl Format
+impl Format {
+    pub fn serialize<T: Serialize>(&self, msg: &T) -> Result<Vec<u8>, CodecError> {
+        match *self {
+            Format::Bincode => {
+                bincode::serialize(msg).map_err(|why| CodecError::Serialize(why.to_string()))
+            }
+            Format::Json => {
+                serde_json::to_vec(msg).map_err(|why| CodecError::Serialize(why.to_string()))
+            }
+        }
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, CodecError> {
+        match *self {
+            Format::Bincode => {
+                bincode::deserialize(data).map_err(|why| CodecError::Deserialize(why.to_string()))
+            }
+            Format::Json => {
+                serde_json::from_slice(data).map_err(|why| CodecError::Deserialize(why.to_string()))
+            }
+        }
+    }
+}
+
+// impl TypedCodec
+impl<T> TypedCodec<T> {
+    pub fn new(format: Format, framing: LengthFraming) -> Self {
+        TypedCodec {
+            format,
+            framing: LengthFieldCodec::new(framing),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn bincode(framing: LengthFraming) -> Self {
+        TypedCodec::new(Format::Bincode, framing)
+    }
+
+    pub fn json(framing: LengthFraming) -> Self {
+        TypedCodec::new(Format::Json, framing)
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+}
+
+impl<T: DeserializeOwned + fmt::Debug> Decoder for TypedCodec<T> {
+    type Item = T;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<T>, CodecError> {
+        match self.framing.decode(buf)? {
+            Some(frame) => self.format.deserialize(&frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T: Serialize> Encoder for TypedCodec<T> {
+    type Item = T;
+
+    fn encode(&mut self, msg: T, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let data = self.format.serialize(&msg)?;
+        self.framing.encode(Bytes::from(data), dst)
+    }
+}
+
+// Derives would needlessly require T: Clone / T: Debug
+impl<T> Clone for TypedCodec<T> {
+    fn clone(&self) -> Self {
+        TypedCodec {
+            format: self.format,
+            framing: self.framing.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TypedCodec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TypedCodec")
+            .field("format", &self.format)
+            .field("framing", &self.framing)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::LengthField;
+    use transport::embedded::EmbeddedChannel;
+
+    type Msg = (u32, String, Vec<u8>);
+
+    fn round_trip(codec: TypedCodec<Msg>) {
+        let msg: Msg = (7, "seven".to_owned(), vec![1, 2, 3]);
+        let mut ch = EmbeddedChannel::new(codec);
+        ch.write_outbound(msg.clone());
+        ch.write_outbound(msg.clone());
+        let mut wire = BytesMut::new();
+        while let Some(frame) = ch.read_outbound() {
+            wire.extend_from_slice(&frame);
+        }
+        // Both frames, cut in an arbitrary place
+        ch.write_inbound(&wire[..5]);
+        ch.write_inbound(&wire[5..]);
+        assert_eq!(ch.read_inbound(), Some(msg.clone()));
+        assert_eq!(ch.read_inbound(), Some(msg));
+    }
+
+    #[test]
+    fn bincode_round_trip() {
+        round_trip(TypedCodec::bincode(LengthFraming::new(
+            LengthField::U32,
+            1024,
+        )));
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip(TypedCodec::json(LengthFraming::new(
+            LengthField::Varint,
+            1024,
+        )));
+    }
+
+    #[test]
+    fn bad_payload_is_a_deserialize_error() {
+        let codec: TypedCodec<Msg> = TypedCodec::json(LengthFraming::new(LengthField::U8, 64));
+        let mut ch = EmbeddedChannel::new(codec);
+        ch.write_inbound(&b"\x05{oops"[..]);
+        match ch.read_codec_error() {
+            Some(CodecError::Deserialize(_)) => {}
+            other => panic!("expected Deserialize, got {:?}", other),
+        }
+    }
+}
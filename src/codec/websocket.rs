@@ -0,0 +1,677 @@
+use bytes::{Bytes, BytesMut};
+use codec::http::{HttpServerCodec, Request, Response};
+use codec::{CodecError, Decoder, Encoder};
+use entropy;
+use sha1_smol::Sha1;
+use std::str;
+
+// Appended to the client's key to derive Sec-WebSocket-Accept (RFC 6455, section 1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Which end of the connection a codec speaks for; clients mask their frames, servers do not.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+/// A complete WebSocket message; fragmented messages are delivered reassembled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Bytes),
+    Ping(Bytes),
+    Pong(Bytes),
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+/// Events of a server-side WebSocket connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketEvent {
+    /// A valid upgrade request. Encoding it back accepts the upgrade with a `101` response.
+    Handshake(Request),
+    Message(Message),
+    /// Reports a decode error to the peer: an HTTP error response until the `101` has been
+    /// written, a close frame after it. Nothing more is decoded after an error, so the
+    /// channel should be closed once this is written.
+    Fail(CodecError),
+}
+
+/// RFC 6455 framing for an already upgraded connection.
+///
+/// Once a close frame has been received, or a protocol error reported, the rest of the stream is
+/// discarded. Replying to pings and echoing the close frame is left to the application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketCodec {
+    role: Role,
+    max_message_size: usize,
+    // Outgoing data messages are split into frames of at most this many bytes; 0 never splits
+    max_frame_size: usize,
+    // Opcode and payload of a fragmented message still missing its final frame
+    fragment: Option<(u8, BytesMut)>,
+    closed: bool,
+}
+
+/// Accepts an HTTP/1.1 upgrade and then speaks RFC 6455 as the server.
+///
+/// A request that isn't an upgrade is reported as a `MalformedHttp` error; encoding
+/// `WebSocketEvent::Fail` with that error answers it with `400 Bad Request`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketServerCodec {
+    handshake: HttpServerCodec,
+    frames: WebSocketCodec,
+    state: Upgrade,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Upgrade {
+    Pending,
+    Done,
+    Refused,
+}
+
+// Header of a single frame, parsed before its payload is available
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    header_len: usize,
+    payload_len: u64,
+}
+
+// impl Message
+impl Message {
+    /// The close frame to send back when acknowledging `self`, if it is a close.
+    pub fn close_reply(&self) -> Option<Message> {
+        match *self {
+            Message::Close(Some(ref frame)) => Some(Message::Close(Some(CloseFrame {
+                code: frame.code,
+                reason: String::new(),
+            }))),
+            Message::Close(None) => Some(Message::Close(None)),
+            _ => None,
+        }
+    }
+
+    /// The pong to send back when `self` is a ping.
+    pub fn pong_reply(&self) -> Option<Message> {
+        match *self {
+            Message::Ping(ref data) => Some(Message::Pong(data.clone())),
+            _ => None,
+        }
+    }
+}
+
+// impl WebSocketCodec
+impl WebSocketCodec {
+    pub fn new(role: Role, max_message_size: usize) -> Self {
+        WebSocketCodec {
+            role,
+            max_message_size,
+            max_frame_size: 0,
+            fragment: None,
+            closed: false,
+        }
+    }
+
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    fn fail(&mut self, buf: &mut BytesMut, why: CodecError) -> Result<Option<Message>, CodecError> {
+        self.closed = true;
+        self.fragment = None;
+        buf.clear();
+        Err(why)
+    }
+
+    fn read_header(&self, buf: &[u8]) -> Result<Option<FrameHeader>, CodecError> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buf[0] & 0x80 != 0;
+        if buf[0] & 0x70 != 0 {
+            return Err(CodecError::WebSocketProtocol("reserved bits set"));
+        }
+        let opcode = buf[0] & 0x0F;
+        let masked = buf[1] & 0x80 != 0;
+        match (self.role, masked) {
+            (Role::Server, false) => return Err(CodecError::WebSocketProtocol("unmasked frame")),
+            (Role::Client, true) => return Err(CodecError::WebSocketProtocol("masked frame")),
+            _ => {}
+        }
+
+        let (payload_len, mut header_len) = match buf[1] & 0x7F {
+            126 => {
+                if buf.len() < 4 {
+                    return Ok(None);
+                }
+                (u64::from(buf[2]) << 8 | u64::from(buf[3]), 4)
+            }
+            127 => {
+                if buf.len() < 10 {
+                    return Ok(None);
+                }
+                let len = buf[2..10]
+                    .iter()
+                    .fold(0u64, |acc, b| acc << 8 | u64::from(*b));
+                if len >> 63 != 0 {
+                    return Err(CodecError::WebSocketProtocol("invalid payload length"));
+                }
+                (len, 10)
+            }
+            len => (u64::from(len), 2),
+        };
+
+        let mask = if masked {
+            if buf.len() < header_len + 4 {
+                return Ok(None);
+            }
+            let mut key = [0u8; 4];
+            key.copy_from_slice(&buf[header_len..header_len + 4]);
+            header_len += 4;
+            Some(key)
+        } else {
+            None
+        };
+
+        Ok(Some(FrameHeader {
+            fin,
+            opcode,
+            mask,
+            header_len,
+            payload_len,
+        }))
+    }
+
+    // Validates a frame header against the fragmentation state and size limits
+    fn check(&self, header: &FrameHeader) -> Result<(), CodecError> {
+        match header.opcode {
+            OP_CLOSE | OP_PING | OP_PONG => {
+                if !header.fin {
+                    return Err(CodecError::WebSocketProtocol("fragmented control frame"));
+                }
+                if header.payload_len > MAX_CONTROL_PAYLOAD as u64 {
+                    return Err(CodecError::WebSocketProtocol("control frame too long"));
+                }
+            }
+            OP_CONTINUATION if self.fragment.is_none() => {
+                return Err(CodecError::WebSocketProtocol(
+                    "unexpected continuation frame",
+                ));
+            }
+            OP_TEXT | OP_BINARY if self.fragment.is_some() => {
+                return Err(CodecError::WebSocketProtocol("expected continuation frame"));
+            }
+            OP_CONTINUATION | OP_TEXT | OP_BINARY => {}
+            _ => return Err(CodecError::WebSocketProtocol("unknown opcode")),
+        }
+        // Control frames may arrive between fragments but are not part of the message
+        let buffered = match self.fragment {
+            Some((_, ref data)) if header.opcode < OP_CLOSE => data.len() as u64,
+            _ => 0,
+        };
+        let length = buffered + header.payload_len;
+        if length > self.max_message_size as u64 {
+            return Err(CodecError::FrameTooLong {
+                length,
+                max: self.max_message_size,
+            });
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, fin: bool, opcode: u8, payload: &[u8], dst: &mut BytesMut) {
+        let len = payload.len();
+        dst.reserve(len + 14);
+        let fin_bit = if fin { 0x80 } else { 0 };
+        dst.extend_from_slice(&[fin_bit | opcode]);
+        let mask_bit = if self.role == Role::Client { 0x80 } else { 0 };
+        if len < 126 {
+            dst.extend_from_slice(&[mask_bit | len as u8]);
+        } else if len <= 0xFFFF {
+            dst.extend_from_slice(&[mask_bit | 126]);
+            dst.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            dst.extend_from_slice(&[mask_bit | 127]);
+            dst.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        if self.role == Role::Client {
+            // RFC 6455, section 5.3: a fresh key from a strong source of entropy per frame
+            let mut key = [0u8; 4];
+            entropy::random_bytes(&mut key);
+            dst.extend_from_slice(&key);
+            let start = dst.len();
+            dst.extend_from_slice(payload);
+            apply_mask(&mut dst[start..], key);
+        } else {
+            dst.extend_from_slice(payload);
+        }
+    }
+
+    fn write_data(&mut self, opcode: u8, payload: &[u8], dst: &mut BytesMut) {
+        if self.max_frame_size == 0 || payload.len() <= self.max_frame_size {
+            return self.write_frame(true, opcode, payload, dst);
+        }
+        let chunks = payload.chunks(self.max_frame_size);
+        let last = chunks.len() - 1;
+        for (idx, chunk) in chunks.enumerate() {
+            let opcode = if idx == 0 { opcode } else { OP_CONTINUATION };
+            self.write_frame(idx == last, opcode, chunk, dst);
+        }
+    }
+}
+
+impl Decoder for WebSocketCodec {
+    type Item = Message;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Message>, CodecError> {
+        loop {
+            if self.closed {
+                buf.clear();
+                return Ok(None);
+            }
+            let header = match self.read_header(buf) {
+                Ok(Some(header)) => header,
+                Ok(None) => return Ok(None),
+                Err(why) => return self.fail(buf, why),
+            };
+            if let Err(why) = self.check(&header) {
+                return self.fail(buf, why);
+            }
+            let frame_len = header.header_len + header.payload_len as usize;
+            if buf.len() < frame_len {
+                buf.reserve(frame_len - buf.len());
+                return Ok(None);
+            }
+
+            let mut frame = buf.split_to(frame_len);
+            frame.advance(header.header_len);
+            if let Some(key) = header.mask {
+                apply_mask(&mut frame, key);
+            }
+
+            let message = match header.opcode {
+                OP_PING => Message::Ping(frame.freeze()),
+                OP_PONG => Message::Pong(frame.freeze()),
+                OP_CLOSE => match parse_close(&frame) {
+                    Ok(close) => {
+                        self.closed = true;
+                        Message::Close(close)
+                    }
+                    Err(why) => return self.fail(buf, why),
+                },
+                opcode => {
+                    let (opcode, data) = match self.fragment.take() {
+                        Some((first, mut data)) => {
+                            data.extend_from_slice(&frame);
+                            (first, data)
+                        }
+                        None => (opcode, frame),
+                    };
+                    if !header.fin {
+                        self.fragment = Some((opcode, data));
+                        continue;
+                    }
+                    if opcode == OP_BINARY {
+                        Message::Binary(data.freeze())
+                    } else {
+                        match String::from_utf8(data.to_vec()) {
+                            Ok(text) => Message::Text(text),
+                            Err(why) => {
+                                let valid_up_to = why.utf8_error().valid_up_to();
+                                return self.fail(buf, CodecError::InvalidUtf8 { valid_up_to });
+                            }
+                        }
+                    }
+                }
+            };
+            return Ok(Some(message));
+        }
+    }
+}
+
+impl Encoder for WebSocketCodec {
+    type Item = Message;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), CodecError> {
+        match msg {
+            Message::Text(text) => self.write_data(OP_TEXT, text.as_bytes(), dst),
+            Message::Binary(data) => self.write_data(OP_BINARY, &data, dst),
+            Message::Ping(data) | Message::Pong(data) if data.len() > MAX_CONTROL_PAYLOAD => {
+                return Err(CodecError::WebSocketProtocol("control frame too long"));
+            }
+            Message::Ping(data) => self.write_frame(true, OP_PING, &data, dst),
+            Message::Pong(data) => self.write_frame(true, OP_PONG, &data, dst),
+            Message::Close(None) => self.write_frame(true, OP_CLOSE, &[], dst),
+            Message::Close(Some(close)) => {
+                if close.reason.len() + 2 > MAX_CONTROL_PAYLOAD {
+                    return Err(CodecError::WebSocketProtocol("control frame too long"));
+                }
+                let mut payload = Vec::with_capacity(close.reason.len() + 2);
+                payload.extend_from_slice(&close.code.to_be_bytes());
+                payload.extend_from_slice(close.reason.as_bytes());
+                self.write_frame(true, OP_CLOSE, &payload, dst);
+            }
+        }
+        Ok(())
+    }
+}
+
+// impl WebSocketServerCodec
+impl WebSocketServerCodec {
+    pub fn new(max_header_size: usize, max_message_size: usize) -> Self {
+        WebSocketServerCodec {
+            handshake: HttpServerCodec::new(max_header_size, 0),
+            frames: WebSocketCodec::new(Role::Server, max_message_size),
+            state: Upgrade::Pending,
+        }
+    }
+
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.frames = self.frames.with_max_frame_size(max_frame_size);
+        self
+    }
+}
+
+impl Decoder for WebSocketServerCodec {
+    type Item = WebSocketEvent;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<WebSocketEvent>, CodecError> {
+        match self.state {
+            Upgrade::Done => Ok(self.frames.decode(buf)?.map(WebSocketEvent::Message)),
+            Upgrade::Refused => {
+                buf.clear();
+                Ok(None)
+            }
+            Upgrade::Pending => match self.handshake.decode(buf)? {
+                Some(ref request) if !is_upgrade(request) => {
+                    // Frames cannot follow a refused upgrade
+                    self.state = Upgrade::Refused;
+                    buf.clear();
+                    Err(CodecError::MalformedHttp("not a WebSocket upgrade request"))
+                }
+                Some(request) => {
+                    self.state = Upgrade::Done;
+                    Ok(Some(WebSocketEvent::Handshake(request)))
+                }
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+impl Encoder for WebSocketServerCodec {
+    type Item = WebSocketEvent;
+
+    fn encode(&mut self, msg: WebSocketEvent, dst: &mut BytesMut) -> Result<(), CodecError> {
+        match msg {
+            WebSocketEvent::Handshake(request) => match handshake_response(&request) {
+                Some(response) => {
+                    // Decoding and encoding may be done by separate codecs; this one only knows
+                    // the upgrade is done once it has written the 101
+                    self.handshake.encode(response, dst)?;
+                    self.state = Upgrade::Done;
+                    Ok(())
+                }
+                None => Err(CodecError::MalformedHttp("not a WebSocket upgrade request")),
+            },
+            WebSocketEvent::Message(msg) => self.frames.encode(msg, dst),
+            WebSocketEvent::Fail(why) => match self.state {
+                Upgrade::Done => {
+                    let close = CloseFrame {
+                        code: close_code(&why),
+                        reason: String::new(),
+                    };
+                    self.frames.encode(Message::Close(Some(close)), dst)
+                }
+                Upgrade::Pending | Upgrade::Refused => {
+                    self.handshake.encode(Response::for_error(&why), dst)
+                }
+            },
+        }
+    }
+}
+
+/// Whether `request` asks to upgrade to WebSocket version 13.
+pub fn is_upgrade(request: &Request) -> bool {
+    let has_token = |name: &str, token: &str| {
+        request
+            .header(name)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    request.method == "GET"
+        && has_token("upgrade", "websocket")
+        && has_token("connection", "upgrade")
+        && request.header("sec-websocket-version") == Some("13")
+        && request.header("sec-websocket-key").is_some()
+}
+
+/// The `101 Switching Protocols` response accepting `request`, if it is an upgrade request.
+pub fn handshake_response(request: &Request) -> Option<Response> {
+    if !is_upgrade(request) {
+        return None;
+    }
+    let key = request.header("sec-websocket-key")?;
+    let mut sha1 = Sha1::new();
+    sha1.update(key.trim().as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    let accept = base64(&sha1.digest().bytes());
+    Some(
+        Response::new(101)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", &accept),
+    )
+}
+
+// RFC 6455, section 7.4.1
+fn close_code(why: &CodecError) -> u16 {
+    match *why {
+        CodecError::InvalidUtf8 { .. } => 1007,
+        CodecError::FrameTooLong { .. } => 1009,
+        _ => 1002,
+    }
+}
+
+fn parse_close(payload: &[u8]) -> Result<Option<CloseFrame>, CodecError> {
+    match payload.len() {
+        0 => Ok(None),
+        1 => Err(CodecError::WebSocketProtocol("truncated close code")),
+        _ => {
+            let code = u16::from(payload[0]) << 8 | u16::from(payload[1]);
+            let reason = str::from_utf8(&payload[2..]).map_err(|why| CodecError::InvalidUtf8 {
+                valid_up_to: why.valid_up_to(),
+            })?;
+            Ok(Some(CloseFrame {
+                code,
+                reason: reason.to_owned(),
+            }))
+        }
+    }
+}
+
+fn apply_mask(data: &mut [u8], key: [u8; 4]) {
+    for (idx, b) in data.iter_mut().enumerate() {
+        *b ^= key[idx % 4];
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let n = group
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (idx, b)| acc | u32::from(*b) << (16 - 8 * idx));
+        for idx in 0..4 {
+            if idx <= group.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * idx) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::embedded::EmbeddedChannel;
+
+    const UPGRADE: &[u8] = b"GET /chat HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\n\
+        Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n";
+
+    type Channel = EmbeddedChannel<WebSocketCodec>;
+
+    fn pair(max_frame_size: usize) -> (Channel, Channel) {
+        let client = WebSocketCodec::new(Role::Client, 64).with_max_frame_size(max_frame_size);
+        let server = WebSocketCodec::new(Role::Server, 64);
+        (EmbeddedChannel::new(client), EmbeddedChannel::new(server))
+    }
+
+    // Moves everything `from` wrote to `to`'s inbound side
+    fn pipe(from: &mut Channel, to: &mut Channel) {
+        while let Some(wire) = from.read_outbound() {
+            to.write_inbound(wire);
+        }
+    }
+
+    #[test]
+    fn client_frames_are_masked_with_fresh_keys() {
+        let (mut client, mut server) = pair(0);
+        client.write_outbound(Message::Text("hello".to_owned()));
+        client.write_outbound(Message::Text("hello".to_owned()));
+        let first = client.read_outbound().expect("first frame");
+        let second = client.read_outbound().expect("second frame");
+        assert_eq!(first[1], 0x80 | 5);
+        assert_ne!(first[2..], second[2..]);
+
+        server.write_inbound(first);
+        server.write_inbound(second);
+        assert_eq!(
+            server.read_inbound(),
+            Some(Message::Text("hello".to_owned()))
+        );
+        assert_eq!(
+            server.read_inbound(),
+            Some(Message::Text("hello".to_owned()))
+        );
+    }
+
+    #[test]
+    fn fragments_are_reassembled_around_control_frames() {
+        let (mut client, mut server) = pair(4);
+        client.write_outbound(Message::Binary(Bytes::from(&b"0123456789"[..])));
+        let mut fragments = client.read_outbound().expect("fragments");
+        // Header, masking key and four bytes of payload
+        server.write_inbound(fragments.split_to(10));
+        client.write_outbound(Message::Ping(Bytes::from(&b"?"[..])));
+        pipe(&mut client, &mut server);
+        server.write_inbound(fragments);
+
+        assert_eq!(
+            server.read_inbound(),
+            Some(Message::Ping(Bytes::from(&b"?"[..])))
+        );
+        let msg = Message::Binary(Bytes::from(&b"0123456789"[..]));
+        assert_eq!(server.read_inbound(), Some(msg));
+    }
+
+    #[test]
+    fn server_frames_decode_on_the_client() {
+        let (mut client, mut server) = pair(0);
+        let close = Message::Close(Some(CloseFrame {
+            code: 1000,
+            reason: "bye".to_owned(),
+        }));
+        server.write_outbound(close.clone());
+        pipe(&mut server, &mut client);
+        assert_eq!(client.read_inbound(), Some(close));
+    }
+
+    #[test]
+    fn protocol_violations() {
+        let (_, mut server) = pair(0);
+        server.write_inbound(&[0x81, 0x01, b'x'][..]);
+        assert_eq!(
+            server.read_codec_error(),
+            Some(CodecError::WebSocketProtocol("unmasked frame"))
+        );
+
+        let (mut client, mut server) = pair(0);
+        client.write_outbound(Message::Binary(Bytes::from(vec![0; 65])));
+        pipe(&mut client, &mut server);
+        assert_eq!(
+            server.read_codec_error(),
+            Some(CodecError::FrameTooLong {
+                length: 65,
+                max: 64
+            })
+        );
+    }
+
+    #[test]
+    fn handshake_accept_key() {
+        let mut ch = EmbeddedChannel::new(WebSocketServerCodec::new(256, 64));
+        ch.write_inbound(UPGRADE);
+        let request = match ch.read_inbound() {
+            Some(WebSocketEvent::Handshake(request)) => request,
+            other => panic!("expected a handshake, got {:?}", other),
+        };
+        let response = handshake_response(&request).expect("upgrade request");
+        assert_eq!(response.status, 101);
+        let accept = response
+            .headers
+            .iter()
+            .find(|&(name, _)| name == "Sec-WebSocket-Accept")
+            .map(|(_, value)| value.as_str());
+        assert_eq!(accept, Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    }
+
+    #[test]
+    fn failures_before_and_after_the_upgrade() {
+        let mut ch = EmbeddedChannel::new(WebSocketServerCodec::new(256, 64));
+        ch.write_inbound(&b"GET / HTTP/1.1\r\n\r\n"[..]);
+        let why = ch.read_codec_error().expect("refused upgrade");
+        ch.write_outbound(WebSocketEvent::Fail(why));
+        let wire = ch.read_outbound().expect("error response");
+        assert!(wire.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+
+        let mut ch = EmbeddedChannel::new(WebSocketServerCodec::new(256, 64));
+        ch.write_inbound(UPGRADE);
+        let request = ch.read_inbound().expect("upgrade request");
+        ch.write_outbound(request);
+        assert!(ch
+            .read_outbound()
+            .expect("101")
+            .starts_with(b"HTTP/1.1 101 "));
+        ch.write_inbound(&[0x81, 0x01, b'x'][..]);
+        let why = ch.read_codec_error().expect("unmasked frame");
+        ch.write_outbound(WebSocketEvent::Fail(why));
+        let wire = ch.read_outbound().expect("close frame");
+        assert_eq!(wire, Bytes::from(&[0x88, 0x02, 0x03, 0xEA][..]));
+    }
+}
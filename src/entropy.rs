@@ -0,0 +1,10 @@
+use rand::{self, RngCore};
+
+// Random bits from a CSPRNG seeded by the operating system, so peers cannot predict them
+pub(crate) fn random_u64() -> u64 {
+    rand::thread_rng().next_u64()
+}
+
+pub(crate) fn random_bytes(buf: &mut [u8]) {
+    rand::thread_rng().fill_bytes(buf);
+}
@@ -1,37 +1,55 @@
-use bytes::Bytes;
+use async_io;
+use attribute::Attributes;
+use channel::ChannelError;
+use channel::ConnectError;
+use channel::ErrorEvent;
 use channel::RWEvent;
 use channel::ReadEvent;
 use channel::RegistrationEvent;
 use channel::StateEvent;
 use futures;
+use futures::sync::mpsc::{SendError, UnboundedReceiver, UnboundedSender};
+use futures::{Async, Poll, Stream};
+use idle::IdleTracker;
+use metrics::ErrorKind;
 use ops::Ops;
 use selector::Selector;
 use selector::SelectorKey;
+use std::cell::Cell;
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
 use std::marker::PhantomData;
+use std::mem;
 use std::net::SocketAddr;
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use timer::{DeadlineKind, Deadlines, Scheduler, Timers};
 
 pub trait FnBox<S, K>
 where
     K: SelectorKey,
     S: Selector<K>,
 {
-    fn call_box(self: Box<Self>, sys: &mut S, events: futures::sync::mpsc::UnboundedSender<Trigger<K>>);
+    fn call_box(self: Box<Self>, sys: &mut S, events: EventSender<K>);
 }
 
 impl<S, K, F> FnBox<S, K> for F
 where
-    F: FnOnce(&mut S, futures::sync::mpsc::UnboundedSender<Trigger<K>>),
+    F: FnOnce(&mut S, EventSender<K>),
     K: SelectorKey,
     S: Selector<K>,
 {
-    fn call_box(self: Box<F>, sys: &mut S, events: futures::sync::mpsc::UnboundedSender<Trigger<K>>) {
+    fn call_box(self: Box<F>, sys: &mut S, events: EventSender<K>) {
         (*self)(sys, events);
     }
 }
 
-pub type Work<'a, S, K> = Box<FnBox<S, K> + Send + 'a>;
+pub type Work<'a, S, K> = Box<dyn FnBox<S, K> + Send + 'a>;
 
 pub struct SelectorEventLoop<S, K>
 where
@@ -40,10 +58,64 @@ where
 {
     selector: S,
     // Outgoing events from this event loop
-    events: futures::sync::mpsc::UnboundedSender<Trigger<K>>,
+    events: EventSender<K>,
     io_tasks: mpsc::Receiver<Work<'static, S, K>>,
+    // Tasks sent through a `TaskSender` and not yet run
+    queued_tasks: Arc<AtomicUsize>,
     key: PhantomData<K>,
     events_buf: Vec<RWEvent<K>>,
+    // Whether reads are suspended because the events consumer fell behind
+    reads_paused: bool,
+    timers: Timers<S, K>,
+    // Connect timeouts and idle checks of registered keys
+    deadlines: Deadlines<K::Resource>,
+    idle_trackers: HashMap<K::Resource, IdleTracker>,
+    // Attempts made so far for keys re-dialing a lost or failed connection
+    reconnects: HashMap<K::Resource, u32>,
+    // Keys waiting out their backoff before connecting
+    redials: Vec<(Instant, K::Resource)>,
+}
+
+/// Sending half of the event stream, shared by the loop and its tasks.
+///
+/// When created with a capacity, the sender tracks how many events the consumer has yet to take so
+/// the loop can stop reading from channels until the stream drains. The bound is checked before
+/// each key is read, so only the events of the read that reached it can go past it.
+#[derive(Debug)]
+pub struct EventSender<K: SelectorKey> {
+    tx: UnboundedSender<Trigger<K>>,
+    pending: Arc<AtomicUsize>,
+    sent: Arc<AtomicUsize>,
+    capacity: Option<usize>,
+}
+
+/// Sends tasks to run on the loop, counting those it has yet to run.
+pub struct TaskSender<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    tx: mpsc::Sender<Work<'static, S, K>>,
+    queued: Arc<AtomicUsize>,
+}
+
+/// What one or more iterations of the loop did.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    pub iterations: usize,
+    // Keys handled after select reported them ready
+    pub selected: usize,
+    // Events sent to the stream, including those sent by tasks
+    pub events: usize,
+    // Tasks and expired timers run
+    pub tasks: usize,
+}
+
+/// Receiving half of the event stream.
+#[derive(Debug)]
+pub struct EventReceiver<K: SelectorKey> {
+    rx: UnboundedReceiver<Trigger<K>>,
+    pending: Arc<AtomicUsize>,
 }
 
 impl<S, K> SelectorEventLoop<S, K>
@@ -51,116 +123,513 @@ where
     S: Selector<K>,
     K: SelectorKey,
 {
-    pub fn new(
+    pub fn new(selector: S) -> (Self, TaskSender<S, K>, EventReceiver<K>) {
+        SelectorEventLoop::with_capacity(selector, None)
+    }
+
+    /// Creates an event loop whose event stream holds at most `capacity` undelivered events before
+    /// read interest is withdrawn from all channels.
+    pub fn bounded(selector: S, capacity: usize) -> (Self, TaskSender<S, K>, EventReceiver<K>) {
+        SelectorEventLoop::with_capacity(selector, Some(capacity))
+    }
+
+    fn with_capacity(
         selector: S,
-    ) -> (
-        Self,
-        mpsc::Sender<Work<'static, S, K>>,
-        futures::sync::mpsc::UnboundedReceiver<Trigger<K>>,
-    ) {
-        let (ev_tx, ev_rx) = futures::sync::mpsc::unbounded();
+        capacity: Option<usize>,
+    ) -> (Self, TaskSender<S, K>, EventReceiver<K>) {
+        let (ev_tx, ev_rx) = event_channel(capacity);
         let (io_tx, io_rx) = mpsc::channel();
+        let queued_tasks = Arc::new(AtomicUsize::new(0));
+        let tasks = TaskSender {
+            tx: io_tx,
+            queued: queued_tasks.clone(),
+        };
 
         let event_loop = SelectorEventLoop {
             selector,
             events: ev_tx,
             io_tasks: io_rx,
+            queued_tasks,
             key: PhantomData,
             events_buf: Vec::new(),
+            reads_paused: false,
+            timers: Timers::new(),
+            deadlines: Deadlines::new(),
+            idle_trackers: HashMap::new(),
+            reconnects: HashMap::new(),
+            redials: Vec::new(),
         };
-        (event_loop, io_tx, ev_rx)
+        (event_loop, tasks, ev_rx)
     }
 
     pub fn register(&mut self, key: K, ops: Ops) {
         self.selector.register(key, ops);
     }
 
+    /// A handle for running tasks on this loop after a delay.
+    pub fn scheduler(&self) -> Scheduler<S, K> {
+        self.timers.scheduler()
+    }
+
     pub fn run(&mut self) {
         loop {
-            self.selector.select(S::DEFAULT_TIMEOUT_MS);
-            self.process_selected();
-            self.run_io_tasks();
+            self.iterate(None);
+        }
+    }
+
+    /// Runs a single iteration of the loop: one select waiting at most `timeout`, then the I/O,
+    /// tasks and timeouts it leads to.
+    pub fn run_once(&mut self, timeout: Duration) -> RunSummary {
+        self.iterate(Some(timeout))
+    }
+
+    /// Runs iterations until `done` returns true for the selector and everything run so far; it
+    /// is checked after each iteration.
+    pub fn run_until<F>(&mut self, mut done: F) -> RunSummary
+    where
+        F: FnMut(&S, &RunSummary) -> bool,
+    {
+        let mut summary = RunSummary::default();
+        loop {
+            summary += self.iterate(None);
+            if done(&self.selector, &summary) {
+                return summary;
+            }
+        }
+    }
+
+    /// Runs iterations until `duration` has passed.
+    pub fn run_for(&mut self, duration: Duration) -> RunSummary {
+        let deadline = Instant::now() + duration;
+        let mut summary = RunSummary::default();
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            summary += self.iterate(Some(remaining));
+            if Instant::now() >= deadline {
+                return summary;
+            }
+        }
+    }
+
+    pub fn selector(&self) -> &S {
+        &self.selector
+    }
+
+    pub fn selector_mut(&mut self) -> &mut S {
+        &mut self.selector
+    }
+
+    /// Deregisters and closes the key for `resource`, returning it if it was registered.
+    ///
+    /// A closed key is never re-dialed. Closing a registered key emits `StateEvent::Closed`.
+    pub fn close(&mut self, resource: &K::Resource) -> Option<K> {
+        self.reconnects.remove(resource);
+        self.redials.retain(|(_, r)| r != resource);
+        async_io::close(&mut self.selector, &self.events, resource)
+    }
+
+    fn iterate(&mut self, timeout: Option<Duration>) -> RunSummary {
+        let sent = self.events.sent();
+        self.apply_backpressure();
+        let mut select_timeout = self.select_timeout();
+        if let Some(timeout) = timeout {
+            select_timeout = cmp::min(select_timeout, millis_ceil(timeout));
+        }
+        self.selector.select(select_timeout);
+        let selected = self.process_selected();
+        let tasks = self.run_timers() + self.run_io_tasks();
+        self.selector.metrics().record_tasks(tasks);
+        self.run_redials();
+        self.arm_registered();
+        self.run_deadlines();
+        RunSummary {
+            iterations: 1,
+            selected,
+            events: self.events.sent() - sent,
+            tasks,
+        }
+    }
+
+    // When the next timer, idle check, connect timeout or re-dial is due
+    pub(crate) fn next_deadline(&mut self) -> Option<Instant> {
+        [
+            self.timers.next_deadline(),
+            self.deadlines.next_deadline(),
+            self.redials.iter().map(|(at, _)| *at).min(),
+        ]
+        .iter()
+        .filter_map(|deadline| *deadline)
+        .min()
+    }
+
+    // Wakes up in time for the next deadline, but no later than the selector's default
+    fn select_timeout(&mut self) -> i64 {
+        match self.next_deadline() {
+            None => S::DEFAULT_TIMEOUT_MS,
+            Some(deadline) => {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                cmp::min(millis_ceil(wait), S::DEFAULT_TIMEOUT_MS)
+            }
         }
     }
 
-    fn process_selected(&mut self) {
+    fn apply_backpressure(&mut self) {
+        if !self.reads_paused && self.events.is_full() {
+            debug!("Events consumer fell behind; pausing reads");
+            self.reads_paused = true;
+            self.selector.set_reads_paused(true);
+        } else if self.reads_paused && self.events.is_drained() {
+            debug!("Events consumer drained; resuming reads");
+            self.reads_paused = false;
+            self.selector.set_reads_paused(false);
+        }
+    }
+
+    // Returns how many keys were handled
+    fn process_selected(&mut self) -> usize {
         use channel::{ChExt, ChRead, ChWrite};
 
+        let selected = Cell::new(0);
+        let events = &self.events;
         self.selector
             .on_selected(&mut self.events_buf, |ev, key: &mut K| {
+                selected.set(selected.get() + 1);
                 let ready_ops = key.ready_ops();
-                println!("handling {:?}", ready_ops);
+                trace!("{:?} handling {:?}", key.resource(), ready_ops);
 
                 if ready_ops.has_connect() {
-                    let mut updated_ops = Ops::from(ready_ops);
+                    let mut updated_ops = ready_ops;
                     updated_ops.remove(Ops::CONNECT);
                     key.set_readiness(updated_ops);
-                    key.set_interest(updated_ops);
-                    ev.push(RWEvent::Registration(RegistrationEvent::Update(
-                        key.resource(),
-                        updated_ops,
-                    )));
+                    // The channel updates its registration once the connect completes
                     let io = key.io();
                     io.finish_connect(ev);
                 }
 
                 if ready_ops.has_read() || ready_ops.has_accept() {
-                    let io = key.io();
-                    io.read(ev);
+                    // Keys left unread stay ready and are read once the consumer catches up
+                    if events.is_full_with(ev.len()) {
+                        trace!("{:?} not read; events consumer is behind", key.resource());
+                    } else {
+                        let io = key.io();
+                        io.read(ev);
+                    }
                 }
                 if ready_ops.has_write() {
                     let io = key.io();
                     io.flush(ev);
                 }
             });
-        // TODO the event loop shouldn't really be driving this logic
-        // TODO but instead something like Netty's Unsafe abstractions
-        for ev in self.events_buf.drain(..) {
+        self.dispatch_events();
+        selected.get()
+    }
+
+    // TODO the event loop shouldn't really be driving this logic
+    // TODO but instead something like Netty's Unsafe abstractions
+    fn dispatch_events(&mut self) {
+        let mut buf = mem::take(&mut self.events_buf);
+        for ev in buf.drain(..) {
             match ev {
                 RWEvent::Read(ReadEvent::NewPeer(key, addr)) => {
-                    let mut ops = Ops::empty();
-                    ops.apply(Ops::READ);
-                    ops.apply(Ops::ERROR);
+                    let ops = Ops::READ | Ops::ERROR;
                     let resource = key.resource();
                     self.selector.register(key, ops);
+                    self.selector.metrics().record_accepted();
                     self.events
-                        .unbounded_send(Trigger::State(events::StateEvent::Connected(
+                        .send(Trigger::State(events::StateEvent::Connected(
                             resource, addr,
-                        ))).expect("Dropped unbounded events receiver");
+                        )))
+                        .expect("Dropped unbounded events receiver");
                 }
-                RWEvent::Read(ReadEvent::Data(bytes)) => {
+                RWEvent::Read(ReadEvent::Data(resource, msg)) => {
                     self.events
-                        .unbounded_send(Trigger::Read(events::ReadEvent::Data(bytes)))
+                        .send(Trigger::Read(events::ReadEvent::Data(resource, msg)))
                         .expect("Dropped unbounded events receiver");
                 }
                 RWEvent::Registration(RegistrationEvent::Update(resource, ops)) => {
-                    println!("Updating registration {:?}", ops);
+                    debug!("{:?} updating registration to {:?}", resource, ops);
                     self.selector.update_registration(resource, ops);
                 }
+                RWEvent::Registration(RegistrationEvent::Flushed(resource)) => {
+                    async_io::flushed(&mut self.selector, &self.events, &resource, Ok(()));
+                }
                 RWEvent::State(StateEvent::ConnectedPeer(resource, addr)) => {
+                    let ev = match self.reconnects.remove(&resource) {
+                        Some(attempt) => {
+                            info!(
+                                "{:?} reconnected to {:?} (attempt {})",
+                                resource, addr, attempt
+                            );
+                            events::StateEvent::Reconnected(resource, addr)
+                        }
+                        None => events::StateEvent::Connected(resource, addr),
+                    };
                     self.events
-                        .unbounded_send(Trigger::State(events::StateEvent::Connected(
-                            resource, addr,
-                        ))).expect("Dropped unbounded events receiver");
+                        .send(Trigger::State(ev))
+                        .expect("Dropped unbounded events receiver");
+                }
+                RWEvent::State(StateEvent::ConnectFailed(resource, addr, why)) => {
+                    self.fail_connect(resource, addr, why);
+                }
+                RWEvent::State(StateEvent::Disconnected(resource, addr)) => {
+                    self.disconnect(resource, addr);
+                }
+                RWEvent::State(StateEvent::Rejected(resource, addr, reason)) => {
+                    info!("{:?} rejected {:?}: {:?}", resource, addr, reason);
+                    self.selector.metrics().record_rejected();
+                    self.events
+                        .send(Trigger::State(events::StateEvent::Rejected(
+                            resource, addr, reason,
+                        )))
+                        .expect("Dropped unbounded events receiver");
+                }
+                RWEvent::Error(ErrorEvent::Codec(resource, why)) => {
+                    warn!("{:?} codec error: {}", resource, why);
+                    self.selector.metrics().record_error(ErrorKind::Codec);
+                    self.events
+                        .send(Trigger::Error(events::ErrorEvent::Codec(resource, why)))
+                        .expect("Dropped unbounded events receiver");
+                }
+                RWEvent::Error(ErrorEvent::WriteRejected(resource)) => {
+                    self.events
+                        .send(Trigger::Error(events::ErrorEvent::WriteRejected(resource)))
+                        .expect("Dropped unbounded events receiver");
                 }
-                other => {
-                    println!("Unhandled event {:?}", other);
+                RWEvent::Error(ErrorEvent::Send(resource, why)) => {
+                    warn!("{:?} send failed: {}", resource, why);
+                    self.selector.metrics().record_error(ErrorKind::Send);
+                    let failed = Err(ChannelError::Send(why.clone()));
+                    async_io::flushed(&mut self.selector, &self.events, &resource, failed);
+                    self.events
+                        .send(Trigger::Error(events::ErrorEvent::Send(resource, why)))
+                        .expect("Dropped unbounded events receiver");
+                }
+                RWEvent::Error(ErrorEvent::Accept(resource, why)) => {
+                    warn!("{:?} failed to accept: {}", resource, why);
+                    self.selector.metrics().record_error(ErrorKind::Accept);
+                    self.events
+                        .send(Trigger::Error(events::ErrorEvent::Accept(resource, why)))
+                        .expect("Dropped unbounded events receiver");
                 }
             }
         }
+        // Hand the buffer back to keep its allocation
+        self.events_buf = buf;
+    }
+
+    fn run_timers(&mut self) -> usize {
+        let due = self.timers.expired(Instant::now());
+        let run = due.len();
+        for work in due {
+            self.handle_task(work);
+        }
+        run
     }
 
-    fn run_io_tasks(&mut self) {
+    fn run_io_tasks(&mut self) -> usize {
+        let mut run = 0;
+        let queued = self.queued_tasks.load(Ordering::Acquire);
+        self.selector.metrics().record_task_queue_depth(queued);
         loop {
             match self.io_tasks.try_recv() {
-                Ok(task) => self.handle_task(task),
+                Ok(task) => {
+                    let queued = self.queued_tasks.fetch_sub(1, Ordering::AcqRel) - 1;
+                    self.selector.metrics().record_task_queue_depth(queued);
+                    self.handle_task(task);
+                    run += 1;
+                }
                 Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Disconnected) => {
-                    println!("Disconnected I/O task channel!");
+                    error!("Disconnected I/O task channel!");
+                    self.selector.metrics().record_error(ErrorKind::TaskQueue);
                     break;
                 }
             }
         }
+        run
+    }
+
+    fn fail_connect(&mut self, resource: K::Resource, addr: SocketAddr, why: ConnectError) {
+        warn!("{:?} failed to connect to {:?}: {}", resource, addr, why);
+        self.selector.metrics().record_error(ErrorKind::Connect);
+        let attempt = self.reconnects.remove(&resource).unwrap_or(0);
+        self.redials.retain(|(_, r)| *r != resource);
+        let closed = self.close_for_redial(&resource);
+        self.events
+            .send(Trigger::State(events::StateEvent::ConnectionError(
+                resource, addr, why,
+            )))
+            .expect("Dropped unbounded events receiver");
+        if let Some((key, attributes)) = closed {
+            self.reconnect(key, attributes, addr, attempt + 1);
+        }
+    }
+
+    fn disconnect(&mut self, resource: K::Resource, addr: SocketAddr) {
+        info!("{:?} disconnected from {:?}", resource, addr);
+        self.reconnects.remove(&resource);
+        self.redials.retain(|(_, r)| *r != resource);
+        let closed = self.close_for_redial(&resource);
+        self.events
+            .send(Trigger::State(events::StateEvent::Disconnected(
+                resource, addr,
+            )))
+            .expect("Dropped unbounded events receiver");
+        if let Some((key, attributes)) = closed {
+            self.reconnect(key, attributes, addr, 1);
+        }
+    }
+
+    // Closes the key for `resource`, holding on to its attributes in case it is re-dialed
+    fn close_for_redial(&mut self, resource: &K::Resource) -> Option<(K, Attributes)> {
+        let attributes = mem::take(self.selector.key_mut(resource)?.attributes_mut());
+        let key = async_io::close_key(&mut self.selector, resource)?;
+        Some((key, attributes))
+    }
+
+    // Re-registers the closed `key` under the same resource, with the attributes it had, to
+    // connect once its backoff has passed. The attributes are dropped if it won't be re-dialed.
+    fn reconnect(&mut self, mut key: K, attributes: Attributes, remote: SocketAddr, attempt: u32) {
+        let backoff = {
+            let connector = match key.connector() {
+                Some(connector) => connector,
+                None => return,
+            };
+            let backoff = match connector.backoff() {
+                Some(backoff) => backoff,
+                None => return,
+            };
+            if !backoff.allows(attempt) {
+                warn!("Giving up on {:?} after {} attempts", remote, attempt - 1);
+                return;
+            }
+            if let Err(why) = connector.redial() {
+                error!("Unable to re-dial {:?}: {}", remote, why);
+                self.selector.metrics().record_error(ErrorKind::Connect);
+                return;
+            }
+            backoff
+        };
+        let resource = key.resource();
+        let delay = backoff.delay(attempt);
+        debug!("{:?} re-dialing {:?} in {:?}", resource, remote, delay);
+        self.reconnects.insert(key.resource(), attempt);
+        self.redials.push((Instant::now() + delay, key.resource()));
+        *key.attributes_mut() = attributes;
+        // No interest until the connect starts; writes in the meantime follow the write policy
+        self.selector.register(key, Ops::empty());
+        self.events
+            .send(Trigger::State(events::StateEvent::Reconnecting {
+                resource,
+                remote,
+                attempt,
+                delay,
+            }))
+            .expect("Dropped unbounded events receiver");
+    }
+
+    fn run_redials(&mut self) {
+        use channel::ChExt;
+
+        if self.redials.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let (due, waiting): (Vec<_>, Vec<_>) =
+            self.redials.drain(..).partition(|(at, _)| *at <= now);
+        self.redials = waiting;
+        for (_, resource) in due {
+            self.selector
+                .on_resource(&resource, &mut self.events_buf, |ev, key| {
+                    key.io().connect(ev)
+                });
+            self.arm_connect(&resource);
+        }
+        self.dispatch_events();
+    }
+
+    // Arms the timeouts of keys registered since the last iteration
+    fn arm_registered(&mut self) {
+        let now = Instant::now();
+        for resource in self.selector.take_registered() {
+            self.arm_connect(&resource);
+            self.idle_trackers.remove(&resource);
+            self.check_idle(&resource, now);
+        }
+    }
+
+    fn arm_connect(&mut self, resource: &K::Resource) {
+        if let Some(key) = self.selector.key(resource) {
+            if let Some(deadline) = key.connect_deadline() {
+                self.deadlines
+                    .arm(deadline, key.resource(), DeadlineKind::Connect);
+            }
+        }
+    }
+
+    fn run_deadlines(&mut self) {
+        let now = Instant::now();
+        for (at, resource, kind) in self.deadlines.expired(now) {
+            match kind {
+                DeadlineKind::Connect => self.check_connect(resource, now),
+                // Only the latest idle check armed for a key counts
+                DeadlineKind::Idle => {
+                    let armed = self.idle_trackers.get(&resource).and_then(|t| t.armed);
+                    if armed == Some(at) {
+                        self.check_idle(&resource, now);
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_connect(&mut self, resource: K::Resource, now: Instant) {
+        let (deadline, remote) = match self.selector.key(&resource) {
+            Some(key) => (key.connect_deadline(), key.remote()),
+            None => return,
+        };
+        match (deadline, remote) {
+            (Some(deadline), Some(remote)) if deadline <= now => {
+                self.fail_connect(resource, remote, ConnectError::TimedOut)
+            }
+            // The key started connecting again since this deadline was armed
+            (Some(deadline), _) if deadline > now => {
+                self.deadlines
+                    .arm(deadline, resource, DeadlineKind::Connect)
+            }
+            _ => {}
+        }
+    }
+
+    // Reports the idle states `resource` has reached and arms its next idle check
+    fn check_idle(&mut self, resource: &K::Resource, now: Instant) {
+        let key = match self.selector.key(resource) {
+            Some(key) => key,
+            None => {
+                // Deregistered since the check was armed
+                self.idle_trackers.remove(resource);
+                return;
+            }
+        };
+        let timeouts = key.idle_timeouts();
+        let activity = match key.activity() {
+            Some(activity) if !timeouts.is_empty() => activity,
+            _ => return,
+        };
+        let mut states = Vec::new();
+        let tracker = self.idle_trackers.entry(key.resource()).or_default();
+        if let Some(next) = tracker.poll(&timeouts, activity, now, &mut states) {
+            self.deadlines.arm(next, key.resource(), DeadlineKind::Idle);
+        }
+        for state in states {
+            trace!("{:?} is {:?}", resource, state);
+            self.events
+                .send(Trigger::State(events::StateEvent::Idle(
+                    key.resource(),
+                    state,
+                )))
+                .expect("Dropped unbounded events receiver");
+        }
     }
 
     fn handle_task(&mut self, task: Work<'static, S, K>) {
@@ -168,41 +637,429 @@ where
     }
 }
 
+// Rounding up keeps the loop from waking just short of a deadline
+fn millis_ceil(wait: Duration) -> i64 {
+    cmp::min(wait.as_micros().div_ceil(1000), i64::MAX as u128) as i64
+}
+
+fn event_channel<K: SelectorKey>(capacity: Option<usize>) -> (EventSender<K>, EventReceiver<K>) {
+    let (tx, rx) = futures::sync::mpsc::unbounded();
+    let pending = Arc::new(AtomicUsize::new(0));
+    let sender = EventSender {
+        tx,
+        pending: pending.clone(),
+        sent: Arc::new(AtomicUsize::new(0)),
+        capacity,
+    };
+    (sender, EventReceiver { rx, pending })
+}
+
+// impl EventSender
+impl<K: SelectorKey> EventSender<K> {
+    pub fn send(&self, event: Trigger<K>) -> Result<(), SendError<Trigger<K>>> {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        self.tx
+            .unbounded_send(event)
+            .map(|_| {
+                self.sent.fetch_add(1, Ordering::Relaxed);
+            })
+            .inspect_err(|_| {
+                self.pending.fetch_sub(1, Ordering::AcqRel);
+            })
+    }
+
+    /// Number of events sent over the lifetime of the stream, by the loop and its tasks.
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Number of events sent but not yet taken by the receiver.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.is_full_with(0)
+    }
+
+    // Whether the stream is full once `queued` more events are sent
+    fn is_full_with(&self, queued: usize) -> bool {
+        self.capacity
+            .is_some_and(|cap| self.pending() + queued >= cap)
+    }
+
+    // Reads resume once the consumer has worked through half of the backlog
+    fn is_drained(&self) -> bool {
+        self.capacity.is_none_or(|cap| self.pending() <= cap / 2)
+    }
+}
+
+impl<K: SelectorKey> Clone for EventSender<K> {
+    fn clone(&self) -> Self {
+        EventSender {
+            tx: self.tx.clone(),
+            pending: self.pending.clone(),
+            sent: self.sent.clone(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+// impl RunSummary
+impl AddAssign for RunSummary {
+    fn add_assign(&mut self, other: RunSummary) {
+        self.iterations += other.iterations;
+        self.selected += other.selected;
+        self.events += other.events;
+        self.tasks += other.tasks;
+    }
+}
+
+// impl TaskSender
+impl<S, K> TaskSender<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    /// Queues `task` to run on the loop; fails with the task if the loop has gone away.
+    pub fn send(
+        &self,
+        task: Work<'static, S, K>,
+    ) -> Result<(), mpsc::SendError<Work<'static, S, K>>> {
+        // Counted first, so the loop never runs a task it hasn't counted
+        self.queued.fetch_add(1, Ordering::AcqRel);
+        self.tx.send(task).inspect_err(|_| {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+        })
+    }
+
+    /// Tasks sent and not yet run.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+}
+
+impl<S, K> Clone for TaskSender<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    fn clone(&self) -> Self {
+        TaskSender {
+            tx: self.tx.clone(),
+            queued: self.queued.clone(),
+        }
+    }
+}
+
+impl<S, K> fmt::Debug for TaskSender<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TaskSender")
+            .field("queued", &self.queued())
+            .finish()
+    }
+}
+
+// impl EventReceiver
+impl<K: SelectorKey> Stream for EventReceiver<K> {
+    type Item = Trigger<K>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let polled = self.rx.poll();
+        if let Ok(Async::Ready(Some(_))) = polled {
+            self.pending.fetch_sub(1, Ordering::AcqRel);
+        }
+        polled
+    }
+}
+
 #[derive(Debug)]
 pub enum Trigger<K: SelectorKey> {
     State(events::StateEvent<K>),
-    Read(events::ReadEvent),
+    Read(events::ReadEvent<K>),
     Write(events::WriteEvent),
-    Error(events::ErrorEvent),
+    Error(events::ErrorEvent<K>),
 }
 
 #[derive(Debug)]
 pub enum IoTask<K: SelectorKey> {
     Connect(SocketAddr),
 
-    Write(K::Resource, Bytes),
+    Write(K::Resource, K::Outbound),
     Flush(K::Resource),
-    WriteAndFlush(K::Resource, Bytes),
+    WriteAndFlush(K::Resource, K::Outbound),
 }
 
 pub mod events {
-    use bytes::Bytes;
+    use accept::RejectReason;
+    use channel::ConnectError;
+    use codec::CodecError;
+    use idle::IdleState;
     use selector::SelectorKey;
     use std::net::SocketAddr;
+    use std::time::Duration;
 
     #[derive(Debug)]
     pub enum StateEvent<K: SelectorKey> {
         Connected(K::Resource, SocketAddr),
-        ConnectionError(SocketAddr),
-        Disconnected(SocketAddr),
+        ConnectionError(K::Resource, SocketAddr, ConnectError),
+        Disconnected(K::Resource, SocketAddr),
+        Idle(K::Resource, IdleState),
+        /// The lost or failed connection of `resource` will be re-dialed after `delay`.
+        ///
+        /// The resource stays the same across attempts; writes to it follow the channel's write
+        /// policy until it connects.
+        Reconnecting {
+            resource: K::Resource,
+            remote: SocketAddr,
+            attempt: u32,
+            delay: Duration,
+        },
+        Reconnected(K::Resource, SocketAddr),
+        /// The channel was closed on this side, by the loop's owner or a task, and is no longer
+        /// registered. It won't be re-dialed.
+        Closed(K::Resource),
+        /// The acceptor `K::Resource` closed a peer from this address as soon as it was accepted.
+        Rejected(K::Resource, SocketAddr, RejectReason),
     }
 
     #[derive(Debug)]
-    pub enum ReadEvent {
-        Data(Bytes),
+    pub enum ReadEvent<K: SelectorKey> {
+        Data(K::Resource, K::Inbound),
     }
     #[derive(Debug)]
     pub struct WriteEvent;
     #[derive(Debug)]
-    pub struct ErrorEvent;
+    pub enum ErrorEvent<K: SelectorKey> {
+        Codec(K::Resource, CodecError),
+        WriteRejected(K::Resource),
+        /// The channel's transport failed to send a write, which is lost.
+        Send(K::Resource, String),
+        /// The acceptor `K::Resource` failed to accept or set up a peer. Peers turned away by its
+        /// limits or filter are reported with `StateEvent::Rejected` instead.
+        Accept(K::Resource, String),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel::ChExt;
+    use codec::BytesCodec;
+    use futures::Stream;
+    use idle::{IdleState, IdleTimeouts};
+    use reconnect::Backoff;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use transport::embedded::EmbeddedChannel;
+    use transport::mock::{MockSelector, SelectorCall};
+
+    type Key = EmbeddedChannel<BytesCodec>;
+
+    const NOW: Duration = Duration::from_millis(0);
+
+    // Every event sent by the loop, once it has gone away
+    fn drain(
+        ev_loop: SelectorEventLoop<MockSelector<Key>, Key>,
+        events: EventReceiver<Key>,
+    ) -> Vec<Trigger<Key>> {
+        drop(ev_loop);
+        events.wait().map(|ev| ev.expect("event")).collect()
+    }
+
+    fn remote() -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 9000))
+    }
+
+    #[test]
+    fn bounded_loop_stops_reading_keys_once_full() {
+        let (mut ev_loop, _tasks, events) = SelectorEventLoop::bounded(MockSelector::new(), 2);
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let mut ch = EmbeddedChannel::new(BytesCodec);
+            ch.push_inbound(&b"ping"[..]);
+            ids.push(ch.id());
+            ev_loop.register(ch, Ops::READ);
+        }
+        let ready = ids.iter().map(|id| (*id, Ops::READ)).collect();
+        ev_loop.selector_mut().push_ready(ready);
+        assert_eq!(ev_loop.run_once(NOW).events, 2);
+
+        // Reads stay paused until the consumer has drained the stream
+        ev_loop.run_once(NOW);
+        let paused = SelectorCall::SetReadsPaused(true);
+        assert!(ev_loop.selector().calls().contains(&paused));
+        let mut events = events.wait();
+        for _ in 0..2 {
+            events.next().expect("event").expect("event");
+        }
+        let ready = ids.iter().map(|id| (*id, Ops::READ)).collect();
+        ev_loop.selector_mut().push_ready(ready);
+        assert_eq!(ev_loop.run_once(NOW).events, 1);
+        let resumed = SelectorCall::SetReadsPaused(false);
+        assert!(ev_loop.selector().calls().contains(&resumed));
+    }
+
+    #[test]
+    fn queued_tasks_are_counted_until_run() {
+        let (mut ev_loop, tasks, _events) = SelectorEventLoop::new(MockSelector::<Key>::new());
+        let seen = Arc::new(AtomicUsize::new(usize::MAX));
+        let seen_by_task = seen.clone();
+        tasks
+            .send(Box::new(move |sys: &mut MockSelector<Key>, _| {
+                let depth = sys.metrics().task_queue_depth as usize;
+                seen_by_task.store(depth, Ordering::SeqCst);
+            }))
+            .expect("queued");
+        for _ in 0..2 {
+            tasks
+                .send(Box::new(|_: &mut MockSelector<Key>, _| {}))
+                .expect("queued");
+        }
+        assert_eq!(tasks.queued(), 3);
+
+        assert_eq!(ev_loop.run_once(NOW).tasks, 3);
+        // The first task saw the two queued behind it
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+        assert_eq!(tasks.queued(), 0);
+        let metrics = ev_loop.selector().snapshot();
+        assert!(metrics
+            .to_prometheus()
+            .contains("# TYPE petty_task_queue_depth gauge\npetty_task_queue_depth 0\n"));
+    }
+
+    #[test]
+    fn send_failures_are_counted_and_forwarded() {
+        let (mut ev_loop, _tasks, events) = SelectorEventLoop::new(MockSelector::<Key>::new());
+        let ch = EmbeddedChannel::new(BytesCodec);
+        let id = ch.id();
+        ev_loop.register(ch, Ops::READ);
+        let failed = ErrorEvent::Send(id, "broken pipe".to_owned());
+        ev_loop.events_buf.push(RWEvent::Error(failed));
+        ev_loop.dispatch_events();
+
+        let errors = ev_loop.selector().snapshot().errors();
+        assert_eq!(errors.get(&ErrorKind::Send), Some(&1));
+        match drain(ev_loop, events).as_slice() {
+            [Trigger::Error(events::ErrorEvent::Send(r, why))] => {
+                assert_eq!((*r, why.as_str()), (id, "broken pipe"))
+            }
+            other => panic!("expected a send failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accept_failures_are_counted_and_forwarded() {
+        let (mut ev_loop, _tasks, events) = SelectorEventLoop::new(MockSelector::<Key>::new());
+        let ch = EmbeddedChannel::new(BytesCodec);
+        let id = ch.id();
+        ev_loop.register(ch, Ops::ACCEPT);
+        let failed = ErrorEvent::Accept(id, "too many open files".to_owned());
+        ev_loop.events_buf.push(RWEvent::Error(failed));
+        ev_loop.dispatch_events();
+
+        let errors = ev_loop.selector().snapshot().errors();
+        assert_eq!(errors.get(&ErrorKind::Accept), Some(&1));
+        match drain(ev_loop, events).as_slice() {
+            [Trigger::Error(events::ErrorEvent::Accept(r, why))] => {
+                assert_eq!((*r, why.as_str()), (id, "too many open files"))
+            }
+            other => panic!("expected an accept failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn connects_are_abandoned_after_their_timeout() {
+        let (mut ev_loop, _tasks, events) = SelectorEventLoop::new(MockSelector::new());
+        let mut ch = EmbeddedChannel::connector(BytesCodec, remote())
+            .with_connect_timeout(Duration::from_millis(5));
+        // The channel's virtual clock has no say in the loop's timeouts
+        ch.advance_time(Duration::from_secs(60));
+        ch.connect(&mut Vec::new());
+        let id = ch.id();
+        ev_loop.register(ch, Ops::CONNECT);
+        ev_loop.run_for(Duration::from_millis(30));
+
+        assert!(ev_loop.selector().key(&id).is_none());
+        assert_eq!(
+            ev_loop.selector_mut().metrics().errors[&ErrorKind::Connect],
+            1
+        );
+        match drain(ev_loop, events).as_slice() {
+            [Trigger::State(events::StateEvent::ConnectionError(r, _, ConnectError::TimedOut))] => {
+                assert_eq!(*r, id)
+            }
+            other => panic!("expected a connect timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn idle_timeouts_apply_per_channel() {
+        let (mut ev_loop, _tasks, events) = SelectorEventLoop::new(MockSelector::new());
+        let timeouts = IdleTimeouts::new().with_reader(Duration::from_millis(5));
+        let watched = EmbeddedChannel::new(BytesCodec).with_idle_timeouts(timeouts);
+        let id = watched.id();
+        ev_loop.register(watched, Ops::READ);
+        ev_loop.register(EmbeddedChannel::new(BytesCodec), Ops::READ);
+        ev_loop.run_for(Duration::from_millis(30));
+
+        // Closed channels are forgotten once their next check comes up
+        ev_loop.close(&id);
+        ev_loop.run_for(Duration::from_millis(10));
+        assert!(ev_loop.idle_trackers.is_empty());
+        let events = drain(ev_loop, events);
+        assert!(!events.is_empty());
+        for ev in events {
+            match ev {
+                Trigger::State(events::StateEvent::Idle(r, IdleState::ReaderIdle)) => {
+                    assert_eq!(r, id)
+                }
+                Trigger::State(events::StateEvent::Closed(r)) => assert_eq!(r, id),
+                other => panic!("expected reader idle events, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn lost_connections_are_redialed_under_the_same_resource() {
+        let (mut ev_loop, _tasks, events) = SelectorEventLoop::new(MockSelector::new());
+        let backoff =
+            Backoff::new(Duration::from_millis(1), Duration::from_millis(1)).with_jitter_percent(0);
+        let mut ch = EmbeddedChannel::new(BytesCodec).with_reconnect(backoff);
+        ch.disconnect();
+        let id = ch.id();
+        ev_loop.register(ch, Ops::READ);
+        ev_loop.selector_mut().push_ready(vec![(id, Ops::READ)]);
+        ev_loop.run_for(Duration::from_millis(10));
+        assert_eq!(
+            ev_loop.selector().interest(&id),
+            Some(Ops::CONNECT | Ops::ERROR)
+        );
+
+        ev_loop.selector_mut().push_ready(vec![(id, Ops::CONNECT)]);
+        ev_loop.run_once(NOW);
+        {
+            let key = ev_loop.selector().key(&id).expect("still registered");
+            assert!(key.is_connected());
+            assert_eq!(key.redials(), 1);
+        }
+        match drain(ev_loop, events).as_slice() {
+            [Trigger::State(events::StateEvent::Disconnected(lost, _)), Trigger::State(events::StateEvent::Reconnecting {
+                resource,
+                attempt: 1,
+                ..
+            }), Trigger::State(events::StateEvent::Reconnected(found, _))] => {
+                assert_eq!([*lost, *resource, *found], [id, id, id])
+            }
+            other => panic!("expected a re-dial, got {:?}", other),
+        }
+    }
 }
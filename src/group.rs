@@ -0,0 +1,493 @@
+use async_io::{close, with_key};
+use attribute::AttributeKey;
+use bytes::Bytes;
+use channel::{ChWrite, ChannelError};
+use ev_loop::{EventSender, TaskSender};
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+use selector::{Selector, SelectorKey};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+// Attribute holding the memberships of a channel, one per group it belongs to
+const MEMBERSHIPS: &str = "petty.channel_group.memberships";
+
+type Members<S, K> = Mutex<HashMap<<K as SelectorKey>::Resource, Member<S, K>>>;
+
+/// A set of channels, possibly registered with different loops, that can be written to or closed
+/// together.
+///
+/// Channels leave the group on their own once they are deregistered from their loop, whether they
+/// were closed, disconnected or failed. Connectors their loop re-dials stay members while they
+/// reconnect, and leave if it gives up on them. Clones share the same members.
+pub struct ChannelGroup<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    members: Arc<Members<S, K>>,
+    // Tells apart successive memberships of the same channel
+    generation: Arc<AtomicUsize>,
+}
+
+/// Resolves once every member an operation was sent to has handled it.
+pub struct GroupCompletion<R> {
+    pending: Vec<(R, oneshot::Receiver<Result<(), ChannelError>>)>,
+    outcome: GroupOutcome<R>,
+}
+
+/// Which members a group operation succeeded on, and why it failed on the others.
+///
+/// An operation fails with `ChannelError::Closed` on a member that was deregistered before its
+/// loop got to it or whose loop has stopped, and with the member's own error otherwise, such as a
+/// rejected write.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupOutcome<R> {
+    pub succeeded: Vec<R>,
+    pub failed: Vec<(R, ChannelError)>,
+}
+
+struct Member<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    tasks: TaskSender<S, K>,
+    generation: usize,
+}
+
+// Stored on the channel's key; removes the channel from the group when dropped along with the
+// key's attributes
+struct Membership<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    members: Weak<Members<S, K>>,
+    resource: K::Resource,
+    generation: usize,
+}
+
+// impl ChannelGroup
+impl<S, K> ChannelGroup<S, K>
+where
+    S: Selector<K> + 'static,
+    K: SelectorKey + 'static,
+    K::Resource: Clone + Send + 'static,
+{
+    pub fn new() -> Self {
+        ChannelGroup {
+            members: Arc::new(Mutex::new(HashMap::new())),
+            generation: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Adds the channel for `resource`, registered with the loop taking `tasks`.
+    ///
+    /// Returns false if it is already a member. A channel that turns out not to be registered by
+    /// the time its loop handles the addition leaves the group again.
+    pub fn add(&self, resource: K::Resource, tasks: &TaskSender<S, K>) -> bool {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut members = self.lock();
+            if members.contains_key(&resource) {
+                return false;
+            }
+            let member = Member {
+                tasks: tasks.clone(),
+                generation,
+            };
+            members.insert(resource.clone(), member);
+        }
+        let membership = Membership {
+            members: Arc::downgrade(&self.members),
+            resource: resource.clone(),
+            generation,
+        };
+        let sent = tasks.send(Box::new(move |sys: &mut S, _: EventSender<K>| {
+            if let Some(key) = sys.key_mut(&resource) {
+                key.attributes_mut()
+                    .get_or_insert_with(&memberships_key::<S, K>(), Vec::new)
+                    .push(membership);
+            }
+            // Otherwise the membership is dropped here, taking the channel out of the group
+        }));
+        if sent.is_err() {
+            // The loop has stopped; dropping the task removed the member
+            return false;
+        }
+        true
+    }
+
+    /// Takes the channel for `resource` out of the group, leaving the channel itself open.
+    pub fn remove(&self, resource: &K::Resource) -> bool {
+        self.lock().remove(resource).is_some()
+    }
+
+    pub fn contains(&self, resource: &K::Resource) -> bool {
+        self.lock().contains_key(resource)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    pub fn members(&self) -> Vec<K::Resource> {
+        self.lock().keys().cloned().collect()
+    }
+
+    /// Deregisters and closes every member.
+    pub fn close_all(&self) -> GroupCompletion<K::Resource> {
+        self.each(
+            |sys, events, resource| match close(sys, &events, resource) {
+                Some(_) => Ok(()),
+                None => Err(ChannelError::Closed),
+            },
+        )
+    }
+
+    // Runs `f` for each member on its loop, completing with what `f` returned for each
+    fn each<F>(&self, f: F) -> GroupCompletion<K::Resource>
+    where
+        F: Fn(&mut S, EventSender<K>, &K::Resource) -> Result<(), ChannelError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let f = Arc::new(f);
+        let members: Vec<_> = self
+            .lock()
+            .iter()
+            .map(|(resource, member)| (resource.clone(), member.tasks.clone()))
+            .collect();
+        let mut completion = GroupCompletion::new();
+        for (resource, tasks) in members {
+            let (tx, rx) = oneshot::channel();
+            let f = f.clone();
+            let target = resource.clone();
+            let sent = tasks.send(Box::new(move |sys: &mut S, events: EventSender<K>| {
+                // The receiver may have been dropped by a caller that doesn't wait
+                let _ = tx.send(f(sys, events, &target));
+            }));
+            match sent {
+                Ok(()) => completion.pending.push((resource, rx)),
+                Err(_) => {
+                    // The loop has stopped, so its channels are gone
+                    self.lock().remove(&resource);
+                    completion
+                        .outcome
+                        .failed
+                        .push((resource, ChannelError::Closed));
+                }
+            }
+        }
+        completion
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<K::Resource, Member<S, K>>> {
+        self.members.lock().expect("Poisoned channel group")
+    }
+}
+
+impl<S, K> ChannelGroup<S, K>
+where
+    S: Selector<K> + 'static,
+    K: SelectorKey<Outbound = Bytes> + 'static,
+    K::Resource: Clone + Send + 'static,
+{
+    /// Writes `data` to every member, failing on those that reject or fail to send it.
+    pub fn write_all(&self, data: Bytes) -> GroupCompletion<K::Resource> {
+        self.each(move |sys, events, resource| {
+            let data = Cell::new(Some(data.clone()));
+            with_key(sys, events, resource, |ev, key| {
+                if let Some(data) = data.take() {
+                    key.io().write(data, ev);
+                }
+            })
+        })
+    }
+}
+
+impl<S, K> Clone for ChannelGroup<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    fn clone(&self) -> Self {
+        ChannelGroup {
+            members: self.members.clone(),
+            generation: self.generation.clone(),
+        }
+    }
+}
+
+impl<S, K> Default for ChannelGroup<S, K>
+where
+    S: Selector<K> + 'static,
+    K: SelectorKey + 'static,
+    K::Resource: Clone + Send + 'static,
+{
+    fn default() -> Self {
+        ChannelGroup::new()
+    }
+}
+
+impl<S, K> fmt::Debug for ChannelGroup<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let members = self.members.lock().expect("Poisoned channel group");
+        f.debug_set().entries(members.keys()).finish()
+    }
+}
+
+// impl GroupCompletion
+impl<R> GroupCompletion<R> {
+    fn new() -> Self {
+        GroupCompletion {
+            pending: Vec::new(),
+            outcome: GroupOutcome::default(),
+        }
+    }
+}
+
+impl<R> Future for GroupCompletion<R> {
+    type Item = GroupOutcome<R>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut i = 0;
+        while i < self.pending.len() {
+            let done = match self.pending[i].1.poll() {
+                Ok(Async::NotReady) => None,
+                Ok(Async::Ready(res)) => Some(res),
+                // The loop dropped the task without running it
+                Err(oneshot::Canceled) => Some(Err(ChannelError::Closed)),
+            };
+            match done {
+                None => i += 1,
+                Some(res) => {
+                    let (resource, _) = self.pending.swap_remove(i);
+                    match res {
+                        Ok(()) => self.outcome.succeeded.push(resource),
+                        Err(why) => self.outcome.failed.push((resource, why)),
+                    }
+                }
+            }
+        }
+        if self.pending.is_empty() {
+            Ok(Async::Ready(mem::take(&mut self.outcome)))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<R: fmt::Debug> fmt::Debug for GroupCompletion<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GroupCompletion")
+            .field("pending", &self.pending.len())
+            .field("outcome", &self.outcome)
+            .finish()
+    }
+}
+
+// impl GroupOutcome
+impl<R> GroupOutcome<R> {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl<R> Default for GroupOutcome<R> {
+    fn default() -> Self {
+        GroupOutcome {
+            succeeded: Vec::new(),
+            failed: Vec::new(),
+        }
+    }
+}
+
+// impl Membership
+impl<S, K> Drop for Membership<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    fn drop(&mut self) {
+        let members = match self.members.upgrade() {
+            Some(members) => members,
+            None => return,
+        };
+        // A poisoned group can't be trusted to tell who's still a member, so leave it alone
+        // rather than panic while dropping the key's attributes
+        let mut members = match members.lock() {
+            Ok(members) => members,
+            Err(_) => return,
+        };
+        // A channel removed and added again has a newer membership, which stays
+        if members.get(&self.resource).map(|m| m.generation) == Some(self.generation) {
+            members.remove(&self.resource);
+        }
+    }
+}
+
+fn memberships_key<S, K>() -> AttributeKey<Vec<Membership<S, K>>>
+where
+    S: Selector<K> + 'static,
+    K: SelectorKey + 'static,
+    K::Resource: Send,
+{
+    AttributeKey::new(MEMBERSHIPS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::BytesCodec;
+    use ev_loop::SelectorEventLoop;
+    use ops::Ops;
+    use reconnect::Backoff;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use transport::embedded::EmbeddedChannel;
+    use transport::mock::MockSelector;
+
+    type Key = EmbeddedChannel<BytesCodec>;
+
+    const NOW: Duration = Duration::from_millis(0);
+
+    #[test]
+    fn write_all_reports_why_it_failed_on_each_member() {
+        let (mut ev_loop, tasks, _events) = SelectorEventLoop::new(MockSelector::<Key>::new());
+        let remote: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let channels = vec![
+            EmbeddedChannel::new(BytesCodec),
+            EmbeddedChannel::connector(BytesCodec, remote),
+            EmbeddedChannel::new(BytesCodec),
+        ];
+        let ids: Vec<_> = channels.iter().map(|ch| ch.id()).collect();
+        let group = ChannelGroup::new();
+        for ch in channels {
+            let id = ch.id();
+            ev_loop.register(ch, Ops::READ);
+            assert!(group.add(id, &tasks));
+        }
+        ev_loop.run_once(NOW);
+
+        let completion = group.write_all(Bytes::from(&b"hello"[..]));
+        // Closed before its loop gets to the write
+        ev_loop.close(&ids[2]);
+        ev_loop.run_once(NOW);
+        let outcome = completion.wait().expect("outcome");
+        assert_eq!(outcome.succeeded, vec![ids[0]]);
+        assert_eq!(outcome.failed.len(), 2);
+        assert!(outcome
+            .failed
+            .contains(&(ids[1], ChannelError::WriteRejected)));
+        assert!(outcome.failed.contains(&(ids[2], ChannelError::Closed)));
+        assert_eq!(group.members().len(), 2);
+    }
+
+    #[test]
+    fn close_all_deregisters_every_member() {
+        let (mut ev_loop, tasks, _events) = SelectorEventLoop::new(MockSelector::<Key>::new());
+        let group = ChannelGroup::new();
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let ch = EmbeddedChannel::new(BytesCodec);
+            ids.push(ch.id());
+            assert!(group.add(ch.id(), &tasks));
+            ev_loop.register(ch, Ops::READ);
+        }
+        let outsider = EmbeddedChannel::new(BytesCodec);
+        let outsider_id = outsider.id();
+        ev_loop.register(outsider, Ops::READ);
+        ev_loop.run_once(NOW);
+
+        let completion = group.close_all();
+        ev_loop.run_once(NOW);
+        let mut outcome = completion.wait().expect("outcome");
+        outcome.succeeded.sort();
+        ids.sort();
+        assert_eq!(outcome.succeeded, ids);
+        assert!(outcome.failed.is_empty());
+        for id in &ids {
+            assert!(ev_loop.selector().key(id).is_none());
+        }
+        assert!(ev_loop.selector().key(&outsider_id).is_some());
+        assert_eq!(ev_loop.selector_mut().metrics().closed, 2);
+        assert!(group.is_empty());
+    }
+
+    #[test]
+    fn redialed_connectors_stay_members() {
+        let (mut ev_loop, tasks, _events) = SelectorEventLoop::new(MockSelector::<Key>::new());
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(1))
+            .with_jitter_percent(0)
+            .with_max_attempts(1);
+        let redialed = EmbeddedChannel::new(BytesCodec).with_reconnect(backoff);
+        let lost = EmbeddedChannel::new(BytesCodec);
+        let ids = [redialed.id(), lost.id()];
+        let group = ChannelGroup::new();
+        for ch in [redialed, lost] {
+            assert!(group.add(ch.id(), &tasks));
+            ev_loop.register(ch, Ops::READ);
+        }
+        ev_loop.run_once(NOW);
+
+        for id in &ids {
+            ev_loop
+                .selector_mut()
+                .key_mut(id)
+                .expect("member")
+                .disconnect();
+        }
+        let ready = ids.iter().map(|id| (*id, Ops::READ)).collect();
+        ev_loop.selector_mut().push_ready(ready);
+        ev_loop.run_for(Duration::from_millis(10));
+        assert_eq!(group.members(), vec![ids[0]]);
+
+        ev_loop
+            .selector_mut()
+            .push_ready(vec![(ids[0], Ops::CONNECT)]);
+        ev_loop.run_once(NOW);
+        assert!(ev_loop
+            .selector()
+            .key(&ids[0])
+            .expect("redialed")
+            .is_connected());
+        let completion = group.write_all(Bytes::from(&b"hello"[..]));
+        ev_loop.run_once(NOW);
+        let outcome = completion.wait().expect("outcome");
+        assert_eq!(outcome.succeeded, vec![ids[0]]);
+
+        // Once the loop gives up on it, it leaves like any other channel
+        ev_loop
+            .selector_mut()
+            .key_mut(&ids[0])
+            .expect("member")
+            .disconnect();
+        ev_loop.selector_mut().push_ready(vec![(ids[0], Ops::READ)]);
+        ev_loop.run_for(Duration::from_millis(10));
+        assert!(group.contains(&ids[0]));
+        let key = ev_loop.selector_mut().key_mut(&ids[0]).expect("redialing");
+        key.refuse_connect();
+        ev_loop
+            .selector_mut()
+            .push_ready(vec![(ids[0], Ops::CONNECT)]);
+        ev_loop.run_once(NOW);
+        assert!(ev_loop.selector().key(&ids[0]).is_none());
+        assert!(group.is_empty());
+    }
+}
@@ -0,0 +1,267 @@
+use async_io::{close_when_flushed, with_key};
+use channel::ChWrite;
+use ev_loop::events;
+use ev_loop::{EventReceiver, EventSender, TaskSender, Trigger};
+use futures::Stream;
+use selector::{Selector, SelectorKey};
+use std::cell::Cell;
+
+pub use codec::http::{Body, HttpServerCodec, Request, Response, Version};
+
+/// Produces a response for every decoded request.
+pub trait Handler {
+    fn handle(&mut self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(&Request) -> Response,
+{
+    fn handle(&mut self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+/// Answers requests arriving on `events` with `handler` until the event loop goes away.
+///
+/// Meant for channels created with an `HttpServerCodec`, such as peers of a `TcpChannel`
+/// listener, which plain HTTP clients can reach. Each response is written by a task on the loop,
+/// using the request's HTTP version; the channel is closed once the response has been sent unless
+/// both the request and the handler keep it alive. Requests the codec can't decode are answered
+/// with 400, 413 or 431 and the channel is closed.
+pub fn serve<S, K, H>(events: EventReceiver<K>, tasks: &TaskSender<S, K>, mut handler: H)
+where
+    S: Selector<K> + 'static,
+    K: SelectorKey<Inbound = Request, Outbound = Response> + 'static,
+    K::Resource: Send,
+    H: Handler,
+{
+    for ev in events.wait() {
+        let (resource, response) = match ev {
+            Ok(Trigger::Read(events::ReadEvent::Data(resource, request))) => {
+                trace!("{:?} {} {}", resource, request.method, request.uri);
+                let mut response = handler.handle(&request);
+                response.version = request.version;
+                response.keep_alive &= request.keep_alive();
+                (resource, response)
+            }
+            Ok(Trigger::Error(events::ErrorEvent::Codec(resource, why))) => {
+                debug!("{:?} rejecting malformed request: {}", resource, why);
+                (resource, Response::for_error(&why))
+            }
+            Ok(_) => continue,
+            Err(()) => break,
+        };
+        let task = Box::new(move |sys: &mut S, events: EventSender<K>| {
+            respond(sys, events, resource, response)
+        });
+        if tasks.send(task).is_err() {
+            break;
+        }
+    }
+}
+
+fn respond<S, K>(sys: &mut S, events: EventSender<K>, resource: K::Resource, response: Response)
+where
+    S: Selector<K>,
+    K: SelectorKey<Outbound = Response>,
+{
+    let keep_alive = response.keep_alive;
+    let written = write(sys, events.clone(), &resource, response);
+    if !written {
+        // Most likely the handler's response didn't encode; the client still gets an answer
+        let response = Response::new(500).with_keep_alive(false);
+        write(sys, events.clone(), &resource, response);
+    }
+    if !written || !keep_alive {
+        close_when_flushed(sys, &events, &resource);
+    }
+}
+
+pub(crate) fn write<S, K>(
+    sys: &mut S,
+    events: EventSender<K>,
+    resource: &K::Resource,
+    msg: K::Outbound,
+) -> bool
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    let msg = Cell::new(Some(msg));
+    with_key(sys, events, resource, |ev, key| {
+        if let Some(msg) = msg.take() {
+            key.io().write(msg, ev);
+        }
+    })
+    .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use channel::ChannelId;
+    use codec::Encoder;
+    use ev_loop::SelectorEventLoop;
+    use ops::Ops;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use transport::embedded::EmbeddedChannel;
+    use transport::mock::MockSelector;
+    use transport::tcp::{TcpChannel, TcpKey, TcpOptions, TcpSelector};
+
+    type Key = EmbeddedChannel<HttpServerCodec>;
+    type Loop = SelectorEventLoop<MockSelector<Key>, Key>;
+
+    // Serves `request` on a fresh channel until the response task has run, then hands the loop
+    // and channel to `check`
+    fn exchange<F>(request: &'static [u8], check: F)
+    where
+        F: FnOnce(&mut Loop, ChannelId),
+    {
+        exchange_on(
+            EmbeddedChannel::new(HttpServerCodec::new(64, 64)),
+            request,
+            check,
+        )
+    }
+
+    fn exchange_on<F>(mut ch: Key, request: &'static [u8], check: F)
+    where
+        F: FnOnce(&mut Loop, ChannelId),
+    {
+        let (mut ev_loop, tasks, events) = SelectorEventLoop::new(MockSelector::new());
+        ch.push_inbound(request);
+        let id = ch.id();
+        ev_loop.register(ch, Ops::READ);
+        ev_loop.selector_mut().push_ready(vec![(id, Ops::READ)]);
+        let server = thread::spawn(move || {
+            serve(events, &tasks, |request: &Request| {
+                Response::for_request(request, 200).with_body("hi")
+            })
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ev_loop.run_once(Duration::from_millis(1)).tasks == 0 {
+            assert!(Instant::now() < deadline, "no response");
+        }
+        check(&mut ev_loop, id);
+        drop(ev_loop);
+        server.join().expect("server thread");
+    }
+
+    fn encoded(response: Response) -> usize {
+        let mut dst = BytesMut::new();
+        let mut codec = HttpServerCodec::new(64, 64);
+        codec.encode(response, &mut dst).expect("encodable");
+        dst.len()
+    }
+
+    #[test]
+    fn keep_alive_responses_leave_the_channel_open() {
+        exchange(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n", |ev_loop, id| {
+            let key = ev_loop
+                .selector_mut()
+                .key_mut(&id)
+                .expect("still registered");
+            let wire = key.read_outbound().expect("response");
+            assert!(wire.starts_with(b"HTTP/1.1 200 OK\r\n"));
+            assert!(wire.ends_with(b"\r\n\r\nhi"));
+        });
+    }
+
+    #[test]
+    fn closing_responses_close_the_channel() {
+        exchange(b"GET / HTTP/1.0\r\n\r\n", |ev_loop, id| {
+            assert!(ev_loop.selector().key(&id).is_none());
+            assert_eq!(ev_loop.selector_mut().metrics().closed, 1);
+            let mut expected = Response::new(200).with_body("hi").with_keep_alive(false);
+            expected.version = Version::Http10;
+            let written = ev_loop.selector().snapshot().bytes_written();
+            assert_eq!(written, encoded(expected) as u64);
+        });
+        exchange(
+            b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+            |ev_loop, id| {
+                assert!(ev_loop.selector().key(&id).is_none());
+            },
+        );
+    }
+
+    #[test]
+    fn closing_responses_are_sent_before_the_channel_closes() {
+        let mut ch = EmbeddedChannel::new(HttpServerCodec::new(64, 64));
+        ch.set_writable(false);
+        exchange_on(ch, b"GET / HTTP/1.0\r\n\r\n", |ev_loop, id| {
+            let key = ev_loop.selector_mut().key_mut(&id).expect("still sending");
+            assert_eq!(key.pending_writes(), 1);
+            key.set_writable(true);
+            ev_loop.selector_mut().push_ready(vec![(id, Ops::WRITE)]);
+            ev_loop.run_once(Duration::from_millis(0));
+            assert!(ev_loop.selector().key(&id).is_none());
+            let mut expected = Response::new(200).with_body("hi").with_keep_alive(false);
+            expected.version = Version::Http10;
+            let written = ev_loop.selector().snapshot().bytes_written();
+            assert_eq!(written, encoded(expected) as u64);
+        });
+    }
+
+    #[test]
+    fn answers_plain_tcp_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let codec = HttpServerCodec::new(256, 64);
+        let ch = TcpChannel::with_codec(listener, codec, TcpOptions::default()).expect("acceptor");
+        let addr = ch.local_addr().expect("bound address");
+        let (mut ev_loop, tasks, events) = SelectorEventLoop::new(TcpSelector::new());
+        ev_loop.register(TcpKey::new(ch), Ops::ACCEPT);
+        let server = thread::spawn(move || {
+            serve(events, &tasks, |request: &Request| {
+                Response::for_request(request, 200).with_body(request.uri.clone())
+            })
+        });
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).expect("connect");
+            stream
+                .write_all(b"GET /health HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+                .expect("write");
+            let mut wire = Vec::new();
+            stream.read_to_end(&mut wire).expect("read until closed");
+            wire
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ev_loop.selector().snapshot().event_loop.closed == 0 {
+            assert!(Instant::now() < deadline, "no response");
+            ev_loop.run_once(Duration::from_millis(1));
+        }
+        let wire = client.join().expect("client thread");
+        assert!(wire.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(wire.ends_with(b"Connection: close\r\nContent-Length: 7\r\n\r\n/health"));
+        drop(ev_loop);
+        server.join().expect("server thread");
+    }
+
+    #[test]
+    fn malformed_requests_get_400_and_are_closed() {
+        exchange(b"NONSENSE\r\n\r\n", |ev_loop, id| {
+            assert!(ev_loop.selector().key(&id).is_none());
+            let written = ev_loop.selector().snapshot().bytes_written();
+            let expected = Response::new(400).with_keep_alive(false);
+            assert_eq!(written, encoded(expected) as u64);
+        });
+    }
+
+    #[test]
+    fn oversized_heads_get_431_and_are_closed() {
+        let request = b"GET / HTTP/1.1\r\nX-Padding: 0123456789012345678901234567890123456789\r\n";
+        exchange(request, |ev_loop, id| {
+            assert!(ev_loop.selector().key(&id).is_none());
+            let written = ev_loop.selector().snapshot().bytes_written();
+            let expected = Response::new(431).with_keep_alive(false);
+            assert_eq!(written, encoded(expected) as u64);
+        });
+    }
+}
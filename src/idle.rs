@@ -0,0 +1,128 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// How long a channel may go without reads, writes, or either before an idle event is emitted.
+///
+/// While a channel stays idle, the event is repeated every time the duration passes again.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct IdleTimeouts {
+    pub reader: Option<Duration>,
+    pub writer: Option<Duration>,
+    pub all: Option<Duration>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum IdleState {
+    ReaderIdle,
+    WriterIdle,
+    AllIdle,
+}
+
+/// When a channel last read or wrote any bytes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Activity {
+    pub last_read: Instant,
+    pub last_write: Instant,
+}
+
+// Per-channel bookkeeping so an idle period is reported once per timeout
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IdleTracker {
+    reader_fired: Option<Instant>,
+    writer_fired: Option<Instant>,
+    all_fired: Option<Instant>,
+    // The idle check the loop has armed for the channel; earlier ones are stale
+    pub(crate) armed: Option<Instant>,
+}
+
+// impl IdleTimeouts
+impl IdleTimeouts {
+    pub fn new() -> Self {
+        IdleTimeouts::default()
+    }
+
+    pub fn with_reader(mut self, timeout: Duration) -> Self {
+        self.reader = Some(timeout);
+        self
+    }
+
+    pub fn with_writer(mut self, timeout: Duration) -> Self {
+        self.writer = Some(timeout);
+        self
+    }
+
+    pub fn with_all(mut self, timeout: Duration) -> Self {
+        self.all = Some(timeout);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reader.is_none() && self.writer.is_none() && self.all.is_none()
+    }
+}
+
+// impl Activity
+impl Activity {
+    pub fn new(now: Instant) -> Self {
+        Activity {
+            last_read: now,
+            last_write: now,
+        }
+    }
+
+    pub fn last_any(&self) -> Instant {
+        cmp::max(self.last_read, self.last_write)
+    }
+}
+
+// impl IdleTracker
+impl IdleTracker {
+    /// Pushes the idle states reached by `now` and returns when the next one is due.
+    pub(crate) fn poll(
+        &mut self,
+        timeouts: &IdleTimeouts,
+        activity: Activity,
+        now: Instant,
+        fired: &mut Vec<IdleState>,
+    ) -> Option<Instant> {
+        let checks = [
+            (
+                IdleState::ReaderIdle,
+                timeouts.reader,
+                activity.last_read,
+                &mut self.reader_fired,
+            ),
+            (
+                IdleState::WriterIdle,
+                timeouts.writer,
+                activity.last_write,
+                &mut self.writer_fired,
+            ),
+            (
+                IdleState::AllIdle,
+                timeouts.all,
+                activity.last_any(),
+                &mut self.all_fired,
+            ),
+        ];
+
+        let mut next: Option<Instant> = None;
+        for (state, timeout, last, last_fired) in checks {
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => continue,
+            };
+            // Activity since the last event restarts the countdown
+            let since = last_fired.map_or(last, |at| cmp::max(at, last));
+            let mut deadline = since + timeout;
+            if now >= deadline {
+                fired.push(state);
+                *last_fired = Some(now);
+                deadline = now + timeout;
+            }
+            next = Some(next.map_or(deadline, |n| cmp::min(n, deadline)));
+        }
+        self.armed = next;
+        next
+    }
+}
@@ -1,11 +1,34 @@
+extern crate bincode;
 extern crate bytes;
 extern crate core;
 extern crate futures;
+extern crate libc;
 extern crate libudt4_sys as udtsys;
+#[macro_use]
+extern crate log;
+extern crate rand;
+extern crate serde;
+extern crate serde_json;
+extern crate sha1_smol;
+extern crate tokio_io;
 pub extern crate udt;
 
+pub mod accept;
+pub mod async_io;
+pub mod attribute;
 pub mod channel;
+pub mod codec;
 pub mod ev_loop;
+pub mod group;
+pub mod http;
+pub mod idle;
+pub mod loop_future;
+pub mod metrics;
 pub mod ops;
+pub mod reconnect;
 pub mod selector;
+pub mod timer;
 pub mod transport;
+pub mod websocket;
+
+mod entropy;
@@ -0,0 +1,222 @@
+use ev_loop::SelectorEventLoop;
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll};
+use selector::{Selector, SelectorKey};
+use std::cmp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_POLL_INTERVAL_MS: u64 = 10;
+
+/// Drives a `SelectorEventLoop` from a futures executor, such as tokio's runtime, instead of a
+/// dedicated thread.
+///
+/// Each poll runs one iteration of the loop whose select doesn't block, so the executor thread is
+/// never held. While iterations find work the task is rescheduled straight away. Once idle, it is
+/// woken by a helper thread at the next timer, idle check or re-dial, or after the poll interval
+/// at the latest: UDT's epoll has no file descriptor a reactor could watch, so channel readiness
+/// is noticed at that point. The future resolves to the event loop once `LoopStopper::stop` is
+/// called.
+pub struct LoopFuture<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    event_loop: Option<SelectorEventLoop<S, K>>,
+    poll_interval: Duration,
+    // Started the first time the loop goes idle
+    waker: Option<Waker>,
+    stop: Arc<Stop>,
+}
+
+/// Stops a `LoopFuture` from any thread.
+#[derive(Debug, Clone)]
+pub struct LoopStopper {
+    stop: Arc<Stop>,
+}
+
+#[derive(Debug)]
+struct Stop {
+    stopped: AtomicBool,
+    // The task that last polled the future, to be woken up by `stop`
+    task: Mutex<Option<Task>>,
+}
+
+// Notifies a task once a point in time has passed; a newer request replaces the one waiting. The
+// thread exits once the future drops its end.
+#[derive(Debug)]
+struct Waker {
+    requests: mpsc::Sender<(Task, Instant)>,
+}
+
+// impl LoopFuture
+impl<S, K> LoopFuture<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    pub fn new(event_loop: SelectorEventLoop<S, K>) -> Self {
+        LoopFuture {
+            event_loop: Some(event_loop),
+            poll_interval: Duration::from_millis(DEFAULT_POLL_INTERVAL_MS),
+            waker: None,
+            stop: Arc::new(Stop {
+                stopped: AtomicBool::new(false),
+                task: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// How long an idle loop goes before checking its channels again; timers due sooner cut it
+    /// short.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn stopper(&self) -> LoopStopper {
+        LoopStopper {
+            stop: self.stop.clone(),
+        }
+    }
+}
+
+impl<S, K> Future for LoopFuture<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    type Item = SelectorEventLoop<S, K>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // Parked before checking the flag, so a concurrent stop either is seen or wakes us
+        *self.stop.task.lock().expect("Poisoned loop stopper") = Some(task::current());
+        if self.stop.stopped.load(Ordering::Acquire) {
+            return match self.event_loop.take() {
+                Some(event_loop) => Ok(Async::Ready(event_loop)),
+                None => Err(()),
+            };
+        }
+        let event_loop = match self.event_loop {
+            Some(ref mut event_loop) => event_loop,
+            None => return Err(()),
+        };
+        let summary = event_loop.run_once(Duration::from_millis(0));
+        if summary.selected > 0 || summary.tasks > 0 {
+            task::current().notify();
+            return Ok(Async::NotReady);
+        }
+        let mut wake_at = Instant::now() + self.poll_interval;
+        if let Some(deadline) = event_loop.next_deadline() {
+            wake_at = cmp::min(wake_at, deadline);
+        }
+        self.waker
+            .get_or_insert_with(Waker::spawn)
+            .notify_at(task::current(), wake_at);
+        Ok(Async::NotReady)
+    }
+}
+
+// impl LoopStopper
+impl LoopStopper {
+    /// Makes the future resolve, waking up the task polling it.
+    pub fn stop(&self) {
+        self.stop.stopped.store(true, Ordering::Release);
+        let task = self.stop.task.lock().expect("Poisoned loop stopper").take();
+        if let Some(task) = task {
+            task.notify();
+        }
+    }
+}
+
+// impl Waker
+impl Waker {
+    fn spawn() -> Self {
+        let (requests, rx) = mpsc::channel::<(Task, Instant)>();
+        thread::spawn(move || {
+            while let Ok(mut request) = rx.recv() {
+                loop {
+                    let wait = request.1.saturating_duration_since(Instant::now());
+                    match rx.recv_timeout(wait) {
+                        Ok(newer) => request = newer,
+                        Err(RecvTimeoutError::Timeout) => {
+                            request.0.notify();
+                            break;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            }
+        });
+        Waker { requests }
+    }
+
+    fn notify_at(&self, task: Task, at: Instant) {
+        if let Err(mpsc::SendError((task, _))) = self.requests.send((task, at)) {
+            // Without the thread the loop can only spin
+            task.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::BytesCodec;
+    use futures::sync::oneshot;
+    use transport::embedded::EmbeddedChannel;
+    use transport::mock::{MockSelector, SelectorCall};
+    use transport::tcp::TcpSelector;
+
+    type Key = EmbeddedChannel<BytesCodec>;
+
+    #[test]
+    fn stopping_resolves_to_the_event_loop() {
+        let (ev_loop, _tasks, _events) = SelectorEventLoop::new(MockSelector::<Key>::new());
+        let future = LoopFuture::new(ev_loop).with_poll_interval(Duration::from_millis(5));
+        let stopper = future.stopper();
+        let started = Instant::now();
+        let stopping = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            stopper.stop();
+        });
+        let ev_loop = future.wait().expect("stopped loop");
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        for call in ev_loop.selector().calls() {
+            if let SelectorCall::Select(timeout) = *call {
+                assert_eq!(timeout, 0);
+            }
+        }
+        stopping.join().expect("stopping thread");
+    }
+
+    #[test]
+    fn other_futures_on_the_executor_keep_running() {
+        // A real selector, whose select would hold the thread for as long as it is allowed to
+        let (ev_loop, _tasks, _events) = SelectorEventLoop::new(TcpSelector::<BytesCodec>::new());
+        let future = LoopFuture::new(ev_loop).with_poll_interval(Duration::from_secs(1));
+        let stopper = future.stopper();
+        let (tx, rx) = oneshot::channel();
+        let sending = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            let _ = tx.send(Instant::now());
+        });
+        let other = rx.map_err(|_| ()).map(move |sent| {
+            let waited = sent.elapsed();
+            stopper.stop();
+            waited
+        });
+
+        // Both futures share the thread calling `wait`
+        let (ev_loop, waited) = future.join(other).wait().expect("both resolved");
+        assert!(waited < Duration::from_millis(500), "waited {:?}", waited);
+        // Idle, the loop was only polled when first run and when woken for the stop
+        let iterations = ev_loop.selector().snapshot().event_loop.iterations;
+        assert!(iterations <= 3, "{} iterations", iterations);
+        sending.join().expect("sending thread");
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fmt::Write;
+use std::hash::Hash;
+use std::ops::AddAssign;
+use std::time::Duration;
+
+// Bucket upper bounds for latencies, in microseconds
+const LATENCY_US_BOUNDS: &[u64] = &[
+    10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000,
+];
+// Bucket upper bounds for per-iteration counts (ready keys, tasks)
+const COUNT_BOUNDS: &[u64] = &[0, 1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ErrorKind {
+    Select,
+    Accept,
+    Recv,
+    Send,
+    Registration,
+    TaskQueue,
+    Codec,
+    Connect,
+}
+
+/// Fixed-bucket histogram; each bucket counts observations `<=` its bound.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<u64>,
+    count: u64,
+    sum: u64,
+}
+
+/// Counters and histograms kept for a single event loop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopMetrics {
+    pub iterations: u64,
+    pub select_latency_us: Histogram,
+    pub ready_keys: Histogram,
+    pub tasks_run: Histogram,
+    pub accepted: u64,
+    // Peers closed by their acceptor as soon as they were accepted
+    pub rejected: u64,
+    pub closed: u64,
+    // Tasks sent to the loop and not yet run, as of the last task run
+    pub task_queue_depth: u64,
+    pub errors: BTreeMap<ErrorKind, u64>,
+    // Counters of channels no longer registered, so that totals never go down
+    pub deregistered: ChannelMetrics,
+}
+
+/// Counters kept for a single channel.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ChannelMetrics {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub recv_errors: u64,
+    pub send_errors: u64,
+}
+
+/// Point-in-time snapshot of a loop and all of its registered channels.
+#[derive(Debug, Clone)]
+pub struct Metrics<R: Hash + Eq> {
+    pub event_loop: LoopMetrics,
+    pub channels: HashMap<R, ChannelMetrics>,
+}
+
+// impl ErrorKind
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ErrorKind::Select => "select",
+            ErrorKind::Accept => "accept",
+            ErrorKind::Recv => "recv",
+            ErrorKind::Send => "send",
+            ErrorKind::Registration => "registration",
+            ErrorKind::TaskQueue => "task_queue",
+            ErrorKind::Codec => "codec",
+            ErrorKind::Connect => "connect",
+        }
+    }
+}
+
+// impl Histogram
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            count: 0,
+            sum: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        if let Some(idx) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[idx] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /// Cumulative `(upper bound, count)` pairs, as used by Prometheus `le` buckets.
+    pub fn cumulative(&self) -> Vec<(u64, u64)> {
+        let mut acc = 0;
+        self.bounds
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|(bound, n)| {
+                acc += n;
+                (*bound, acc)
+            })
+            .collect()
+    }
+}
+
+// impl LoopMetrics
+impl LoopMetrics {
+    pub fn new() -> Self {
+        LoopMetrics {
+            iterations: 0,
+            select_latency_us: Histogram::new(LATENCY_US_BOUNDS),
+            ready_keys: Histogram::new(COUNT_BOUNDS),
+            tasks_run: Histogram::new(COUNT_BOUNDS),
+            accepted: 0,
+            rejected: 0,
+            closed: 0,
+            task_queue_depth: 0,
+            errors: BTreeMap::new(),
+            deregistered: ChannelMetrics::default(),
+        }
+    }
+
+    pub fn record_select(&mut self, latency: Duration, ready: usize) {
+        let micros = latency.as_secs() * 1_000_000 + u64::from(latency.subsec_micros());
+        self.iterations += 1;
+        self.select_latency_us.record(micros);
+        self.ready_keys.record(ready as u64);
+    }
+
+    pub fn record_tasks(&mut self, run: usize) {
+        self.tasks_run.record(run as u64);
+    }
+
+    pub fn record_task_queue_depth(&mut self, depth: usize) {
+        self.task_queue_depth = depth as u64;
+    }
+
+    pub fn record_accepted(&mut self) {
+        self.accepted += 1;
+    }
+
+    pub fn record_rejected(&mut self) {
+        self.rejected += 1;
+    }
+
+    pub fn record_closed(&mut self) {
+        self.closed += 1;
+    }
+
+    pub fn record_error(&mut self, kind: ErrorKind) {
+        *self.errors.entry(kind).or_insert(0) += 1;
+    }
+
+    /// Keeps the counters of a channel that is being deregistered.
+    pub fn record_deregistered(&mut self, channel: ChannelMetrics) {
+        self.deregistered += channel;
+    }
+}
+
+impl Default for LoopMetrics {
+    fn default() -> Self {
+        LoopMetrics::new()
+    }
+}
+
+// impl ChannelMetrics
+impl AddAssign for ChannelMetrics {
+    fn add_assign(&mut self, other: ChannelMetrics) {
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.recv_errors += other.recv_errors;
+        self.send_errors += other.send_errors;
+    }
+}
+
+// impl Metrics
+impl<R: Hash + Eq> Metrics<R> {
+    /// Bytes read by every channel the loop has had, registered or not.
+    pub fn bytes_read(&self) -> u64 {
+        let registered: u64 = self.channels.values().map(|m| m.bytes_read).sum();
+        self.event_loop.deregistered.bytes_read + registered
+    }
+
+    /// Bytes written by every channel the loop has had, registered or not.
+    pub fn bytes_written(&self) -> u64 {
+        let registered: u64 = self.channels.values().map(|m| m.bytes_written).sum();
+        self.event_loop.deregistered.bytes_written + registered
+    }
+
+    /// Loop-level errors merged with the receive errors of every channel the loop has had.
+    ///
+    /// Send errors are counted by the loop as channels report them, so channels' own
+    /// `send_errors` aren't added again.
+    pub fn errors(&self) -> BTreeMap<ErrorKind, u64> {
+        let mut errors = self.event_loop.errors.clone();
+        let deregistered = &self.event_loop.deregistered;
+        for ch in Some(deregistered).into_iter().chain(self.channels.values()) {
+            *errors.entry(ErrorKind::Recv).or_insert(0) += ch.recv_errors;
+        }
+        errors.retain(|_, n| *n > 0);
+        errors
+    }
+}
+
+impl<R: Hash + Eq + Display> Metrics<R> {
+    /// Renders the snapshot in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let lp = &self.event_loop;
+
+        counter(
+            &mut out,
+            "petty_loop_iterations_total",
+            "Event loop iterations.",
+            lp.iterations,
+        );
+        histogram(
+            &mut out,
+            "petty_select_latency_us",
+            "Time spent in select, in microseconds.",
+            &lp.select_latency_us,
+        );
+        histogram(
+            &mut out,
+            "petty_ready_keys",
+            "Ready keys per select.",
+            &lp.ready_keys,
+        );
+        histogram(
+            &mut out,
+            "petty_tasks_run",
+            "Tasks run per iteration.",
+            &lp.tasks_run,
+        );
+        gauge(
+            &mut out,
+            "petty_task_queue_depth",
+            "Tasks sent to the loop and not yet run.",
+            lp.task_queue_depth,
+        );
+        counter(
+            &mut out,
+            "petty_accepted_total",
+            "Accepted connections.",
+            lp.accepted,
+        );
+        counter(
+            &mut out,
+            "petty_rejected_total",
+            "Rejected connections.",
+            lp.rejected,
+        );
+        counter(
+            &mut out,
+            "petty_closed_total",
+            "Closed connections.",
+            lp.closed,
+        );
+        counter(
+            &mut out,
+            "petty_bytes_read_total",
+            "Bytes read.",
+            self.bytes_read(),
+        );
+        counter(
+            &mut out,
+            "petty_bytes_written_total",
+            "Bytes written.",
+            self.bytes_written(),
+        );
+
+        header(&mut out, "petty_errors_total", "Errors by kind.", "counter");
+        for (kind, n) in self.errors() {
+            let _ = writeln!(out, "petty_errors_total{{kind=\"{}\"}} {}", kind.name(), n);
+        }
+
+        header(
+            &mut out,
+            "petty_channel_bytes_read_total",
+            "Bytes read per channel.",
+            "counter",
+        );
+        for (ch, m) in &self.channels {
+            let _ = writeln!(
+                out,
+                "petty_channel_bytes_read_total{{channel=\"{}\"}} {}",
+                label(ch),
+                m.bytes_read
+            );
+        }
+        header(
+            &mut out,
+            "petty_channel_bytes_written_total",
+            "Bytes written per channel.",
+            "counter",
+        );
+        for (ch, m) in &self.channels {
+            let _ = writeln!(
+                out,
+                "petty_channel_bytes_written_total{{channel=\"{}\"}} {}",
+                label(ch),
+                m.bytes_written
+            );
+        }
+        out
+    }
+}
+
+// Prometheus rendering helpers
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, hist: &Histogram) {
+    header(out, name, help, "histogram");
+    for (bound, n) in hist.cumulative() {
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, n);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, hist.count());
+    let _ = writeln!(out, "{}_sum {}", name, hist.sum());
+    let _ = writeln!(out, "{}_count {}", name, hist.count());
+}
+
+fn label<R: Display>(resource: &R) -> String {
+    resource
+        .to_string()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use codec::BytesCodec;
+    use ops::Ops;
+    use selector::Selector;
+    use transport::embedded::EmbeddedChannel;
+    use transport::mock::MockSelector;
+
+    #[test]
+    fn totals_keep_counting_deregistered_channels() {
+        let mut sys = MockSelector::new();
+        let mut ch = EmbeddedChannel::new(BytesCodec);
+        ch.write_inbound(&b"hello"[..]);
+        let id = ch.id();
+        sys.register(ch, Ops::READ);
+        assert_eq!(sys.snapshot().bytes_read(), 5);
+
+        sys.deregister(&id);
+        let snapshot = sys.snapshot();
+        assert!(snapshot.channels.is_empty());
+        assert_eq!(snapshot.bytes_read(), 5);
+        assert!(snapshot
+            .to_prometheus()
+            .contains("petty_bytes_read_total 5\n"));
+    }
+
+    #[test]
+    fn channels_are_labelled_with_their_short_id() {
+        let mut sys = MockSelector::new();
+        let mut ch = EmbeddedChannel::new(BytesCodec);
+        ch.write_inbound(&b"hi"[..]);
+        let id = ch.id();
+        sys.register(ch, Ops::READ);
+        let expected = format!(
+            "petty_channel_bytes_read_total{{channel=\"{}\"}} 2\n",
+            id.as_short_text()
+        );
+        assert!(sys.snapshot().to_prometheus().contains(&expected));
+    }
+}
@@ -1,71 +1,318 @@
+use std::fmt;
 use std::fmt::Binary;
+use std::fmt::Display;
 use std::fmt::Error;
 use std::fmt::Formatter;
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not, Sub, SubAssign};
+use udt::{self, EpollEvents};
 
-#[derive(Debug, Copy, PartialEq, Eq, Clone, PartialOrd, Ord)]
+#[derive(Copy, PartialEq, Eq, Clone, PartialOrd, Ord, Hash, Default)]
 pub struct Ops(usize);
 
+const NAMED: [(Ops, &str); 5] = [
+    (Ops::ACCEPT, "ACCEPT"),
+    (Ops::CONNECT, "CONNECT"),
+    (Ops::READ, "READ"),
+    (Ops::WRITE, "WRITE"),
+    (Ops::ERROR, "ERROR"),
+];
+
 impl Ops {
-    pub const ACCEPT: usize = 0b0000_0001;
-    pub const CONNECT: usize = 0b0000_0010;
-    pub const READ: usize = 0b0000_0100;
-    pub const WRITE: usize = 0b0000_1000;
-    pub const ERROR: usize = 0b0001_0000;
+    pub const ACCEPT: Ops = Ops(0b0000_0001);
+    pub const CONNECT: Ops = Ops(0b0000_0010);
+    pub const READ: Ops = Ops(0b0000_0100);
+    pub const WRITE: Ops = Ops(0b0000_1000);
+    pub const ERROR: Ops = Ops(0b0001_0000);
 
     pub fn empty() -> Self {
         Ops(0)
     }
 
-    pub fn apply(&mut self, state: usize) {
-        self.0 |= state;
+    pub fn all() -> Self {
+        Ops::ACCEPT | Ops::CONNECT | Ops::READ | Ops::WRITE | Ops::ERROR
+    }
+
+    pub fn bits(&self) -> usize {
+        self.0
+    }
+
+    /// Returns `None` if `bits` contains anything other than the known flags.
+    pub fn from_bits(bits: usize) -> Option<Self> {
+        if bits & !Ops::all().0 == 0 {
+            Some(Ops(bits))
+        } else {
+            None
+        }
+    }
+
+    pub fn from_bits_truncate(bits: usize) -> Self {
+        Ops(bits & Ops::all().0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
     }
 
-    pub fn remove(&mut self, state: usize) {
-        self.0 &= !state;
+    pub fn contains(&self, other: Ops) -> bool {
+        (self.0 & other.0) == other.0
+    }
+
+    pub fn intersects(&self, other: Ops) -> bool {
+        (self.0 & other.0) != 0
+    }
+
+    pub fn apply(&mut self, ops: Ops) {
+        self.0 |= ops.0;
+    }
+
+    pub fn remove(&mut self, ops: Ops) {
+        self.0 &= !ops.0;
+    }
+
+    /// Iterates over the individual flags set in `self`.
+    pub fn iter(&self) -> impl Iterator<Item = Ops> {
+        let ops = *self;
+        NAMED
+            .iter()
+            .map(|&(flag, _)| flag)
+            .filter(move |flag| ops.contains(*flag))
     }
 
     pub fn with_read() -> Self {
-        Ops(Ops::READ)
+        Ops::READ
     }
 
     pub fn with_write() -> Self {
-        Ops(Ops::WRITE)
+        Ops::WRITE
     }
 
     pub fn with_accept() -> Self {
-        Ops(Ops::ACCEPT)
+        Ops::ACCEPT
     }
 
     pub fn with_connect() -> Self {
-        Ops(Ops::CONNECT)
+        Ops::CONNECT
     }
 
     pub fn with_error() -> Self {
-        Ops(Ops::CONNECT)
+        Ops::ERROR
     }
 
     pub fn has_accept(&self) -> bool {
-        (self.0 & Ops::ACCEPT) == Ops::ACCEPT
+        self.contains(Ops::ACCEPT)
     }
 
     pub fn has_connect(&self) -> bool {
-        (self.0 & Ops::CONNECT) == Ops::CONNECT
+        self.contains(Ops::CONNECT)
     }
 
     pub fn has_read(&self) -> bool {
-        (self.0 & Ops::READ) == Ops::READ
+        self.contains(Ops::READ)
     }
 
     pub fn has_write(&self) -> bool {
-        (self.0 & Ops::WRITE) == Ops::WRITE
+        self.contains(Ops::WRITE)
     }
+
     pub fn has_error(&self) -> bool {
-        (self.0 & Ops::ERROR) == Ops::ERROR
+        self.contains(Ops::ERROR)
+    }
+}
+
+impl BitOr for Ops {
+    type Output = Ops;
+
+    fn bitor(self, rhs: Ops) -> Ops {
+        Ops(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Ops {
+    fn bitor_assign(&mut self, rhs: Ops) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for Ops {
+    type Output = Ops;
+
+    fn bitand(self, rhs: Ops) -> Ops {
+        Ops(self.0 & rhs.0)
+    }
+}
+
+impl BitAndAssign for Ops {
+    fn bitand_assign(&mut self, rhs: Ops) {
+        self.0 &= rhs.0;
+    }
+}
+
+impl Sub for Ops {
+    type Output = Ops;
+
+    fn sub(self, rhs: Ops) -> Ops {
+        Ops(self.0 & !rhs.0)
+    }
+}
+
+impl SubAssign for Ops {
+    fn sub_assign(&mut self, rhs: Ops) {
+        self.0 &= !rhs.0;
+    }
+}
+
+impl Not for Ops {
+    type Output = Ops;
+
+    fn not(self) -> Ops {
+        Ops::from_bits_truncate(!self.0)
+    }
+}
+
+impl Display for Ops {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        if self.is_empty() {
+            return f.write_str("(empty)");
+        }
+        let mut first = true;
+        for &(flag, name) in NAMED.iter() {
+            if self.contains(flag) {
+                if !first {
+                    f.write_str(" | ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Ops {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "Ops({})", self)
     }
 }
 
 impl Binary for Ops {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        self.0.fmt(f)
+        Binary::fmt(&self.0, f)
+    }
+}
+
+// ACCEPT and READ both map onto UDT_EPOLL_IN, CONNECT and WRITE onto UDT_EPOLL_OUT
+impl From<Ops> for EpollEvents {
+    fn from(ops: Ops) -> EpollEvents {
+        let mut events = EpollEvents::empty();
+        if ops.intersects(Ops::READ | Ops::ACCEPT) {
+            events |= udt::UDT_EPOLL_IN;
+        }
+        if ops.intersects(Ops::WRITE | Ops::CONNECT) {
+            events |= udt::UDT_EPOLL_OUT;
+        }
+        if ops.has_error() {
+            events |= udt::UDT_EPOLL_ERR;
+        }
+        events
+    }
+}
+
+// Lossy: UDT cannot tell an accept from a read, so IN and OUT map back to READ and WRITE
+impl From<EpollEvents> for Ops {
+    fn from(events: EpollEvents) -> Ops {
+        let mut ops = Ops::empty();
+        if events.contains(udt::UDT_EPOLL_IN) {
+            ops |= Ops::READ;
+        }
+        if events.contains(udt::UDT_EPOLL_OUT) {
+            ops |= Ops::WRITE;
+        }
+        if events.contains(udt::UDT_EPOLL_ERR) {
+            ops |= Ops::ERROR;
+        }
+        ops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operators_combine_flags() {
+        let rw = Ops::READ | Ops::WRITE;
+        assert!(rw.contains(Ops::READ) && rw.contains(Ops::WRITE));
+        assert!(!rw.contains(Ops::READ | Ops::ERROR));
+        assert!(rw.intersects(Ops::READ | Ops::ERROR));
+        assert_eq!(rw & Ops::WRITE, Ops::WRITE);
+        assert_eq!(rw & Ops::ACCEPT, Ops::empty());
+        assert_eq!(rw - Ops::READ, Ops::WRITE);
+        assert_eq!(rw - Ops::ERROR, rw);
+
+        let mut ops = Ops::empty();
+        ops |= Ops::CONNECT;
+        ops |= Ops::ERROR;
+        assert_eq!(ops, Ops::CONNECT | Ops::ERROR);
+        ops -= Ops::CONNECT;
+        assert_eq!(ops, Ops::ERROR);
+        ops &= Ops::READ;
+        assert!(ops.is_empty());
+
+        ops.apply(Ops::ACCEPT | Ops::READ);
+        ops.remove(Ops::ACCEPT);
+        assert_eq!(ops, Ops::READ);
+    }
+
+    #[test]
+    fn complements_stay_within_the_known_flags() {
+        assert_eq!(!Ops::all(), Ops::empty());
+        assert_eq!(!Ops::empty(), Ops::all());
+        assert_eq!(
+            !Ops::READ,
+            Ops::ACCEPT | Ops::CONNECT | Ops::WRITE | Ops::ERROR
+        );
+        assert_eq!(Ops::from_bits(Ops::all().bits()), Some(Ops::all()));
+        assert_eq!(Ops::from_bits(0b10_0000), None);
+        assert_eq!(Ops::from_bits_truncate(0b11_0100), Ops::READ | Ops::ERROR);
+    }
+
+    #[test]
+    fn flags_iterate_and_display_in_order() {
+        let ops = Ops::ERROR | Ops::READ | Ops::ACCEPT;
+        let flags: Vec<_> = ops.iter().collect();
+        assert_eq!(flags, vec![Ops::ACCEPT, Ops::READ, Ops::ERROR]);
+        assert_eq!(ops.to_string(), "ACCEPT | READ | ERROR");
+        assert_eq!(format!("{:?}", Ops::WRITE), "Ops(WRITE)");
+        assert_eq!(Ops::empty().to_string(), "(empty)");
+        assert_eq!(Ops::empty().iter().count(), 0);
+        assert_eq!(format!("{:b}", Ops::READ | Ops::WRITE), "1100");
+    }
+
+    #[test]
+    fn with_error_is_error() {
+        assert_eq!(Ops::with_error(), Ops::ERROR);
+        assert!(Ops::with_error().has_error());
+        assert!(!Ops::with_error().has_connect());
+        assert_eq!(Ops::with_connect(), Ops::CONNECT);
+        assert_eq!(Ops::with_accept(), Ops::ACCEPT);
+        assert_eq!(Ops::with_read(), Ops::READ);
+        assert_eq!(Ops::with_write(), Ops::WRITE);
+    }
+
+    #[test]
+    fn epoll_events_round_trip() {
+        let events = EpollEvents::from(Ops::READ | Ops::WRITE | Ops::ERROR);
+        assert_eq!(
+            events,
+            udt::UDT_EPOLL_IN | udt::UDT_EPOLL_OUT | udt::UDT_EPOLL_ERR
+        );
+        assert_eq!(Ops::from(events), Ops::READ | Ops::WRITE | Ops::ERROR);
+        assert_eq!(Ops::from(EpollEvents::from(Ops::empty())), Ops::empty());
+
+        // UDT has no separate accept and connect events
+        assert_eq!(EpollEvents::from(Ops::ACCEPT), udt::UDT_EPOLL_IN);
+        assert_eq!(EpollEvents::from(Ops::CONNECT), udt::UDT_EPOLL_OUT);
+        assert_eq!(Ops::from(EpollEvents::from(Ops::ACCEPT)), Ops::READ);
+        assert_eq!(Ops::from(EpollEvents::from(Ops::CONNECT)), Ops::WRITE);
     }
 }
@@ -0,0 +1,122 @@
+use channel::ConnectError;
+use entropy;
+use std::cmp;
+use std::time::Duration;
+
+/// How a connector re-dials its remote after a disconnect or a failed connect.
+///
+/// The delay before attempt `n` is `initial * multiplier^(n - 1)`, capped at `max`, with up to
+/// `jitter_percent` of it taken off at random so that many clients don't re-dial in lockstep.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+    pub jitter_percent: u8,
+    // Retries forever when unset
+    pub max_attempts: Option<u32>,
+}
+
+/// A key whose connection the event loop re-dials after it's lost or fails to open.
+pub trait Reconnect {
+    /// How the loop re-dials, or `None` to leave the connection closed.
+    fn backoff(&self) -> Option<Backoff>;
+
+    /// Replaces the transport with a fresh, unconnected one to the same remote.
+    ///
+    /// The key keeps its resource, so writes addressed to it keep working; those not yet sent
+    /// stay queued for the new connection.
+    fn redial(&mut self) -> Result<(), ConnectError>;
+}
+
+/// What a channel does with writes it can't send straight away, either because it has no
+/// connection or because an earlier send is blocked on a slow peer.
+///
+/// Writes over the policy's limit are dropped and reported with `ErrorEvent::WriteRejected`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Rejects writes while not connected. Behind a blocked send, queues up to
+    /// `WritePolicy::BLOCKED_WRITES` encoded writes.
+    #[default]
+    Reject,
+    /// Queues up to `max_messages` encoded writes, sending them once connected or once the
+    /// blocked send completes.
+    Buffer { max_messages: usize },
+}
+
+// impl Backoff
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            multiplier: 2,
+            jitter_percent: 20,
+            max_attempts: None,
+        }
+    }
+
+    pub fn with_multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn with_jitter_percent(mut self, jitter_percent: u8) -> Self {
+        self.jitter_percent = cmp::min(jitter_percent, 100);
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Whether attempt `attempt`, counting from 1, may still be made.
+    pub fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max| attempt <= max)
+    }
+
+    /// The delay before attempt `attempt`, counting from 1, without jitter.
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let mut delay = self.initial;
+        for _ in 1..attempt {
+            delay = match delay.checked_mul(self.multiplier) {
+                Some(next) if next < self.max => next,
+                _ => return self.max,
+            };
+        }
+        cmp::min(delay, self.max)
+    }
+
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay(attempt);
+        let spread = delay * u32::from(self.jitter_percent) / 100;
+        let spread_ns = spread.as_nanos() as u64;
+        if spread_ns == 0 {
+            return delay;
+        }
+        delay - Duration::from_nanos(entropy::random_u64() % (spread_ns + 1))
+    }
+}
+
+// impl WritePolicy
+impl WritePolicy {
+    /// How many writes `Reject` lets wait behind a blocked send.
+    pub const BLOCKED_WRITES: usize = 1024;
+
+    /// How many encoded writes may wait to be sent, counting the one being sent, before further
+    /// writes are rejected.
+    pub fn max_queued(&self, connected: bool) -> usize {
+        match *self {
+            WritePolicy::Reject if connected => WritePolicy::BLOCKED_WRITES,
+            WritePolicy::Reject => 0,
+            WritePolicy::Buffer { max_messages } => max_messages,
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_millis(100), Duration::from_secs(30))
+    }
+}
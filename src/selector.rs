@@ -1,18 +1,42 @@
+use attribute::Attributes;
 use channel;
 use channel::RWEvent;
+use idle::{Activity, IdleTimeouts};
+use metrics::{ChannelMetrics, LoopMetrics, Metrics};
 use ops::Ops;
+use reconnect::Reconnect;
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::net::SocketAddr;
+use std::time::Instant;
 
 pub trait SelectorKey: Eq + Hash + Debug + Sized {
     type Io: channel::ChRead<Self> + channel::ChWrite<Self> + channel::ChExt<Self>;
     type Resource: Hash + Eq + Debug;
+    // Messages produced by the key's decoder and accepted by its encoder
+    type Inbound: Debug;
+    type Outbound;
 
     fn ready_ops(&self) -> Ops;
-    fn set_readiness(&mut self, Ops);
-    fn set_interest(&mut self, Ops);
+    fn set_readiness(&mut self, ops: Ops);
+    fn set_interest(&mut self, ops: Ops);
     fn io(&mut self) -> &mut Self::Io;
     fn resource(&self) -> Self::Resource;
+    fn metrics(&self) -> ChannelMetrics;
+    // None for keys that never carry data, such as acceptors
+    fn activity(&self) -> Option<Activity>;
+    fn idle_timeouts(&self) -> IdleTimeouts;
+    fn remote(&self) -> Option<SocketAddr>;
+    // When an in-progress connect should be abandoned, if it has a timeout
+    fn connect_deadline(&self) -> Option<Instant>;
+    // How the loop re-dials the key's remote after it disconnects or fails to connect; only
+    // connectors have one
+    fn connector(&mut self) -> Option<&mut dyn Reconnect> {
+        None
+    }
+
+    fn attributes(&self) -> &Attributes;
+    fn attributes_mut(&mut self) -> &mut Attributes;
 
     fn apply_read(&mut self) -> bool;
     fn apply_write(&mut self) -> bool;
@@ -22,12 +46,25 @@ pub trait Selector<K: SelectorKey> {
     const DEFAULT_TIMEOUT_MS: i64;
 
     fn register(&mut self, key: K, interest: Ops);
+    // Clears the key's attributes and keeps its metrics in the loop's before handing it back
+    fn deregister(&mut self, key: &K::Resource) -> Option<K>;
+    fn key(&self, resource: &K::Resource) -> Option<&K>;
+    fn key_mut(&mut self, resource: &K::Resource) -> Option<&mut K>;
     fn update_registration(&mut self, key: K::Resource, interest: Ops);
+    fn set_auto_read(&mut self, key: &K::Resource, auto_read: bool);
+    fn set_reads_paused(&mut self, paused: bool);
     fn select(&mut self, timeout: i64);
+    fn metrics(&mut self) -> &mut LoopMetrics;
+    fn snapshot(&self) -> Metrics<K::Resource>;
     fn on_resource<F>(&mut self, resource: &K::Resource, coll: &mut Vec<RWEvent<K>>, f: F)
     where
-        F: Fn(&mut Vec<RWEvent<K>>, &mut K) -> ();
+        F: Fn(&mut Vec<RWEvent<K>>, &mut K);
     fn on_selected<F>(&mut self, coll: &mut Vec<RWEvent<K>>, f: F)
     where
-        F: Fn(&mut Vec<RWEvent<K>>, &mut K) -> ();
+        F: Fn(&mut Vec<RWEvent<K>>, &mut K);
+    fn on_registered<F>(&self, f: F)
+    where
+        F: FnMut(&K);
+    // Resources registered since the last call, whose timeouts the loop has yet to arm
+    fn take_registered(&mut self) -> Vec<K::Resource>;
 }
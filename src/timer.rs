@@ -0,0 +1,258 @@
+use ev_loop::Work;
+use selector::{Selector, SelectorKey};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// Schedules tasks to run on the event loop after a delay.
+pub struct Scheduler<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    tx: mpsc::Sender<Timer<S, K>>,
+}
+
+/// A task waiting for its deadline.
+pub struct Timer<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    deadline: Instant,
+    // Keeps timers with equal deadlines in scheduling order
+    seq: u64,
+    work: Work<'static, S, K>,
+}
+
+// Loop-side state: timers received from schedulers, ordered by deadline
+pub(crate) struct Timers<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    rx: mpsc::Receiver<Timer<S, K>>,
+    tx: mpsc::Sender<Timer<S, K>>,
+    pending: BinaryHeap<Timer<S, K>>,
+    seq: u64,
+}
+
+// Per-key deadlines kept by the loop, ordered so only the due ones are looked at
+pub(crate) struct Deadlines<R> {
+    pending: BinaryHeap<KeyDeadline<R>>,
+    seq: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum DeadlineKind {
+    Connect,
+    Idle,
+}
+
+struct KeyDeadline<R> {
+    at: Instant,
+    seq: u64,
+    kind: DeadlineKind,
+    resource: R,
+}
+
+// impl Scheduler
+impl<S, K> Scheduler<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    /// Runs `work` on the loop once `delay` has passed; fails if the loop has gone away.
+    pub fn schedule(
+        &self,
+        delay: Duration,
+        work: Work<'static, S, K>,
+    ) -> Result<(), mpsc::SendError<Work<'static, S, K>>> {
+        let timer = Timer {
+            deadline: Instant::now() + delay,
+            seq: 0,
+            work,
+        };
+        self.tx
+            .send(timer)
+            .map_err(|mpsc::SendError(timer)| mpsc::SendError(timer.work))
+    }
+}
+
+impl<S, K> Clone for Scheduler<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    fn clone(&self) -> Self {
+        Scheduler {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+// impl Timer
+impl<S, K> Timer<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+// BinaryHeap is a max-heap, so the earliest deadline compares greatest
+impl<S, K> Ord for Timer<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .deadline
+            .cmp(&self.deadline)
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl<S, K> PartialOrd for Timer<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<S, K> PartialEq for Timer<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline && self.seq == other.seq
+    }
+}
+
+impl<S, K> Eq for Timer<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+}
+
+// impl Timers
+impl<S, K> Timers<S, K>
+where
+    S: Selector<K>,
+    K: SelectorKey,
+{
+    pub(crate) fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        Timers {
+            rx,
+            tx,
+            pending: BinaryHeap::new(),
+            seq: 0,
+        }
+    }
+
+    pub(crate) fn scheduler(&self) -> Scheduler<S, K> {
+        Scheduler {
+            tx: self.tx.clone(),
+        }
+    }
+
+    pub(crate) fn next_deadline(&mut self) -> Option<Instant> {
+        self.receive();
+        self.pending.peek().map(|timer| timer.deadline)
+    }
+
+    /// Removes and returns the work of every timer due by `now`, earliest first.
+    pub(crate) fn expired(&mut self, now: Instant) -> Vec<Work<'static, S, K>> {
+        self.receive();
+        let mut due = Vec::new();
+        while self
+            .pending
+            .peek()
+            .is_some_and(|timer| timer.deadline <= now)
+        {
+            if let Some(timer) = self.pending.pop() {
+                due.push(timer.work);
+            }
+        }
+        due
+    }
+
+    fn receive(&mut self) {
+        while let Ok(mut timer) = self.rx.try_recv() {
+            timer.seq = self.seq;
+            self.seq += 1;
+            self.pending.push(timer);
+        }
+    }
+}
+
+// impl Deadlines
+impl<R> Deadlines<R> {
+    pub(crate) fn new() -> Self {
+        Deadlines {
+            pending: BinaryHeap::new(),
+            seq: 0,
+        }
+    }
+
+    pub(crate) fn arm(&mut self, at: Instant, resource: R, kind: DeadlineKind) {
+        self.pending.push(KeyDeadline {
+            at,
+            seq: self.seq,
+            kind,
+            resource,
+        });
+        self.seq += 1;
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.pending.peek().map(|deadline| deadline.at)
+    }
+
+    /// Removes and returns every deadline due by `now`, earliest first. Deadlines are never
+    /// disarmed, so the caller checks each against the key's current state.
+    pub(crate) fn expired(&mut self, now: Instant) -> Vec<(Instant, R, DeadlineKind)> {
+        let mut due = Vec::new();
+        while self
+            .pending
+            .peek()
+            .is_some_and(|deadline| deadline.at <= now)
+        {
+            if let Some(deadline) = self.pending.pop() {
+                due.push((deadline.at, deadline.resource, deadline.kind));
+            }
+        }
+        due
+    }
+}
+
+// Earliest deadline compares greatest, as for timers
+impl<R> Ord for KeyDeadline<R> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.at.cmp(&self.at).then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl<R> PartialOrd for KeyDeadline<R> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<R> PartialEq for KeyDeadline<R> {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at && self.seq == other.seq
+    }
+}
+
+impl<R> Eq for KeyDeadline<R> {}
@@ -0,0 +1,552 @@
+use attribute::Attributes;
+use bytes::Bytes;
+use channel;
+use channel::{
+    ChannelId, ConnectError, ErrorEvent, RWEvent, ReadEvent, RegistrationEvent, StateEvent,
+};
+use codec::{
+    ByteToMessageDecoder, BytesCodec, Codec, CodecError, Decoder, Encoder, MessageToByteEncoder,
+};
+use idle::{Activity, IdleTimeouts};
+use metrics::ChannelMetrics;
+use ops::Ops;
+use reconnect::{Backoff, Reconnect};
+use selector::SelectorKey;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+/// Work run against an `EmbeddedChannel` by `run_pending_tasks` or `advance_time`.
+pub type EmbeddedTask<C> = Box<dyn FnOnce(&mut EmbeddedChannel<C>)>;
+
+/// A channel without a socket, for testing codecs and protocol logic.
+///
+/// Bytes pushed with `write_inbound` are decoded as if read from the wire, messages written
+/// through `ChWrite` are encoded and kept for `read_outbound`, and every `RWEvent` the channel
+/// produces on its own is kept for `take_events`. Tasks run on a virtual clock that only moves
+/// with `advance_time`, which also runs the tasks scheduled up to the new instant.
+///
+/// The channel is its own key and its own `Io`, so it can also be registered with a selector.
+/// The event loop runs on the real clock, so the connect deadline and activity it checks are
+/// taken from the real clock too: `advance_time` never brings a loop's connect or idle timeouts
+/// forward.
+pub struct EmbeddedChannel<C: Codec = BytesCodec> {
+    id: ChannelId,
+    remote: SocketAddr,
+    state: EmbeddedState,
+    readiness: Ops,
+    interest: Ops,
+    connect_timeout: Option<Duration>,
+    connect_deadline: Option<Instant>,
+    idle: IdleTimeouts,
+    reconnect: Option<Backoff>,
+    // Times the channel was re-dialed
+    redials: u32,
+    inbound: VecDeque<Bytes>,
+    decoder: ByteToMessageDecoder<C>,
+    encoder: MessageToByteEncoder<C>,
+    outbound: VecDeque<Bytes>,
+    // Encoded writes held back while the channel isn't writable
+    pending: VecDeque<Bytes>,
+    writable: bool,
+    events: VecDeque<RWEvent<EmbeddedChannel<C>>>,
+    tasks: VecDeque<EmbeddedTask<C>>,
+    // Ordered by insertion; run in deadline order
+    scheduled: Vec<(Instant, EmbeddedTask<C>)>,
+    now: Instant,
+    metrics: ChannelMetrics,
+    activity: Activity,
+    attributes: Attributes,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum EmbeddedState {
+    Idle,
+    Connecting,
+    // Connecting, and the next `finish_connect` fails
+    Refusing,
+    Connected,
+    // The peer went away; the next read reports it
+    Disconnecting,
+    Closed,
+}
+
+// impl EmbeddedChannel
+impl<C: Codec> EmbeddedChannel<C> {
+    /// A connected channel to an unspecified remote.
+    pub fn new(codec: C) -> Self {
+        let remote = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0));
+        let mut ch = EmbeddedChannel::connector(codec, remote);
+        ch.state = EmbeddedState::Connected;
+        ch.interest = Ops::READ | Ops::ERROR;
+        ch
+    }
+
+    /// An unconnected channel that connects to `remote` through `ChExt::connect`.
+    pub fn connector(codec: C, remote: SocketAddr) -> Self {
+        let now = Instant::now();
+        EmbeddedChannel {
+            id: ChannelId::generate(),
+            remote,
+            state: EmbeddedState::Idle,
+            readiness: Ops::empty(),
+            interest: Ops::CONNECT,
+            connect_timeout: None,
+            connect_deadline: None,
+            idle: IdleTimeouts::default(),
+            reconnect: None,
+            redials: 0,
+            inbound: VecDeque::new(),
+            decoder: ByteToMessageDecoder::new(codec.clone()),
+            encoder: MessageToByteEncoder::new(codec),
+            outbound: VecDeque::new(),
+            pending: VecDeque::new(),
+            writable: true,
+            events: VecDeque::new(),
+            tasks: VecDeque::new(),
+            scheduled: Vec::new(),
+            now,
+            metrics: ChannelMetrics::default(),
+            activity: Activity::new(now),
+            attributes: Attributes::new(),
+        }
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_idle_timeouts(mut self, timeouts: IdleTimeouts) -> Self {
+        self.idle = timeouts;
+        self
+    }
+
+    /// Has the loop re-dial the channel with `backoff` once it's lost or fails to connect.
+    pub fn with_reconnect(mut self, backoff: Backoff) -> Self {
+        self.reconnect = Some(backoff);
+        self
+    }
+
+    pub fn id(&self) -> ChannelId {
+        self.id
+    }
+
+    pub fn redials(&self) -> u32 {
+        self.redials
+    }
+
+    pub fn state(&self) -> EmbeddedState {
+        self.state
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state == EmbeddedState::Connected
+    }
+
+    pub fn interest(&self) -> Ops {
+        self.interest
+    }
+
+    /// Queues `data` as received from the peer and reads it.
+    pub fn write_inbound<B: Into<Bytes>>(&mut self, data: B) {
+        self.push_inbound(data);
+        self.collect(channel::ChRead::read);
+    }
+
+    /// Queues `data` as received from the peer, to be decoded by the next read; for channels
+    /// read by a selector.
+    pub fn push_inbound<B: Into<Bytes>>(&mut self, data: B) {
+        self.inbound.push_back(data.into());
+    }
+
+    /// Encodes and writes `msg` as the channel's user would.
+    pub fn write_outbound(&mut self, msg: <C as Encoder>::Item) {
+        self.collect(|ch, ev| channel::ChWrite::write(ch, msg, ev));
+    }
+
+    /// Holds writes back while `writable` is false, as a socket with a full send buffer would.
+    ///
+    /// Held writes ask for `WRITE` interest and go out with the first flush once the channel is
+    /// writable again, as when a selector reports it ready to write.
+    pub fn set_writable(&mut self, writable: bool) {
+        self.writable = writable;
+    }
+
+    /// Takes the next message decoded from inbound bytes, skipping over other events.
+    pub fn read_inbound(&mut self) -> Option<<C as Decoder>::Item> {
+        let pos = self
+            .events
+            .iter()
+            .position(|ev| matches!(*ev, RWEvent::Read(ReadEvent::Data(..))))?;
+        match self.events.remove(pos) {
+            Some(RWEvent::Read(ReadEvent::Data(_, msg))) => Some(msg),
+            _ => None,
+        }
+    }
+
+    /// Takes the next decoding or encoding error, skipping over other events.
+    pub fn read_codec_error(&mut self) -> Option<CodecError> {
+        let pos = self
+            .events
+            .iter()
+            .position(|ev| matches!(*ev, RWEvent::Error(ErrorEvent::Codec(..))))?;
+        match self.events.remove(pos) {
+            Some(RWEvent::Error(ErrorEvent::Codec(_, why))) => Some(why),
+            _ => None,
+        }
+    }
+
+    /// Takes the next encoded write.
+    pub fn read_outbound(&mut self) -> Option<Bytes> {
+        self.outbound.pop_front()
+    }
+
+    /// Every event produced outside a selector so far, oldest first.
+    pub fn take_events(&mut self) -> Vec<RWEvent<EmbeddedChannel<C>>> {
+        self.events.drain(..).collect()
+    }
+
+    /// Makes the next `finish_connect` fail as if the peer refused the connection.
+    pub fn refuse_connect(&mut self) {
+        if self.state == EmbeddedState::Connecting {
+            self.state = EmbeddedState::Refusing;
+        }
+    }
+
+    /// Makes the next read report that the peer went away.
+    pub fn disconnect(&mut self) {
+        if self.state == EmbeddedState::Connected {
+            self.state = EmbeddedState::Disconnecting;
+        }
+    }
+
+    /// Queues `task` to run on the next `run_pending_tasks`.
+    pub fn execute<F>(&mut self, task: F)
+    where
+        F: FnOnce(&mut EmbeddedChannel<C>) + 'static,
+    {
+        self.tasks.push_back(Box::new(task));
+    }
+
+    /// Queues `task` to run once the virtual clock has moved on by `delay`.
+    pub fn schedule<F>(&mut self, delay: Duration, task: F)
+    where
+        F: FnOnce(&mut EmbeddedChannel<C>) + 'static,
+    {
+        let deadline = self.now + delay;
+        self.scheduled.push((deadline, Box::new(task)));
+    }
+
+    /// Runs queued tasks and any scheduled tasks that are due, including those they queue in
+    /// turn; returns how many ran.
+    pub fn run_pending_tasks(&mut self) -> usize {
+        let mut run = 0;
+        loop {
+            let task = match self.tasks.pop_front() {
+                Some(task) => task,
+                None => match self.next_due() {
+                    Some(task) => task,
+                    None => return run,
+                },
+            };
+            task(self);
+            run += 1;
+        }
+    }
+
+    /// Moves the virtual clock on by `by` and runs the tasks that became due.
+    ///
+    /// Timeouts checked by an event loop the channel is registered with are unaffected.
+    pub fn advance_time(&mut self, by: Duration) -> usize {
+        self.now += by;
+        self.run_pending_tasks()
+    }
+
+    /// The virtual clock the channel's scheduled tasks run on.
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// When the earliest scheduled task is due, if any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.scheduled.iter().map(|(at, _)| *at).min()
+    }
+
+    fn next_due(&mut self) -> Option<EmbeddedTask<C>> {
+        let now = self.now;
+        let pos = self
+            .scheduled
+            .iter()
+            .enumerate()
+            .filter(|(_, (at, _))| *at <= now)
+            .min_by_key(|(_, (at, _))| *at)
+            .map(|(pos, _)| pos)?;
+        Some(self.scheduled.remove(pos).1)
+    }
+
+    fn send(&mut self, data: Bytes) {
+        self.metrics.bytes_written += data.len() as u64;
+        self.activity.last_write = Instant::now();
+        self.outbound.push_back(data);
+    }
+
+    fn collect<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Self, &mut Vec<RWEvent<EmbeddedChannel<C>>>),
+    {
+        let mut events = Vec::new();
+        f(self, &mut events);
+        self.events.extend(events);
+    }
+}
+
+impl<C: Codec> fmt::Debug for EmbeddedChannel<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EmbeddedChannel")
+            .field("id", &self.id)
+            .field("remote", &self.remote)
+            .field("state", &self.state)
+            .field("interest", &self.interest)
+            .field("inbound", &self.inbound.len())
+            .field("outbound", &self.outbound.len())
+            .field("pending", &self.pending.len())
+            .field("events", &self.events.len())
+            .field("tasks", &(self.tasks.len() + self.scheduled.len()))
+            .field("attributes", &self.attributes)
+            .finish()
+    }
+}
+
+impl<C: Codec> Hash for EmbeddedChannel<C> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl<C: Codec> PartialEq for EmbeddedChannel<C> {
+    fn eq(&self, other: &EmbeddedChannel<C>) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<C: Codec> Eq for EmbeddedChannel<C> {}
+
+impl<C: Codec> SelectorKey for EmbeddedChannel<C> {
+    type Io = EmbeddedChannel<C>;
+    type Resource = ChannelId;
+    type Inbound = <C as Decoder>::Item;
+    type Outbound = <C as Encoder>::Item;
+
+    fn ready_ops(&self) -> Ops {
+        self.readiness
+    }
+
+    fn set_readiness(&mut self, ops: Ops) {
+        self.readiness = ops;
+    }
+
+    fn set_interest(&mut self, ops: Ops) {
+        self.interest = ops;
+    }
+
+    fn io(&mut self) -> &mut Self::Io {
+        self
+    }
+
+    fn resource(&self) -> ChannelId {
+        self.id
+    }
+
+    fn metrics(&self) -> ChannelMetrics {
+        self.metrics
+    }
+
+    fn activity(&self) -> Option<Activity> {
+        Some(self.activity)
+    }
+
+    fn idle_timeouts(&self) -> IdleTimeouts {
+        self.idle
+    }
+
+    fn remote(&self) -> Option<SocketAddr> {
+        Some(self.remote)
+    }
+
+    fn connect_deadline(&self) -> Option<Instant> {
+        self.connect_deadline
+    }
+
+    fn connector(&mut self) -> Option<&mut dyn Reconnect> {
+        Some(self)
+    }
+
+    fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    fn attributes_mut(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+
+    fn apply_read(&mut self) -> bool {
+        if self.interest.has_read() {
+            self.readiness.apply(Ops::READ);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn apply_write(&mut self) -> bool {
+        let ops = if self.is_connected() {
+            Ops::WRITE
+        } else {
+            Ops::CONNECT
+        };
+        if self.interest.contains(ops) {
+            self.readiness.apply(ops);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl<C: Codec> channel::ChExt<EmbeddedChannel<C>> for EmbeddedChannel<C> {
+    fn connect(&mut self, collector: &mut Vec<RWEvent<EmbeddedChannel<C>>>) {
+        if self.state != EmbeddedState::Idle {
+            let why = ConnectError::Failed(format!("cannot connect in state {:?}", self.state));
+            collector.push(RWEvent::State(StateEvent::ConnectFailed(
+                self.id,
+                self.remote,
+                why,
+            )));
+            return;
+        }
+        self.state = EmbeddedState::Connecting;
+        self.connect_deadline = self.connect_timeout.map(|t| Instant::now() + t);
+        let interest = Ops::CONNECT | Ops::ERROR;
+        collector.push(RWEvent::Registration(RegistrationEvent::Update(
+            self.id, interest,
+        )));
+    }
+
+    fn finish_connect(&mut self, collector: &mut Vec<RWEvent<EmbeddedChannel<C>>>) {
+        self.connect_deadline = None;
+        match self.state {
+            EmbeddedState::Connecting => {
+                self.state = EmbeddedState::Connected;
+                let interest = Ops::READ | Ops::ERROR;
+                collector.push(RWEvent::Registration(RegistrationEvent::Update(
+                    self.id, interest,
+                )));
+                let ev = StateEvent::ConnectedPeer(self.id, self.remote);
+                collector.push(RWEvent::State(ev));
+            }
+            EmbeddedState::Refusing => {
+                self.state = EmbeddedState::Idle;
+                let ev = StateEvent::ConnectFailed(self.id, self.remote, ConnectError::Broken);
+                collector.push(RWEvent::State(ev));
+            }
+            _ => {}
+        }
+    }
+
+    fn close(&mut self) {
+        self.state = EmbeddedState::Closed;
+        self.connect_deadline = None;
+        self.inbound.clear();
+        self.pending.clear();
+    }
+}
+
+impl<C: Codec> Reconnect for EmbeddedChannel<C> {
+    fn backoff(&self) -> Option<Backoff> {
+        self.reconnect
+    }
+
+    fn redial(&mut self) -> Result<(), ConnectError> {
+        self.state = EmbeddedState::Idle;
+        self.inbound.clear();
+        // Nothing half-read carries over to the new connection
+        self.decoder = ByteToMessageDecoder::new(self.encoder.encoder().clone());
+        self.redials += 1;
+        Ok(())
+    }
+}
+
+impl<C: Codec> channel::ChRead<EmbeddedChannel<C>> for EmbeddedChannel<C> {
+    fn read(&mut self, collector: &mut Vec<RWEvent<EmbeddedChannel<C>>>) {
+        match self.state {
+            EmbeddedState::Connected => {}
+            EmbeddedState::Disconnecting => {
+                self.state = EmbeddedState::Idle;
+                let ev = StateEvent::Disconnected(self.id, self.remote);
+                collector.push(RWEvent::State(ev));
+                return;
+            }
+            _ => return,
+        }
+        let id = self.id;
+        let inbound = mem::take(&mut self.inbound);
+        for data in inbound {
+            self.metrics.bytes_read += data.len() as u64;
+            self.activity.last_read = Instant::now();
+            self.decoder.decode(&data, |msg| match msg {
+                Ok(msg) => collector.push(RWEvent::Read(ReadEvent::Data(id, msg))),
+                Err(why) => collector.push(RWEvent::Error(ErrorEvent::Codec(id, why))),
+            });
+        }
+    }
+}
+
+impl<C: Codec> channel::ChWrite<EmbeddedChannel<C>> for EmbeddedChannel<C> {
+    fn write(&mut self, msg: <C as Encoder>::Item, collector: &mut Vec<RWEvent<Self>>) {
+        if !self.is_connected() {
+            collector.push(RWEvent::Error(ErrorEvent::WriteRejected(self.id)));
+            return;
+        }
+        let data = match self.encoder.encode(msg) {
+            Ok(data) => data,
+            Err(why) => {
+                collector.push(RWEvent::Error(ErrorEvent::Codec(self.id, why)));
+                return;
+            }
+        };
+        if self.writable && self.pending.is_empty() {
+            self.send(data);
+            return;
+        }
+        if self.pending.is_empty() {
+            let interest = self.interest | Ops::WRITE;
+            collector.push(RWEvent::Registration(RegistrationEvent::Update(
+                self.id, interest,
+            )));
+        }
+        self.pending.push_back(data);
+    }
+
+    fn flush(&mut self, collector: &mut Vec<RWEvent<Self>>) {
+        if !self.writable || self.pending.is_empty() {
+            return;
+        }
+        while let Some(data) = self.pending.pop_front() {
+            self.send(data);
+        }
+        let mut interest = self.interest;
+        interest.remove(Ops::WRITE);
+        collector.push(RWEvent::Registration(RegistrationEvent::Update(
+            self.id, interest,
+        )));
+        collector.push(RWEvent::Registration(RegistrationEvent::Flushed(self.id)));
+    }
+
+    fn pending_writes(&self) -> usize {
+        self.pending.len()
+    }
+}
@@ -0,0 +1,260 @@
+use channel::RWEvent;
+use metrics::{LoopMetrics, Metrics};
+use ops::Ops;
+use selector::{Selector, SelectorKey};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::mem;
+use std::time::Duration;
+
+/// A selector whose readiness is scripted by the test driving it.
+///
+/// Each `select` takes the next batch queued with `push_ready`; keys in it become selected with
+/// the scripted ops, limited to the interest they are registered with, just as a real poller
+/// would report them. Calls that change registrations are recorded for `calls`.
+pub struct MockSelector<K: SelectorKey> {
+    pub registered: HashMap<K::Resource, K>,
+    interest: HashMap<K::Resource, Ops>,
+    auto_read: HashMap<K::Resource, bool>,
+    script: VecDeque<Vec<(K::Resource, Ops)>>,
+    selected: Vec<K::Resource>,
+    calls: Vec<SelectorCall<K>>,
+    metrics: LoopMetrics,
+    reads_paused: bool,
+    new_keys: Vec<K::Resource>,
+}
+
+/// A call made to a `MockSelector`.
+pub enum SelectorCall<K: SelectorKey> {
+    Register(K::Resource, Ops),
+    Deregister(K::Resource),
+    UpdateRegistration(K::Resource, Ops),
+    SetAutoRead(K::Resource, bool),
+    SetReadsPaused(bool),
+    // The timeout passed, in milliseconds
+    Select(i64),
+}
+
+// impl MockSelector
+impl<K: SelectorKey> MockSelector<K> {
+    pub fn new() -> Self {
+        MockSelector {
+            registered: HashMap::new(),
+            interest: HashMap::new(),
+            auto_read: HashMap::new(),
+            script: VecDeque::new(),
+            selected: Vec::new(),
+            calls: Vec::new(),
+            metrics: LoopMetrics::new(),
+            reads_paused: false,
+            new_keys: Vec::new(),
+        }
+    }
+
+    /// Queues the keys the next unscripted `select` reports as ready, and with which ops.
+    pub fn push_ready(&mut self, ready: Vec<(K::Resource, Ops)>) {
+        self.script.push_back(ready);
+    }
+
+    /// Number of `select` calls scripted but not yet made.
+    pub fn scripted(&self) -> usize {
+        self.script.len()
+    }
+
+    pub fn interest(&self, resource: &K::Resource) -> Option<Ops> {
+        self.interest.get(resource).cloned()
+    }
+
+    pub fn calls(&self) -> &[SelectorCall<K>] {
+        &self.calls
+    }
+
+    pub fn take_calls(&mut self) -> Vec<SelectorCall<K>> {
+        self.calls.drain(..).collect()
+    }
+
+    // What a real poller would watch for, given the current backpressure and auto-read settings
+    fn poll_interest(&self, resource: &K::Resource) -> Ops {
+        let mut ops = self.interest.get(resource).cloned().unwrap_or_default();
+        if self.reads_paused || self.auto_read.get(resource) == Some(&false) {
+            ops.remove(Ops::READ);
+        }
+        ops
+    }
+}
+
+impl<K: SelectorKey> Default for MockSelector<K> {
+    fn default() -> Self {
+        MockSelector::new()
+    }
+}
+
+impl<K: SelectorKey> fmt::Debug for MockSelector<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MockSelector")
+            .field("registered", &self.registered.keys().collect::<Vec<_>>())
+            .field("scripted", &self.script.len())
+            .field("calls", &self.calls)
+            .finish()
+    }
+}
+
+impl<K: SelectorKey> Selector<K> for MockSelector<K> {
+    const DEFAULT_TIMEOUT_MS: i64 = 1000;
+
+    fn register(&mut self, mut key: K, interest: Ops) {
+        key.set_interest(interest);
+        self.calls
+            .push(SelectorCall::Register(key.resource(), interest));
+        self.interest.insert(key.resource(), interest);
+        self.auto_read.insert(key.resource(), true);
+        self.new_keys.push(key.resource());
+        self.registered.insert(key.resource(), key);
+    }
+
+    fn deregister(&mut self, resource: &K::Resource) -> Option<K> {
+        let mut key = self.registered.remove(resource)?;
+        key.attributes_mut().clear();
+        self.metrics.record_deregistered(key.metrics());
+        self.interest.remove(resource);
+        self.auto_read.remove(resource);
+        self.selected.retain(|selected| selected != resource);
+        self.calls.push(SelectorCall::Deregister(key.resource()));
+        Some(key)
+    }
+
+    fn key(&self, resource: &K::Resource) -> Option<&K> {
+        self.registered.get(resource)
+    }
+
+    fn key_mut(&mut self, resource: &K::Resource) -> Option<&mut K> {
+        self.registered.get_mut(resource)
+    }
+
+    fn update_registration(&mut self, resource: K::Resource, interest: Ops) {
+        if let Some(key) = self.registered.get_mut(&resource) {
+            key.set_interest(interest);
+            self.interest.insert(key.resource(), interest);
+        }
+        self.calls
+            .push(SelectorCall::UpdateRegistration(resource, interest));
+    }
+
+    fn set_auto_read(&mut self, resource: &K::Resource, auto_read: bool) {
+        if let Some(key) = self.registered.get(resource) {
+            self.auto_read.insert(key.resource(), auto_read);
+            self.calls
+                .push(SelectorCall::SetAutoRead(key.resource(), auto_read));
+        }
+    }
+
+    fn set_reads_paused(&mut self, paused: bool) {
+        if self.reads_paused != paused {
+            self.reads_paused = paused;
+            self.calls.push(SelectorCall::SetReadsPaused(paused));
+        }
+    }
+
+    fn select(&mut self, timeout: i64) {
+        self.calls.push(SelectorCall::Select(timeout));
+        let ready = self.script.pop_front().unwrap_or_default();
+        for (resource, ops) in ready {
+            let mut ops = ops;
+            ops.remove(!self.poll_interest(&resource));
+            let key = match self.registered.get_mut(&resource) {
+                Some(key) => key,
+                None => continue,
+            };
+            if ops.is_empty() {
+                continue;
+            }
+            let mut readiness = key.ready_ops();
+            readiness.apply(ops);
+            key.set_readiness(readiness);
+            if !self.selected.contains(&resource) {
+                self.selected.push(resource);
+            }
+        }
+        self.metrics
+            .record_select(Duration::from_millis(0), self.selected.len());
+    }
+
+    fn metrics(&mut self) -> &mut LoopMetrics {
+        &mut self.metrics
+    }
+
+    fn snapshot(&self) -> Metrics<K::Resource> {
+        Metrics {
+            event_loop: self.metrics.clone(),
+            channels: self
+                .registered
+                .values()
+                .map(|key| (key.resource(), key.metrics()))
+                .collect(),
+        }
+    }
+
+    fn on_resource<F>(&mut self, resource: &K::Resource, coll: &mut Vec<RWEvent<K>>, f: F)
+    where
+        F: Fn(&mut Vec<RWEvent<K>>, &mut K),
+    {
+        if let Some(key) = self.registered.get_mut(resource) {
+            f(coll, key);
+        }
+    }
+
+    fn on_selected<F>(&mut self, coll: &mut Vec<RWEvent<K>>, f: F)
+    where
+        F: Fn(&mut Vec<RWEvent<K>>, &mut K),
+    {
+        for resource in self.selected.drain(..) {
+            if let Some(key) = self.registered.get_mut(&resource) {
+                f(coll, key);
+                // Readiness is consumed by handling it, as with a real poller
+                key.set_readiness(Ops::empty());
+            }
+        }
+    }
+
+    fn on_registered<F>(&self, f: F)
+    where
+        F: FnMut(&K),
+    {
+        self.registered.values().for_each(f);
+    }
+
+    fn take_registered(&mut self) -> Vec<K::Resource> {
+        mem::take(&mut self.new_keys)
+    }
+}
+
+impl<K: SelectorKey> fmt::Debug for SelectorCall<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SelectorCall::Register(ref r, ops) => write!(f, "Register({:?}, {:?})", r, ops),
+            SelectorCall::Deregister(ref r) => write!(f, "Deregister({:?})", r),
+            SelectorCall::UpdateRegistration(ref r, ops) => {
+                write!(f, "UpdateRegistration({:?}, {:?})", r, ops)
+            }
+            SelectorCall::SetAutoRead(ref r, on) => write!(f, "SetAutoRead({:?}, {:?})", r, on),
+            SelectorCall::SetReadsPaused(paused) => write!(f, "SetReadsPaused({:?})", paused),
+            SelectorCall::Select(timeout) => write!(f, "Select({:?})", timeout),
+        }
+    }
+}
+
+impl<K: SelectorKey> PartialEq for SelectorCall<K> {
+    fn eq(&self, other: &SelectorCall<K>) -> bool {
+        match (self, other) {
+            (SelectorCall::Register(a, x), SelectorCall::Register(b, y)) => a == b && x == y,
+            (SelectorCall::Deregister(a), SelectorCall::Deregister(b)) => a == b,
+            (SelectorCall::UpdateRegistration(a, x), SelectorCall::UpdateRegistration(b, y)) => {
+                a == b && x == y
+            }
+            (SelectorCall::SetAutoRead(a, x), SelectorCall::SetAutoRead(b, y)) => a == b && x == y,
+            (SelectorCall::SetReadsPaused(a), SelectorCall::SetReadsPaused(b)) => a == b,
+            (SelectorCall::Select(a), SelectorCall::Select(b)) => a == b,
+            _ => false,
+        }
+    }
+}
@@ -1 +1,4 @@
+pub mod embedded;
+pub mod mock;
+pub mod tcp;
 pub mod udt;
@@ -0,0 +1,757 @@
+use accept::{AcceptGate, AcceptLimits, IpFilter, RejectReason, PERMIT};
+use attribute::Attributes;
+use bytes::{Bytes, BytesMut};
+use channel;
+use channel::{ChannelId, ErrorEvent, RWEvent, ReadEvent, RegistrationEvent, StateEvent};
+use codec::{ByteToMessageDecoder, BytesCodec, Codec, Decoder, Encoder, MessageToByteEncoder};
+use idle::{Activity, IdleTimeouts};
+use libc;
+use metrics::{ChannelMetrics, ErrorKind, LoopMetrics, Metrics};
+use ops::Ops;
+use reconnect::WritePolicy;
+use selector::{Selector, SelectorKey};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Instant;
+
+const DEFAULT_TCP_BUF_CAPACITY: usize = 16 * 1024;
+
+/// Per-channel configuration applied when a `TcpChannel` is created.
+///
+/// Accepted peers inherit their acceptor's.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct TcpOptions {
+    // Applies to acceptors only
+    pub accept_limits: AcceptLimits,
+    // Acceptors themselves are never idle
+    pub idle: IdleTimeouts,
+    // Limits the writes waiting behind a blocked send
+    pub write_policy: WritePolicy,
+}
+
+/// Selects TCP channels with `poll(2)`.
+///
+/// Meant for serving plain TCP clients, such as HTTP endpoints, from a `SelectorEventLoop`:
+/// channels are listeners and the peers they accept. Outgoing connects are left to the UDT
+/// transport.
+#[derive(Debug)]
+pub struct TcpSelector<C = BytesCodec> {
+    selected: HashSet<ChannelId>,
+    pub registered: HashMap<ChannelId, TcpKey<C>>,
+    // Rebuilt from the registered keys on every select; `polled[i]` owns `fds[i]`
+    polled: Vec<ChannelId>,
+    fds: Vec<libc::pollfd>,
+    metrics: LoopMetrics,
+    // Read interest is withheld from poll while the loop applies backpressure
+    reads_paused: bool,
+    // Registered since the loop last armed timeouts
+    new_keys: Vec<ChannelId>,
+}
+
+#[derive(Debug)]
+pub struct TcpKey<C = BytesCodec> {
+    pub ch: TcpChannel<C>,
+    pub readiness: Ops,
+    pub interest: Ops,
+    pub auto_read: bool,
+    pub attributes: Attributes,
+}
+
+#[derive(Debug)]
+pub struct TcpChannel<C = BytesCodec> {
+    id: ChannelId,
+    socket: Socket,
+    pub options: TcpOptions,
+    // Set for acceptors
+    gate: Option<AcceptGate>,
+    // Checked by acceptors before their limits
+    ip_filter: IpFilter,
+    // Encoded writes left over from a send that would have blocked
+    pending: VecDeque<Bytes>,
+    // How much of the front of `pending` has already been sent
+    written: usize,
+    // Set while WRITE interest is registered to drain `pending`
+    write_blocked: bool,
+    inbound: ByteToMessageDecoder<C>,
+    outbound: MessageToByteEncoder<C>,
+
+    // Debug info
+    metrics: ChannelMetrics,
+    activity: Activity,
+}
+
+#[derive(Debug)]
+enum Socket {
+    Listener(TcpListener),
+    Peer(TcpStream, SocketAddr),
+    Closed,
+}
+
+// impl TcpOptions
+impl TcpOptions {
+    pub fn with_accept_limits(mut self, limits: AcceptLimits) -> Self {
+        self.accept_limits = limits;
+        self
+    }
+
+    /// Emits `StateEvent::Idle` once an accepted peer stays inactive longer than `timeouts`.
+    pub fn with_idle_timeouts(mut self, timeouts: IdleTimeouts) -> Self {
+        self.idle = timeouts;
+        self
+    }
+
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> Self {
+        self.write_policy = policy;
+        self
+    }
+}
+
+// impl TcpSelector
+impl<C: Codec> TcpSelector<C> {
+    pub fn new() -> Self {
+        TcpSelector {
+            selected: HashSet::new(),
+            registered: HashMap::new(),
+            polled: Vec::new(),
+            fds: Vec::new(),
+            metrics: LoopMetrics::new(),
+            reads_paused: false,
+            new_keys: Vec::new(),
+        }
+    }
+
+    // The events handed to poll, which drop reads while they are suspended
+    fn poll_events(&self, key: &TcpKey<C>) -> libc::c_short {
+        let mut events = 0;
+        let reading = key.interest.has_read() || key.interest.has_accept();
+        if reading && key.auto_read && !self.reads_paused {
+            events |= libc::POLLIN;
+        }
+        if key.interest.has_write() {
+            events |= libc::POLLOUT;
+        }
+        events
+    }
+}
+
+impl<C: Codec> Default for TcpSelector<C> {
+    fn default() -> Self {
+        TcpSelector::new()
+    }
+}
+
+impl<C: Codec> Selector<TcpKey<C>> for TcpSelector<C> {
+    const DEFAULT_TIMEOUT_MS: i64 = 1000;
+
+    fn register(&mut self, mut key: TcpKey<C>, interest: Ops) {
+        key.interest = interest;
+        debug!("{:?} registered", key.id());
+        self.new_keys.push(key.id());
+        self.registered.insert(key.id(), key);
+    }
+
+    fn deregister(&mut self, key: &ChannelId) -> Option<TcpKey<C>> {
+        let mut key = self.registered.remove(key)?;
+        key.attributes.clear();
+        self.metrics.record_deregistered(key.metrics());
+        self.selected.remove(&key.id());
+        debug!("{:?} deregistered", key.id());
+        Some(key)
+    }
+
+    fn key(&self, resource: &ChannelId) -> Option<&TcpKey<C>> {
+        self.registered.get(resource)
+    }
+
+    fn key_mut(&mut self, resource: &ChannelId) -> Option<&mut TcpKey<C>> {
+        self.registered.get_mut(resource)
+    }
+
+    fn update_registration(&mut self, key: ChannelId, interest: Ops) {
+        if let Some(k) = self.registered.get_mut(&key) {
+            k.interest = interest;
+        }
+    }
+
+    fn set_auto_read(&mut self, key: &ChannelId, auto_read: bool) {
+        if let Some(k) = self.registered.get_mut(key) {
+            k.auto_read = auto_read;
+        }
+    }
+
+    fn set_reads_paused(&mut self, paused: bool) {
+        self.reads_paused = paused;
+    }
+
+    fn select(&mut self, timeout: i64) {
+        let started = Instant::now();
+        let mut polled = mem::take(&mut self.polled);
+        let mut fds = mem::take(&mut self.fds);
+        polled.clear();
+        fds.clear();
+        for (id, key) in &self.registered {
+            let events = self.poll_events(key);
+            if let (Some(fd), true) = (key.ch.socket.fd(), events != 0) {
+                polled.push(*id);
+                fds.push(libc::pollfd {
+                    fd,
+                    events,
+                    revents: 0,
+                });
+            }
+        }
+        let timeout = timeout.clamp(-1, i64::from(libc::c_int::MAX)) as libc::c_int;
+        // `fds` is a live, correctly sized array of pollfd for the duration of the call
+        let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        let latency = started.elapsed();
+        if res < 0 {
+            let why = io::Error::last_os_error();
+            if why.kind() != io::ErrorKind::Interrupted {
+                error!("TCP error on select: {}", why);
+                self.metrics.record_error(ErrorKind::Select);
+            }
+            self.polled = polled;
+            self.fds = fds;
+            return;
+        }
+
+        for (id, fd) in polled.iter().zip(&fds) {
+            let key = match self.registered.get_mut(id) {
+                Some(key) => key,
+                None => continue,
+            };
+            // Hang-ups and errors are picked up by the read or write they fail
+            let failed = fd.revents & (libc::POLLHUP | libc::POLLERR) != 0;
+            let readable =
+                fd.events & libc::POLLIN != 0 && (fd.revents & libc::POLLIN != 0 || failed);
+            let writable =
+                fd.events & libc::POLLOUT != 0 && (fd.revents & libc::POLLOUT != 0 || failed);
+            if readable && key.apply_read() {
+                self.selected.insert(*id);
+            }
+            if writable && key.apply_write() {
+                self.selected.insert(*id);
+            }
+        }
+        self.polled = polled;
+        self.fds = fds;
+        self.metrics.record_select(latency, self.selected.len());
+    }
+
+    fn metrics(&mut self) -> &mut LoopMetrics {
+        &mut self.metrics
+    }
+
+    fn snapshot(&self) -> Metrics<ChannelId> {
+        Metrics {
+            event_loop: self.metrics.clone(),
+            channels: self
+                .registered
+                .iter()
+                .map(|(id, key)| (*id, key.metrics()))
+                .collect(),
+        }
+    }
+
+    fn on_resource<F>(&mut self, resource: &ChannelId, coll: &mut Vec<RWEvent<TcpKey<C>>>, f: F)
+    where
+        F: Fn(&mut Vec<RWEvent<TcpKey<C>>>, &mut TcpKey<C>),
+    {
+        if let Some(key) = self.registered.get_mut(resource) {
+            f(coll, key);
+        }
+    }
+
+    fn on_selected<F>(&mut self, coll: &mut Vec<RWEvent<TcpKey<C>>>, f: F)
+    where
+        F: Fn(&mut Vec<RWEvent<TcpKey<C>>>, &mut TcpKey<C>),
+    {
+        let mut selected = mem::take(&mut self.selected);
+        for id in selected.drain() {
+            match self.registered.get_mut(&id) {
+                Some(key) => {
+                    f(coll, key);
+                    // poll is level-triggered, so anything still ready is reported again
+                    key.readiness = Ops::empty();
+                }
+                None => warn!("{:?} selected but no longer registered", id),
+            }
+        }
+    }
+
+    fn on_registered<F>(&self, f: F)
+    where
+        F: FnMut(&TcpKey<C>),
+    {
+        self.registered.values().for_each(f);
+    }
+
+    fn take_registered(&mut self) -> Vec<ChannelId> {
+        mem::take(&mut self.new_keys)
+    }
+}
+
+// impl Key
+impl<C: Codec> TcpKey<C> {
+    pub fn new(ch: TcpChannel<C>) -> Self {
+        let interest = match ch.socket {
+            Socket::Listener(_) => Ops::ACCEPT,
+            _ => Ops::READ | Ops::ERROR,
+        };
+        TcpKey {
+            ch,
+            readiness: Ops::empty(),
+            interest,
+            auto_read: true,
+            attributes: Attributes::new(),
+        }
+    }
+
+    pub fn id(&self) -> ChannelId {
+        self.ch.id
+    }
+}
+
+impl<C: Codec> SelectorKey for TcpKey<C> {
+    type Io = TcpChannel<C>;
+    type Resource = ChannelId;
+    type Inbound = <C as Decoder>::Item;
+    type Outbound = <C as Encoder>::Item;
+
+    fn ready_ops(&self) -> Ops {
+        self.readiness
+    }
+
+    fn set_readiness(&mut self, ops: Ops) {
+        self.readiness = ops;
+    }
+
+    fn set_interest(&mut self, ops: Ops) {
+        self.interest = ops;
+    }
+
+    fn io(&mut self) -> &mut Self::Io {
+        &mut self.ch
+    }
+
+    fn resource(&self) -> Self::Resource {
+        self.id()
+    }
+
+    fn metrics(&self) -> ChannelMetrics {
+        self.ch.metrics
+    }
+
+    fn activity(&self) -> Option<Activity> {
+        match self.ch.socket {
+            Socket::Listener(_) => None,
+            _ => Some(self.ch.activity),
+        }
+    }
+
+    fn idle_timeouts(&self) -> IdleTimeouts {
+        self.ch.options.idle
+    }
+
+    fn remote(&self) -> Option<SocketAddr> {
+        self.ch.remote()
+    }
+
+    fn connect_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    fn attributes_mut(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+
+    fn apply_read(&mut self) -> bool {
+        let wanted = match self.ch.socket {
+            Socket::Listener(_) => Ops::ACCEPT,
+            _ => Ops::READ,
+        };
+        if !self.interest.contains(wanted) {
+            return false;
+        }
+        self.readiness.apply(wanted);
+        true
+    }
+
+    fn apply_write(&mut self) -> bool {
+        if !self.interest.has_write() {
+            return false;
+        }
+        self.readiness.apply(Ops::WRITE);
+        true
+    }
+}
+
+impl<C> Hash for TcpKey<C> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ch.id.hash(state)
+    }
+}
+
+impl<C> PartialEq for TcpKey<C> {
+    fn eq(&self, other: &TcpKey<C>) -> bool {
+        self.ch.id == other.ch.id
+    }
+}
+
+impl<C> Eq for TcpKey<C> {}
+
+// impl TcpChannel
+impl TcpChannel {
+    pub fn new(listener: TcpListener) -> io::Result<Self> {
+        TcpChannel::with_options(listener, TcpOptions::default())
+    }
+
+    pub fn with_options(listener: TcpListener, options: TcpOptions) -> io::Result<Self> {
+        TcpChannel::with_codec(listener, BytesCodec, options)
+    }
+}
+
+impl<C: Codec> TcpChannel<C> {
+    /// Creates an acceptor whose peers decode reads and encode writes with `codec`.
+    ///
+    /// Fails if the listener can't be made non-blocking.
+    pub fn with_codec(listener: TcpListener, codec: C, options: TcpOptions) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let gate = Some(AcceptGate::new(options.accept_limits));
+        Ok(TcpChannel::with_socket(
+            Socket::Listener(listener),
+            codec,
+            options,
+            gate,
+        ))
+    }
+
+    fn with_socket(
+        socket: Socket,
+        codec: C,
+        options: TcpOptions,
+        gate: Option<AcceptGate>,
+    ) -> Self {
+        TcpChannel {
+            id: ChannelId::generate(),
+            socket,
+            options,
+            gate,
+            ip_filter: IpFilter::default(),
+            pending: VecDeque::new(),
+            written: 0,
+            write_blocked: false,
+            inbound: ByteToMessageDecoder::new(codec.clone()),
+            outbound: MessageToByteEncoder::new(codec),
+            metrics: ChannelMetrics::default(),
+            activity: Activity::new(Instant::now()),
+        }
+    }
+
+    pub fn id(&self) -> ChannelId {
+        self.id
+    }
+
+    /// Rejects accepted peers whose addresses `filter` doesn't allow.
+    pub fn with_ip_filter(mut self, filter: IpFilter) -> Self {
+        self.ip_filter = filter;
+        self
+    }
+
+    /// Replaces the acceptor's filter; peers already accepted are left alone.
+    pub fn set_ip_filter(&mut self, filter: IpFilter) {
+        self.ip_filter = filter;
+    }
+
+    pub fn ip_filter(&self) -> &IpFilter {
+        &self.ip_filter
+    }
+
+    /// The address the channel's socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.socket {
+            Socket::Listener(ref listener) => listener.local_addr(),
+            Socket::Peer(ref stream, _) => stream.local_addr(),
+            Socket::Closed => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    /// The peer's address, for accepted peers.
+    pub fn remote(&self) -> Option<SocketAddr> {
+        match self.socket {
+            Socket::Peer(_, remote) => Some(remote),
+            _ => None,
+        }
+    }
+
+    /// Number of writes not yet fully sent.
+    pub fn pending_writes(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn accept(&mut self, collector: &mut Vec<RWEvent<TcpKey<C>>>) {
+        let accepted = match self.socket {
+            Socket::Listener(ref listener) => listener.accept(),
+            _ => return,
+        };
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(ref why) if why.kind() == io::ErrorKind::WouldBlock => return,
+            Err(why) => {
+                collector.push(RWEvent::Error(ErrorEvent::Accept(self.id, why.to_string())));
+                return;
+            }
+        };
+        let admitted = if !self.ip_filter.allows(addr.ip()) {
+            Err(RejectReason::Denied)
+        } else {
+            match self.gate {
+                Some(ref gate) => gate.admit(addr.ip(), Instant::now()).map(Some),
+                None => Ok(None),
+            }
+        };
+        let permit = match admitted {
+            Ok(permit) => permit,
+            Err(reason) => {
+                // Dropping the stream closes it
+                let ev = StateEvent::Rejected(self.id, addr, reason);
+                collector.push(RWEvent::State(ev));
+                return;
+            }
+        };
+        if let Err(why) = stream.set_nonblocking(true) {
+            let why = format!("setting up {:?}: {}", addr, why);
+            collector.push(RWEvent::Error(ErrorEvent::Accept(self.id, why)));
+            return;
+        }
+        // Small responses shouldn't wait on the peer's delayed ACK
+        if let Err(why) = stream.set_nodelay(true) {
+            debug!(
+                "{:?} failed to set TCP_NODELAY for {:?}: {}",
+                self.id, addr, why
+            );
+        }
+
+        let codec = self.outbound.encoder().clone();
+        let ch = TcpChannel::with_socket(Socket::Peer(stream, addr), codec, self.options, None);
+        let mut key = TcpKey::new(ch);
+        if let Some(permit) = permit {
+            key.attributes.set(&PERMIT, permit);
+        }
+        collector.push(RWEvent::Read(ReadEvent::NewPeer(key, addr)));
+    }
+
+    // Sends queued writes until the queue empties or the socket would block, registering WRITE
+    // interest for as long as writes are left over. A failed send drops the queue, since nothing
+    // behind it can reach the peer either.
+    fn send_pending(&mut self, collector: &mut Vec<RWEvent<TcpKey<C>>>) {
+        let id = self.id;
+        let queued = !self.pending.is_empty();
+        let mut drained = true;
+        while let Some(data) = self.pending.front() {
+            let sent = match self.socket {
+                Socket::Peer(ref mut stream, _) => stream.write(&data[self.written..]),
+                _ => Err(io::ErrorKind::NotConnected.into()),
+            };
+            match sent {
+                Ok(0) => {
+                    drained = false;
+                    break;
+                }
+                Ok(len) => {
+                    self.metrics.bytes_written += len as u64;
+                    self.activity.last_write = Instant::now();
+                    self.written += len;
+                    if self.written == data.len() {
+                        self.pending.pop_front();
+                        self.written = 0;
+                    }
+                }
+                Err(ref why) if why.kind() == io::ErrorKind::WouldBlock => {
+                    drained = false;
+                    break;
+                }
+                Err(ref why) if why.kind() == io::ErrorKind::Interrupted => {}
+                Err(why) => {
+                    debug!("{:?} dropping {} unsent writes", id, self.pending.len());
+                    self.metrics.send_errors += 1;
+                    self.pending.clear();
+                    self.written = 0;
+                    collector.push(RWEvent::Error(ErrorEvent::Send(id, why.to_string())));
+                    break;
+                }
+            }
+        }
+        if self.write_blocked == drained {
+            self.write_blocked = !drained;
+            let mut interest = Ops::READ | Ops::ERROR;
+            if self.write_blocked {
+                interest.apply(Ops::WRITE);
+            }
+            collector.push(RWEvent::Registration(RegistrationEvent::Update(
+                id, interest,
+            )));
+        }
+        if queued && self.pending.is_empty() {
+            collector.push(RWEvent::Registration(RegistrationEvent::Flushed(id)));
+        }
+    }
+}
+
+impl<C> PartialEq for TcpChannel<C> {
+    fn eq(&self, other: &TcpChannel<C>) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<C> Eq for TcpChannel<C> {}
+
+impl<C: Codec> channel::ChExt<TcpKey<C>> for TcpChannel<C> {
+    fn connect(&mut self, _collector: &mut Vec<RWEvent<TcpKey<C>>>) {
+        warn!("{:?} TCP channels accept peers but don't connect", self.id);
+    }
+
+    fn finish_connect(&mut self, _collector: &mut Vec<RWEvent<TcpKey<C>>>) {}
+
+    fn close(&mut self) {
+        self.write_blocked = false;
+        self.pending.clear();
+        self.written = 0;
+        // Dropping the socket closes it
+        self.socket = Socket::Closed;
+    }
+}
+
+impl<C: Codec> channel::ChRead<TcpKey<C>> for TcpChannel<C> {
+    fn read(&mut self, collector: &mut Vec<RWEvent<TcpKey<C>>>) {
+        let (stream, remote) = match self.socket {
+            Socket::Listener(_) => return self.accept(collector),
+            Socket::Peer(ref mut stream, remote) => (stream, remote),
+            Socket::Closed => return,
+        };
+        // TODO buffer allocator
+        let mut buf = BytesMut::with_capacity(DEFAULT_TCP_BUF_CAPACITY);
+        buf.resize(DEFAULT_TCP_BUF_CAPACITY, 0u8);
+
+        let id = self.id;
+        match stream.read(&mut buf) {
+            Ok(0) => {
+                debug!("{:?} closed by {:?}", id, remote);
+                collector.push(RWEvent::State(StateEvent::Disconnected(id, remote)));
+            }
+            Ok(len) => {
+                buf.truncate(len);
+                trace!("{:?} read {:?} bytes", id, len);
+                self.metrics.bytes_read += len as u64;
+                self.activity.last_read = Instant::now();
+                self.inbound.decode(&buf, |msg| match msg {
+                    Ok(msg) => collector.push(RWEvent::Read(ReadEvent::Data(id, msg))),
+                    Err(why) => collector.push(RWEvent::Error(ErrorEvent::Codec(id, why))),
+                });
+            }
+            Err(ref why)
+                if why.kind() == io::ErrorKind::WouldBlock
+                    || why.kind() == io::ErrorKind::Interrupted => {}
+            Err(why) => {
+                debug!("{:?} lost {:?}: {}", id, remote, why);
+                self.metrics.recv_errors += 1;
+                collector.push(RWEvent::State(StateEvent::Disconnected(id, remote)));
+            }
+        }
+    }
+}
+
+impl<C: Codec> channel::ChWrite<TcpKey<C>> for TcpChannel<C> {
+    fn write(&mut self, msg: <C as Encoder>::Item, collector: &mut Vec<RWEvent<TcpKey<C>>>) {
+        let data = match self.outbound.encode(msg) {
+            Ok(data) => data,
+            Err(why) => {
+                collector.push(RWEvent::Error(ErrorEvent::Codec(self.id, why)));
+                return;
+            }
+        };
+        if self.write_blocked && self.pending.len() >= self.options.write_policy.max_queued(true) {
+            debug!(
+                "{:?} rejected write with {} waiting",
+                self.id,
+                self.pending.len()
+            );
+            collector.push(RWEvent::Error(ErrorEvent::WriteRejected(self.id)));
+            return;
+        }
+        self.pending.push_back(data);
+        // Writes queued behind a blocked send go out once the socket is writable again
+        if !self.write_blocked {
+            self.send_pending(collector);
+        }
+    }
+
+    fn flush(&mut self, collector: &mut Vec<RWEvent<TcpKey<C>>>) {
+        self.send_pending(collector);
+    }
+
+    fn pending_writes(&self) -> usize {
+        TcpChannel::pending_writes(self)
+    }
+}
+
+// impl Socket
+impl Socket {
+    fn fd(&self) -> Option<RawFd> {
+        match *self {
+            Socket::Listener(ref listener) => Some(listener.as_raw_fd()),
+            Socket::Peer(ref stream, _) => Some(stream.as_raw_fd()),
+            Socket::Closed => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ev_loop::{events, SelectorEventLoop, Trigger};
+    use futures::Stream;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn peers_are_accepted_read_and_lost() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let ch = TcpChannel::new(listener).expect("acceptor");
+        let addr = ch.local_addr().expect("bound address");
+        let (mut ev_loop, _tasks, events) = SelectorEventLoop::new(TcpSelector::new());
+        ev_loop.register(TcpKey::new(ch), Ops::ACCEPT);
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).expect("connect");
+            stream.write_all(b"ping").expect("write");
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ev_loop.selector().snapshot().event_loop.closed == 0 {
+            assert!(Instant::now() < deadline, "peer not lost");
+            ev_loop.run_once(Duration::from_millis(1));
+        }
+        client.join().expect("client thread");
+        assert_eq!(ev_loop.selector().registered.len(), 1);
+
+        drop(ev_loop);
+        let events: Vec<_> = events.wait().map(|ev| ev.expect("event")).collect();
+        match events.as_slice() {
+            [Trigger::State(events::StateEvent::Connected(peer, _)), Trigger::Read(events::ReadEvent::Data(read, data)), Trigger::State(events::StateEvent::Disconnected(lost, _))] =>
+            {
+                assert_eq!(&data[..], b"ping");
+                assert_eq!([*read, *lost], [*peer, *peer]);
+            }
+            other => panic!("expected a peer to come and go, got {:?}", other),
+        }
+    }
+}
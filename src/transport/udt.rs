@@ -1,21 +1,32 @@
-use bytes::Bytes;
-use bytes::BytesMut;
+use accept::{AcceptGate, AcceptLimits, IpFilter, RejectReason, PERMIT};
+use attribute::Attributes;
+use bytes::{Bytes, BytesMut};
 use channel;
-use channel::ChWrite;
-use channel::{RWEvent, ReadEvent, StateEvent};
+use channel::{
+    ChannelId, ConnectError, ErrorEvent, RWEvent, ReadEvent, RegistrationEvent, StateEvent,
+};
+use codec::{ByteToMessageDecoder, BytesCodec, Codec, Decoder, Encoder, MessageToByteEncoder};
+use ev_loop::{EventSender, Work};
+use idle::{Activity, IdleTimeouts};
+use metrics::{ChannelMetrics, ErrorKind, LoopMetrics, Metrics};
 use ops::Ops;
+use reconnect::{Backoff, Reconnect, WritePolicy};
 use selector::Selector;
 use selector::SelectorKey;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::CStr;
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::io;
 use std::io::Error;
 use std::io::Read;
 use std::io::Write;
 use std::mem;
 use std::net::SocketAddr;
+use std::os::raw::c_int;
+use std::time::{Duration, Instant};
 use udt::UdtOpts;
-use udt::{self, Epoll, EpollEvents, UdtError, UdtSocket, UdtStatus};
+use udt::{self, Epoll, EpollEvents, SocketFamily, SocketType, UdtError, UdtSocket, UdtStatus};
 
 const DEFAULT_UDT_BUF_CAPACITY: usize = 10000;
 
@@ -32,37 +43,243 @@ pub enum ChannelState {
     Connecting,
 }
 
+/// Congestion control algorithm applied to a single `UdtChannel`.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub enum CongestionControl {
+    /// UDT's built-in native rate control (UDT's default `CUDTCC`).
+    #[default]
+    Native,
+    /// Native rate control, with sending capped at `bytes_per_sec` (UDT's `UDT_MAXBW`).
+    ///
+    /// This is a ceiling, not a pacing rate: the channel may send slower whenever native control
+    /// backs off. Build it with `CongestionControl::max_bandwidth` to have the cap checked.
+    MaxBandwidth { bytes_per_sec: i64 },
+    /// Sends at a constant `bytes_per_sec`, packet headers included, without backing off on loss
+    /// or timeouts.
+    ///
+    /// Must be chosen before the channel connects: UDT won't change the controller of a connected
+    /// socket. Peers accepted by a fixed-rate acceptor send at its rate. Build it with
+    /// `CongestionControl::fixed_rate` to have the rate checked.
+    FixedRate { bytes_per_sec: i64 },
+}
+
+/// Per-channel configuration applied when a `UdtChannel` is created.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct ChannelOptions {
+    pub congestion_control: CongestionControl,
+    // Connects still in progress after this long are abandoned
+    pub connect_timeout: Option<Duration>,
+    // Connectors re-dial their remote with this backoff; never set on accepted peers
+    pub reconnect: Option<Backoff>,
+    pub write_policy: WritePolicy,
+    // Applies to acceptors only
+    pub accept_limits: AcceptLimits,
+    // Accepted peers inherit their acceptor's; acceptors themselves are never idle
+    pub idle: IdleTimeouts,
+}
+
+/// Snapshot of a channel's I/O counters and active configuration.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct ChannelStats {
+    pub io: ChannelMetrics,
+    pub congestion_control: CongestionControl,
+}
+
 #[derive(Debug)]
-pub struct UdtSelector {
+pub struct UdtSelector<C = BytesCodec> {
     poller: Epoll,
-    selected: HashSet<UdtSocket>,
-    pub registered: HashMap<UdtSocket, UdtKey>,
+    selected: HashSet<ChannelId>,
+    pub registered: HashMap<ChannelId, UdtKey<C>>,
+    // The poller reports sockets; this maps them back to the channels they belong to
+    sockets: HashMap<UdtSocket, ChannelId>,
+    metrics: LoopMetrics,
+    // Read interest is withheld from the poller while the loop applies backpressure
+    reads_paused: bool,
+    // Registered since the loop last armed timeouts
+    new_keys: Vec<ChannelId>,
 }
 
-#[derive(Debug, Eq)]
-pub struct UdtKey {
-    pub ch: UdtChannel,
+#[derive(Debug)]
+pub struct UdtKey<C = BytesCodec> {
+    pub ch: UdtChannel<C>,
     pub readiness: Ops,
     pub interest: Ops,
+    pub auto_read: bool,
+    pub attributes: Attributes,
 }
 
-#[derive(Debug, Eq, Copy, Clone)]
-pub struct UdtChannel {
+#[derive(Debug, Clone)]
+pub struct UdtChannel<C = BytesCodec> {
     pub io: SocketIo,
     pub kind: ChannelKind,
     pub state: ChannelState,
+    pub options: ChannelOptions,
+    connect_deadline: Option<Instant>,
+    // Set for acceptors
+    gate: Option<AcceptGate>,
+    // Checked by acceptors before their limits
+    ip_filter: IpFilter,
+    // Encoded writes not yet on the wire, either held back until the channel connects or left
+    // over from a send that would have blocked
+    pending: VecDeque<Bytes>,
+    // How much of the front of `pending` has already been sent
+    written: usize,
+    // Set while WRITE interest is registered to drain `pending`
+    write_blocked: bool,
+    inbound: ByteToMessageDecoder<C>,
+    outbound: MessageToByteEncoder<C>,
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct SocketIo {
+    id: ChannelId,
     socket: UdtSocket,
 
     // Debug info
-    bytes_sent: u64,
+    metrics: ChannelMetrics,
+    activity: Activity,
+}
+
+// Congestion control installed through UDT_CC, built from native/fixed_rate.cpp
+extern "C" {
+    fn petty_udt_set_fixed_rate(socket: c_int, bytes_per_sec: f64) -> c_int;
+    fn petty_udt_set_native_cc(socket: c_int) -> c_int;
+}
+
+// impl CongestionControl
+impl CongestionControl {
+    // UDT_MAXBW uses -1 to mean "no upper limit"
+    const UNLIMITED_BW: i64 = -1;
+
+    /// Caps sending at `bytes_per_sec`, which must be positive.
+    pub fn max_bandwidth(bytes_per_sec: i64) -> Result<Self, UdtError> {
+        let cc = CongestionControl::MaxBandwidth { bytes_per_sec };
+        cc.validate()?;
+        Ok(cc)
+    }
+
+    /// Sends at a constant `bytes_per_sec`, which must be positive.
+    pub fn fixed_rate(bytes_per_sec: i64) -> Result<Self, UdtError> {
+        let cc = CongestionControl::FixedRate { bytes_per_sec };
+        cc.validate()?;
+        Ok(cc)
+    }
+
+    /// The value handed to `UDT_MAXBW`.
+    pub fn maxbw(&self) -> i64 {
+        match *self {
+            CongestionControl::Native | CongestionControl::FixedRate { .. } => {
+                CongestionControl::UNLIMITED_BW
+            }
+            CongestionControl::MaxBandwidth { bytes_per_sec } => bytes_per_sec,
+        }
+    }
+
+    // The rate of the controller handed to `UDT_CC`, or None for UDT's own
+    fn fixed_bytes_per_sec(&self) -> Option<i64> {
+        match *self {
+            CongestionControl::FixedRate { bytes_per_sec } => Some(bytes_per_sec),
+            _ => None,
+        }
+    }
+
+    fn validate(&self) -> Result<(), UdtError> {
+        match *self {
+            CongestionControl::MaxBandwidth { bytes_per_sec } if bytes_per_sec <= 0 => {
+                Err(UdtError {
+                    err_code: udtsys::EINVPARAM,
+                    err_msg: format!("bandwidth cap must be positive, got {}", bytes_per_sec),
+                })
+            }
+            CongestionControl::FixedRate { bytes_per_sec } if bytes_per_sec <= 0 => Err(UdtError {
+                err_code: udtsys::EINVPARAM,
+                err_msg: format!("fixed rate must be positive, got {}", bytes_per_sec),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Installs the controller on `socket` through UDT_CC
+    fn install(&self, socket: UdtSocket) -> Result<(), UdtError> {
+        let raw = raw_socket(socket);
+        let res = match self.fixed_bytes_per_sec() {
+            Some(bytes_per_sec) => unsafe { petty_udt_set_fixed_rate(raw, bytes_per_sec as f64) },
+            None => unsafe { petty_udt_set_native_cc(raw) },
+        };
+        if res == 0 {
+            return Ok(());
+        }
+        let err_msg = unsafe { CStr::from_ptr(udtsys::udt_getlasterror_desc()) }
+            .to_string_lossy()
+            .into_owned();
+        Err(UdtError {
+            err_code: unsafe { udtsys::udt_getlasterror_code() },
+            err_msg,
+        })
+    }
+}
+
+// UdtSocket keeps its UDTSOCKET private; its derived Hash writes it, and nothing else, to the
+// hasher
+fn raw_socket(socket: UdtSocket) -> c_int {
+    #[derive(Default)]
+    struct Capture(c_int);
+
+    impl Hasher for Capture {
+        fn finish(&self) -> u64 {
+            self.0 as u64
+        }
+
+        fn write(&mut self, _: &[u8]) {
+            unreachable!("UdtSocket only hashes its UDTSOCKET");
+        }
+
+        fn write_i32(&mut self, raw: i32) {
+            self.0 = raw;
+        }
+    }
+
+    let mut capture = Capture::default();
+    socket.hash(&mut capture);
+    capture.0
+}
+
+// impl ChannelOptions
+impl ChannelOptions {
+    pub fn with_congestion_control(mut self, cc: CongestionControl) -> Self {
+        self.congestion_control = cc;
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_reconnect(mut self, backoff: Backoff) -> Self {
+        self.reconnect = Some(backoff);
+        self
+    }
+
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> Self {
+        self.write_policy = policy;
+        self
+    }
+
+    pub fn with_accept_limits(mut self, limits: AcceptLimits) -> Self {
+        self.accept_limits = limits;
+        self
+    }
+
+    /// Emits `StateEvent::Idle` once the channel stays inactive longer than `timeouts`.
+    pub fn with_idle_timeouts(mut self, timeouts: IdleTimeouts) -> Self {
+        self.idle = timeouts;
+        self
+    }
 }
 
 // impl UdtSelector
-impl UdtSelector {
+impl<C: Codec> UdtSelector<C> {
     pub fn new() -> Result<Self, UdtError> {
         udt::init();
         let poller = Epoll::create()?;
@@ -70,123 +287,231 @@ impl UdtSelector {
             poller,
             selected: HashSet::new(),
             registered: HashMap::new(),
+            sockets: HashMap::new(),
+            metrics: LoopMetrics::new(),
+            reads_paused: false,
+            new_keys: Vec::new(),
         };
         Ok(selector)
     }
+
+    // The interest actually handed to the poller, which drops READ while reads are suspended
+    fn poll_interest(&self, key: &UdtKey<C>, interest: Ops) -> Ops {
+        let mut ops = interest;
+        if self.reads_paused || !key.auto_read {
+            ops.remove(Ops::READ);
+        }
+        ops
+    }
+
+    fn repoll(&mut self, id: ChannelId, interest: Ops) {
+        let socket = match self.registered.get(&id) {
+            Some(key) => key.socket_clone(),
+            None => return,
+        };
+        let events = EpollEvents::from(interest);
+        let res = self
+            .poller
+            .remove_usock(&socket)
+            .and_then(|_| self.poller.add_usock(&socket, Some(events)));
+        if let Err(why) = res {
+            error!("{:?} failed to update registration: {:?}", id, why);
+            self.metrics.record_error(ErrorKind::Registration);
+        }
+    }
 }
 
-impl Selector<UdtKey> for UdtSelector {
+impl<C: Codec> Selector<UdtKey<C>> for UdtSelector<C> {
     const DEFAULT_TIMEOUT_MS: i64 = 1000;
 
-    fn register(&mut self, mut key: UdtKey, interest: Ops) {
+    fn register(&mut self, mut key: UdtKey<C>, interest: Ops) {
         key.interest = interest;
-        let mut events = EpollEvents::empty();
-        if interest.has_read() || interest.has_accept() {
-            events |= udt::UDT_EPOLL_IN;
-        }
-        if interest.has_write() || interest.has_connect() {
-            events |= udt::UDT_EPOLL_OUT;
-        }
-        if interest.has_error() {
-            events |= udt::UDT_EPOLL_ERR;
-        }
+        let events = EpollEvents::from(self.poll_interest(&key, interest));
 
         self.poller
             .add_usock(key.socket_ref(), Some(events))
             .expect("add_usock err");
         let status = key.socket_ref().getstate();
-        println!("registered state: {:?}", status);
-        self.registered.insert(key.socket_clone(), key);
+        debug!("{:?} registered in state {:?}", key.id(), status);
+        self.sockets.insert(key.socket_clone(), key.id());
+        self.new_keys.push(key.id());
+        self.registered.insert(key.id(), key);
     }
 
-    fn update_registration(&mut self, key: UdtSocket, interest: Ops) {
-        if let Some(_k) = self.registered.get_mut(&key) {
-            let mut events = EpollEvents::empty();
-            if interest.has_read() || interest.has_accept() {
-                events |= udt::UDT_EPOLL_IN;
-            }
-            if interest.has_write() || interest.has_connect() {
-                events |= udt::UDT_EPOLL_OUT;
+    fn deregister(&mut self, key: &ChannelId) -> Option<UdtKey<C>> {
+        let mut key = self.registered.remove(key)?;
+        key.attributes.clear();
+        self.metrics.record_deregistered(key.metrics());
+        self.sockets.remove(key.socket_ref());
+        self.selected.remove(&key.id());
+        if let Err(why) = self.poller.remove_usock(key.socket_ref()) {
+            warn!("{:?} failed to leave poller: {:?}", key.id(), why);
+        }
+        debug!("{:?} deregistered", key.id());
+        Some(key)
+    }
+
+    fn key(&self, resource: &ChannelId) -> Option<&UdtKey<C>> {
+        self.registered.get(resource)
+    }
+
+    fn key_mut(&mut self, resource: &ChannelId) -> Option<&mut UdtKey<C>> {
+        self.registered.get_mut(resource)
+    }
+
+    fn update_registration(&mut self, key: ChannelId, interest: Ops) {
+        let polled = match self.registered.get_mut(&key) {
+            Some(k) => {
+                k.interest = interest;
+                self.poll_interest(&self.registered[&key], interest)
             }
-            if interest.has_error() {
-                events |= udt::UDT_EPOLL_ERR;
+            None => return,
+        };
+        self.repoll(key, polled);
+    }
+
+    fn set_auto_read(&mut self, key: &ChannelId, auto_read: bool) {
+        let interest = match self.registered.get_mut(key) {
+            Some(k) => {
+                if k.auto_read == auto_read {
+                    return;
+                }
+                k.auto_read = auto_read;
+                k.interest
             }
-            self.poller.remove_usock(&key).unwrap();
-            self.poller.add_usock(&key, Some(events)).unwrap()
+            None => return,
+        };
+        if interest.has_read() {
+            let polled = self.poll_interest(&self.registered[key], interest);
+            self.repoll(*key, polled);
+        }
+    }
+
+    fn set_reads_paused(&mut self, paused: bool) {
+        if self.reads_paused == paused {
+            return;
+        }
+        self.reads_paused = paused;
+        let readers: Vec<(ChannelId, Ops)> = self
+            .registered
+            .iter()
+            .filter(|(_, k)| k.auto_read && k.interest.has_read())
+            .map(|(id, k)| (*id, self.poll_interest(k, k.interest)))
+            .collect();
+        for (id, polled) in readers {
+            self.repoll(id, polled);
         }
     }
 
     fn select(&mut self, timeout: i64) {
         // TODO modify poller to re-use fixed length vectors
-        let (readers, writers) = self.poller.wait(timeout as i64, true).unwrap();
-        println!("#r: {:?}, #w: {:?}", readers.len(), writers.len());
+        let started = Instant::now();
+        let (readers, writers) = match self.poller.wait(timeout, true) {
+            Ok(ready) => ready,
+            Err(why) => {
+                error!("UDT error on select: {:?}", why);
+                self.metrics.record_error(ErrorKind::Select);
+                return;
+            }
+        };
+        let latency = started.elapsed();
+        trace!("#r: {:?}, #w: {:?}", readers.len(), writers.len());
 
+        let sockets = &self.sockets;
+        let registered = &mut self.registered;
         for socket in readers {
-            let mut key = {
-                match self.registered.get_mut(&socket) {
+            let key = {
+                match sockets.get(&socket).and_then(|id| registered.get_mut(id)) {
                     None => {
-                        println!("[WARN] unregistered read for {:?}", socket);
+                        warn!("{:?} unregistered read", socket);
                         continue;
                     }
                     Some(key) => key,
                 }
             };
             if key.apply_read() {
-                self.selected.insert(socket);
+                self.selected.insert(key.id());
             } else {
-                println!("Reader {:?} didn't wanna read", socket);
+                trace!("{:?} ready to read without read interest", key.id());
             }
         }
         for socket in writers {
-            let mut key = {
-                match self.registered.get_mut(&socket) {
+            let key = {
+                match sockets.get(&socket).and_then(|id| registered.get_mut(id)) {
                     None => {
-                        println!("[WARN] unregistered write for {:?}", socket);
+                        warn!("{:?} unregistered write", socket);
                         continue;
                     }
                     Some(key) => key,
                 }
             };
             if key.apply_write() {
-                self.selected.insert(socket);
+                self.selected.insert(key.id());
             } else {
-                println!("Writer {:?} didn't wanna write", socket);
+                trace!("{:?} ready to write without write interest", key.id());
             }
         }
+        self.metrics.record_select(latency, self.selected.len());
     }
 
-    fn on_resource<F>(
-        &mut self,
-        resource: &<UdtKey as SelectorKey>::Resource,
-        coll: &mut Vec<RWEvent<UdtKey>>,
-        f: F,
-    ) where
-        F: Fn(&mut Vec<RWEvent<UdtKey>>, &mut UdtKey) -> (),
+    fn metrics(&mut self) -> &mut LoopMetrics {
+        &mut self.metrics
+    }
+
+    fn snapshot(&self) -> Metrics<ChannelId> {
+        Metrics {
+            event_loop: self.metrics.clone(),
+            channels: self
+                .registered
+                .iter()
+                .map(|(id, key)| (*id, key.metrics()))
+                .collect(),
+        }
+    }
+
+    fn on_resource<F>(&mut self, resource: &ChannelId, coll: &mut Vec<RWEvent<UdtKey<C>>>, f: F)
+    where
+        F: Fn(&mut Vec<RWEvent<UdtKey<C>>>, &mut UdtKey<C>),
     {
-        self.registered.get_mut(resource).map(|key| {
+        if let Some(key) = self.registered.get_mut(resource) {
             f(coll, key);
-        });
+        }
     }
 
-    fn on_selected<F>(&mut self, coll: &mut Vec<RWEvent<UdtKey>>, f: F)
+    fn on_selected<F>(&mut self, coll: &mut Vec<RWEvent<UdtKey<C>>>, f: F)
     where
-        F: Fn(&mut Vec<RWEvent<UdtKey>>, &mut UdtKey) -> (),
+        F: Fn(&mut Vec<RWEvent<UdtKey<C>>>, &mut UdtKey<C>),
     {
-        let mut selected = mem::replace(&mut self.selected, HashSet::new());
-        selected.drain().for_each(|s| {
-            self.registered.get_mut(&s).map_or_else(
+        let mut selected = mem::take(&mut self.selected);
+        selected.drain().for_each(|id| {
+            self.registered.get_mut(&id).map_or_else(
                 || {
-                    println!("Key not found!");
+                    warn!("{:?} selected but no longer registered", id);
+                },
+                |key| {
+                    f(coll, key);
+                    // UDT's epoll is level-triggered, so anything still ready is reported again
+                    key.readiness = Ops::empty();
                 },
-                |key| f(coll, key),
             );
         });
     }
+
+    fn on_registered<F>(&self, f: F)
+    where
+        F: FnMut(&UdtKey<C>),
+    {
+        self.registered.values().for_each(f);
+    }
+
+    fn take_registered(&mut self) -> Vec<ChannelId> {
+        mem::take(&mut self.new_keys)
+    }
 }
 
 // impl Key
-impl UdtKey {
-    pub fn new(ch: UdtChannel) -> Self {
+impl<C: Codec> UdtKey<C> {
+    pub fn new(ch: UdtChannel<C>) -> Self {
         let mut interest = Ops::empty();
         let readiness = Ops::empty();
 
@@ -203,21 +528,29 @@ impl UdtKey {
             ch,
             readiness,
             interest,
+            auto_read: true,
+            attributes: Attributes::new(),
         }
     }
 
+    pub fn id(&self) -> ChannelId {
+        self.ch.io.id
+    }
+
     pub fn socket_ref(&self) -> &UdtSocket {
         &self.ch.io.socket
     }
 
     pub fn socket_clone(&self) -> UdtSocket {
-        self.ch.io.socket.clone()
+        self.ch.io.socket
     }
 }
 
-impl SelectorKey for UdtKey {
-    type Io = UdtChannel;
-    type Resource = UdtSocket;
+impl<C: Codec> SelectorKey for UdtKey<C> {
+    type Io = UdtChannel<C>;
+    type Resource = ChannelId;
+    type Inbound = <C as Decoder>::Item;
+    type Outbound = <C as Encoder>::Item;
 
     fn ready_ops(&self) -> Ops {
         self.readiness
@@ -236,7 +569,48 @@ impl SelectorKey for UdtKey {
     }
 
     fn resource(&self) -> Self::Resource {
-        self.socket_clone()
+        self.id()
+    }
+
+    fn metrics(&self) -> ChannelMetrics {
+        self.ch.io.metrics
+    }
+
+    fn activity(&self) -> Option<Activity> {
+        match self.ch.kind {
+            ChannelKind::Acceptor => None,
+            ChannelKind::Connector { .. } => Some(self.ch.io.activity),
+        }
+    }
+
+    fn idle_timeouts(&self) -> IdleTimeouts {
+        self.ch.options.idle
+    }
+
+    fn remote(&self) -> Option<SocketAddr> {
+        match self.ch.kind {
+            ChannelKind::Acceptor => None,
+            ChannelKind::Connector { remote } => Some(remote),
+        }
+    }
+
+    fn connect_deadline(&self) -> Option<Instant> {
+        self.ch.connect_deadline
+    }
+
+    fn connector(&mut self) -> Option<&mut dyn Reconnect> {
+        match self.ch.kind {
+            ChannelKind::Acceptor => None,
+            ChannelKind::Connector { .. } => Some(&mut self.ch),
+        }
+    }
+
+    fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    fn attributes_mut(&mut self) -> &mut Attributes {
+        &mut self.attributes
     }
 
     fn apply_read(&mut self) -> bool {
@@ -261,25 +635,23 @@ impl SelectorKey for UdtKey {
 
     fn apply_write(&mut self) -> bool {
         match self.ch.kind {
-            ChannelKind::Acceptor => {
-                return false;
-            }
+            ChannelKind::Acceptor => false,
             ChannelKind::Connector { .. } => {
                 if self.ch.state() == ChannelState::Connected {
                     // Connected; transition to writable
                     if self.interest.has_write() {
                         self.readiness.apply(Ops::WRITE);
-                        return true;
+                        true
                     } else {
-                        return false;
+                        false
                     }
                 } else {
                     // Not connected, but ready to connect
                     if self.interest.has_connect() {
                         self.readiness.apply(Ops::CONNECT);
-                        return true;
+                        true
                     } else {
-                        return false;
+                        false
                     }
                 }
             }
@@ -287,36 +659,156 @@ impl SelectorKey for UdtKey {
     }
 }
 
-impl Hash for UdtKey {
+impl<C> Hash for UdtKey<C> {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.ch.io.socket.hash(state)
+        self.ch.io.id.hash(state)
     }
 }
 
-impl PartialEq for UdtKey {
-    fn eq(&self, other: &UdtKey) -> bool {
-        self.ch.io.socket == other.ch.io.socket
+impl<C> PartialEq for UdtKey<C> {
+    fn eq(&self, other: &UdtKey<C>) -> bool {
+        self.ch.io.id == other.ch.io.id
     }
 }
 
+impl<C> Eq for UdtKey<C> {}
+
 // impl UdtChannel
 impl UdtChannel {
-    pub fn new(socket: UdtSocket, kind: ChannelKind) -> Self {
+    pub fn new(socket: UdtSocket, kind: ChannelKind) -> Result<Self, UdtError> {
+        UdtChannel::with_options(socket, kind, ChannelOptions::default())
+    }
+
+    pub fn with_options(
+        socket: UdtSocket,
+        kind: ChannelKind,
+        options: ChannelOptions,
+    ) -> Result<Self, UdtError> {
+        UdtChannel::with_codec(socket, kind, BytesCodec, options)
+    }
+}
+
+impl<C: Codec> UdtChannel<C> {
+    /// Creates a channel that decodes reads and encodes writes with `codec`.
+    ///
+    /// Fails if the socket can't be made non-blocking or the congestion control can't be applied.
+    pub fn with_codec(
+        socket: UdtSocket,
+        kind: ChannelKind,
+        codec: C,
+        options: ChannelOptions,
+    ) -> Result<Self, UdtError> {
         let io = SocketIo::new(socket);
+        let gate = match kind {
+            ChannelKind::Acceptor => Some(AcceptGate::new(options.accept_limits)),
+            ChannelKind::Connector { .. } => None,
+        };
         // Ensure non-blocking mode
-        socket.setsockopt(UdtOpts::UDT_SNDSYN, false).unwrap();
-        socket.setsockopt(UdtOpts::UDT_RCVSYN, false).unwrap();
+        socket.setsockopt(UdtOpts::UDT_SNDSYN, false)?;
+        socket.setsockopt(UdtOpts::UDT_RCVSYN, false)?;
 
-        UdtChannel {
+        let mut ch = UdtChannel {
             io,
             kind,
             state: ChannelState::Idle,
+            options,
+            connect_deadline: None,
+            gate,
+            ip_filter: IpFilter::default(),
+            pending: VecDeque::new(),
+            written: 0,
+            write_blocked: false,
+            inbound: ByteToMessageDecoder::new(codec.clone()),
+            outbound: MessageToByteEncoder::new(codec),
+        };
+        // Accepted peers are already connected, with their acceptor's controller; other sockets
+        // start out with UDT's own
+        let installed = match ch.sockstate() {
+            UdtStatus::CONNECTED => options.congestion_control,
+            _ => CongestionControl::Native,
+        };
+        ch.apply_congestion_control(installed, options.congestion_control)?;
+        Ok(ch)
+    }
+
+    pub fn id(&self) -> ChannelId {
+        self.io.id
+    }
+
+    /// Switches the channel's congestion control.
+    ///
+    /// Fails if the controller itself would change, to or from `FixedRate`, once the channel has
+    /// started connecting; bandwidth caps can change at any time.
+    pub fn set_congestion_control(&mut self, cc: CongestionControl) -> Result<(), UdtError> {
+        let installed = self.options.congestion_control;
+        self.apply_congestion_control(installed, cc)
+    }
+
+    fn apply_congestion_control(
+        &mut self,
+        installed: CongestionControl,
+        cc: CongestionControl,
+    ) -> Result<(), UdtError> {
+        cc.validate()?;
+        if cc.fixed_bytes_per_sec() != installed.fixed_bytes_per_sec() {
+            cc.install(self.io.socket)?;
         }
+        self.io.socket.setsockopt(UdtOpts::UDT_MAXBW, cc.maxbw())?;
+        self.options.congestion_control = cc;
+        Ok(())
+    }
+
+    /// Rejects accepted peers whose addresses `filter` doesn't allow.
+    pub fn with_ip_filter(mut self, filter: IpFilter) -> Self {
+        self.ip_filter = filter;
+        self
+    }
+
+    /// Replaces the acceptor's filter; peers already accepted are left alone.
+    pub fn set_ip_filter(&mut self, filter: IpFilter) {
+        self.ip_filter = filter;
+    }
+
+    pub fn ip_filter(&self) -> &IpFilter {
+        &self.ip_filter
+    }
+
+    pub fn congestion_control(&self) -> CongestionControl {
+        self.options.congestion_control
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            io: self.io.metrics,
+            congestion_control: self.options.congestion_control,
+        }
+    }
+
+    /// Starts a non-blocking connect to the connector's remote address.
+    ///
+    /// The attempt is abandoned after the channel's connect timeout once the key is registered
+    /// with `CONNECT` interest.
+    pub fn connect(&mut self) -> Result<(), ConnectError> {
+        let remote = match self.kind {
+            ChannelKind::Acceptor => {
+                return Err(ConnectError::Failed("acceptors cannot connect".to_owned()))
+            }
+            ChannelKind::Connector { remote } => remote,
+        };
+        self.io
+            .socket
+            .connect(remote)
+            .map_err(|why| ConnectError::Failed(why.err_msg))?;
+        self.state = ChannelState::Connecting;
+        self.connect_deadline = self.options.connect_timeout.map(|t| Instant::now() + t);
+        self.finish_connect();
+        Ok(())
     }
 
     pub fn finish_connect(&mut self) -> ChannelState {
         if self.is_connected() {
             self.state = ChannelState::Connected;
+            self.connect_deadline = None;
         }
         self.state
     }
@@ -351,48 +843,243 @@ impl UdtChannel {
     pub fn sockstate(&self) -> UdtStatus {
         self.io.socket.getstate()
     }
+
+    /// Number of writes not yet fully sent.
+    pub fn pending_writes(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn connected(&mut self, collector: &mut Vec<RWEvent<UdtKey<C>>>) {
+        let id = self.io.id;
+        let addr: SocketAddr = match self.io.socket.getpeername() {
+            Ok(addr) => addr,
+            Err(why) => {
+                // Connected by its state, yet already without a peer
+                let why = ConnectError::Failed(why.err_msg);
+                return self.connect_failed(why, collector);
+            }
+        };
+        let interest = Ops::READ | Ops::ERROR;
+        collector.push(RWEvent::Registration(RegistrationEvent::Update(
+            id, interest,
+        )));
+        self.write_blocked = false;
+        self.send_pending(collector);
+        collector.push(RWEvent::State(StateEvent::ConnectedPeer(id, addr)));
+    }
+
+    // Sends queued writes until the queue empties or the socket would block, registering WRITE
+    // interest for as long as writes are left over. A failed send drops the queue, since nothing
+    // behind it can reach the peer either.
+    fn send_pending(&mut self, collector: &mut Vec<RWEvent<UdtKey<C>>>) {
+        let id = self.io.id;
+        let queued = !self.pending.is_empty();
+        let mut drained = true;
+        while let Some(data) = self.pending.front() {
+            match self.io.write(&data[self.written..]) {
+                Ok(0) => {
+                    drained = false;
+                    break;
+                }
+                Ok(len) => {
+                    self.written += len;
+                    if self.written == data.len() {
+                        self.pending.pop_front();
+                        self.written = 0;
+                    }
+                }
+                Err(ref why) if why.kind() == io::ErrorKind::WouldBlock => {
+                    drained = false;
+                    break;
+                }
+                Err(why) => {
+                    debug!("{:?} dropping {} unsent writes", id, self.pending.len());
+                    self.pending.clear();
+                    self.written = 0;
+                    collector.push(RWEvent::Error(ErrorEvent::Send(id, why.to_string())));
+                    break;
+                }
+            }
+        }
+        if self.write_blocked == drained {
+            self.write_blocked = !drained;
+            let mut interest = Ops::READ | Ops::ERROR;
+            if self.write_blocked {
+                interest.apply(Ops::WRITE);
+            }
+            collector.push(RWEvent::Registration(RegistrationEvent::Update(
+                id, interest,
+            )));
+        }
+        if queued && self.pending.is_empty() {
+            collector.push(RWEvent::Registration(RegistrationEvent::Flushed(id)));
+        }
+    }
+
+    fn connect_failed(&mut self, why: ConnectError, collector: &mut Vec<RWEvent<UdtKey<C>>>) {
+        self.connect_deadline = None;
+        if let ChannelKind::Connector { remote } = self.kind {
+            let ev = StateEvent::ConnectFailed(self.io.id, remote, why);
+            collector.push(RWEvent::State(ev));
+        }
+    }
 }
 
-impl PartialEq for UdtChannel {
-    fn eq(&self, other: &UdtChannel) -> bool {
-        self.io.socket == other.io.socket
+impl<C: Codec> Reconnect for UdtChannel<C> {
+    fn backoff(&self) -> Option<Backoff> {
+        match self.kind {
+            ChannelKind::Acceptor => None,
+            ChannelKind::Connector { .. } => self.options.reconnect,
+        }
+    }
+
+    /// Swaps in a new socket to the same remote, keeping the channel's id and codec.
+    ///
+    /// Writes not yet sent on the old socket are sent whole once the new one connects.
+    fn redial(&mut self) -> Result<(), ConnectError> {
+        let remote = match self.kind {
+            ChannelKind::Acceptor => {
+                return Err(ConnectError::Failed("acceptors cannot connect".to_owned()))
+            }
+            ChannelKind::Connector { remote } => remote,
+        };
+        let family = match remote {
+            SocketAddr::V4(_) => SocketFamily::AFInet,
+            SocketAddr::V6(_) => SocketFamily::AFInet6,
+        };
+        let socket = UdtSocket::new(family, SocketType::Stream)
+            .map_err(|why| ConnectError::Failed(why.err_msg))?;
+        let codec = self.outbound.encoder().clone();
+        let ch = match UdtChannel::with_codec(socket, self.kind, codec, self.options) {
+            Ok(ch) => ch,
+            Err(why) => {
+                if let Err(why) = socket.close() {
+                    warn!("{:?} failed to close new socket: {:?}", self.io.id, why);
+                }
+                return Err(ConnectError::Failed(why.err_msg));
+            }
+        };
+        let io = SocketIo {
+            id: self.io.id,
+            ..ch.io
+        };
+        let pending = mem::take(&mut self.pending);
+        *self = UdtChannel { io, pending, ..ch };
+        Ok(())
     }
 }
-impl channel::ChExt<UdtKey> for UdtChannel {
-    fn finish_connect(&mut self, collector: &mut Vec<RWEvent<UdtKey>>) {
-        self.finish_connect();
-        let addr: SocketAddr = self
-            .io
-            .socket
-            .getpeername()
-            .expect("no associated peer with socket in finish_connect");
-        let ev: StateEvent<UdtKey> = StateEvent::ConnectedPeer(self.io.socket, addr);
-        collector.push(RWEvent::State(ev));
+
+impl<C> PartialEq for UdtChannel<C> {
+    fn eq(&self, other: &UdtChannel<C>) -> bool {
+        self.io.id == other.io.id
+    }
+}
+
+impl<C> Eq for UdtChannel<C> {}
+
+impl<C: Codec> channel::ChExt<UdtKey<C>> for UdtChannel<C> {
+    fn connect(&mut self, collector: &mut Vec<RWEvent<UdtKey<C>>>) {
+        let id = self.io.id;
+        if let Err(why) = UdtChannel::connect(self) {
+            self.connect_failed(why, collector);
+            return;
+        }
+        if self.state == ChannelState::Connected {
+            self.connected(collector);
+        } else {
+            let interest = Ops::CONNECT | Ops::ERROR;
+            collector.push(RWEvent::Registration(RegistrationEvent::Update(
+                id, interest,
+            )));
+        }
+    }
+
+    fn finish_connect(&mut self, collector: &mut Vec<RWEvent<UdtKey<C>>>) {
+        let id = self.io.id;
+        match self.sockstate() {
+            UdtStatus::CONNECTED => {
+                self.finish_connect();
+                self.connected(collector);
+            }
+            UdtStatus::CONNECTING => trace!("{:?} still connecting", id),
+            status => {
+                debug!("{:?} failed to connect in state {:?}", id, status);
+                self.connect_failed(ConnectError::Broken, collector);
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        self.state = ChannelState::Idle;
+        self.connect_deadline = None;
+        self.write_blocked = false;
+        if let Err(why) = self.io.socket.close() {
+            warn!("{:?} failed to close: {:?}", self.io.id, why);
+        }
     }
 }
 
-impl channel::ChRead<UdtKey> for UdtChannel {
-    fn read(&mut self, collector: &mut Vec<RWEvent<UdtKey>>) {
+impl<C: Codec> channel::ChRead<UdtKey<C>> for UdtChannel<C> {
+    fn read(&mut self, collector: &mut Vec<RWEvent<UdtKey<C>>>) {
         match self.kind {
             ChannelKind::Acceptor => {
-                let (peer, addr) = self
-                    .io
-                    .socket
-                    .accept()
-                    .expect("told to accept but experienced err!");
-
-                let ch = UdtChannel::new(
-                    peer,
-                    ChannelKind::Connector {
-                        remote: addr.clone(),
-                    },
-                );
-                let key = UdtKey::new(ch);
+                let (peer, addr) = match self.io.socket.accept() {
+                    Ok(accepted) => accepted,
+                    Err(why) => {
+                        let ev = ErrorEvent::Accept(self.io.id, why.err_msg);
+                        collector.push(RWEvent::Error(ev));
+                        return;
+                    }
+                };
+                let admitted = if !self.ip_filter.allows(addr.ip()) {
+                    Err(RejectReason::Denied)
+                } else {
+                    match self.gate {
+                        Some(ref gate) => gate.admit(addr.ip(), Instant::now()).map(Some),
+                        None => Ok(None),
+                    }
+                };
+                let permit = match admitted {
+                    Ok(permit) => permit,
+                    Err(reason) => {
+                        if let Err(why) = peer.close() {
+                            warn!("{:?} failed to close rejected peer: {:?}", self.io.id, why);
+                        }
+                        let ev = StateEvent::Rejected(self.io.id, addr, reason);
+                        collector.push(RWEvent::State(ev));
+                        return;
+                    }
+                };
+
+                // Accepted peers inherit the acceptor's codec and channel options, but it's up to
+                // the remote end to re-dial
+                let options = ChannelOptions {
+                    reconnect: None,
+                    ..self.options
+                };
+                let kind = ChannelKind::Connector { remote: addr };
+                let codec = self.outbound.encoder().clone();
+                let mut ch = match UdtChannel::with_codec(peer, kind, codec, options) {
+                    Ok(ch) => ch,
+                    Err(why) => {
+                        if let Err(why) = peer.close() {
+                            warn!("{:?} failed to close peer: {:?}", self.io.id, why);
+                        }
+                        let why = format!("setting up {:?}: {}", addr, why.err_msg);
+                        collector.push(RWEvent::Error(ErrorEvent::Accept(self.io.id, why)));
+                        return;
+                    }
+                };
+                ch.state = ChannelState::Connected;
+                let mut key = UdtKey::new(ch);
+                if let Some(permit) = permit {
+                    key.attributes.set(&PERMIT, permit);
+                }
                 let ev = ReadEvent::NewPeer(key, addr);
                 // TODO figure out Netty-like pipeline for funneling read events
                 collector.push(RWEvent::Read(ev));
             }
-            ChannelKind::Connector { .. } => {
+            ChannelKind::Connector { remote } => {
                 // TODO buffer allocator
                 let mut buf = {
                     let mut buf = BytesMut::with_capacity(DEFAULT_UDT_BUF_CAPACITY);
@@ -400,26 +1087,67 @@ impl channel::ChRead<UdtKey> for UdtChannel {
                     buf
                 };
 
-                if let Ok(len) = self.io.read(&mut buf) {
-                    buf.truncate(len);
-                    let ev = ReadEvent::Data(buf.freeze());
-                    println!("Read {:?} bytes", len);
-                    // TODO figure out Netty-like pipeline for funneling read events
-                    collector.push(RWEvent::Read(ev));
+                let id = self.io.id;
+                match self.io.read(&mut buf) {
+                    Ok(len) => {
+                        buf.truncate(len);
+                        trace!("{:?} read {:?} bytes", id, len);
+                        // TODO figure out Netty-like pipeline for funneling read events
+                        self.inbound.decode(&buf, |msg| match msg {
+                            Ok(msg) => collector.push(RWEvent::Read(ReadEvent::Data(id, msg))),
+                            Err(why) => collector.push(RWEvent::Error(ErrorEvent::Codec(id, why))),
+                        });
+                    }
+                    // A failed read on a socket that was connected means the peer is gone
+                    Err(_) if self.state == ChannelState::Connected && !self.is_connected() => {
+                        debug!("{:?} disconnected in state {:?}", id, self.sockstate());
+                        self.state = ChannelState::Idle;
+                        let ev = StateEvent::Disconnected(id, remote);
+                        collector.push(RWEvent::State(ev));
+                    }
+                    Err(_) => {}
                 }
             }
         }
     }
 }
 
-impl channel::ChWrite<UdtKey> for UdtChannel {
-    fn write(&mut self, data: &Bytes, _collector: &mut Vec<RWEvent<UdtKey>>) {
-        // TODO write to buffer, not directly to wire (though UDT buffers internally, so this might be ok)
-        let _num_bytes = self.io.socket.send(data.as_ref()).expect("send err");
+impl<C: Codec> channel::ChWrite<UdtKey<C>> for UdtChannel<C> {
+    fn write(&mut self, msg: <C as Encoder>::Item, collector: &mut Vec<RWEvent<UdtKey<C>>>) {
+        let data = match self.outbound.encode(msg) {
+            Ok(data) => data,
+            Err(why) => {
+                collector.push(RWEvent::Error(ErrorEvent::Codec(self.io.id, why)));
+                return;
+            }
+        };
+        let connected = self.state == ChannelState::Connected;
+        let waiting = !connected || self.write_blocked;
+        if waiting && self.pending.len() >= self.options.write_policy.max_queued(connected) {
+            debug!(
+                "{:?} rejected write with {} waiting",
+                self.io.id,
+                self.pending.len()
+            );
+            collector.push(RWEvent::Error(ErrorEvent::WriteRejected(self.io.id)));
+            return;
+        }
+        self.pending.push_back(data);
+        // Writes queued behind a blocked send go out once the socket is writable again, and
+        // those made before connecting once it connects
+        if !waiting {
+            self.send_pending(collector);
+        }
+    }
+
+    fn flush(&mut self, collector: &mut Vec<RWEvent<UdtKey<C>>>) {
+        if self.state == ChannelState::Connected {
+            self.send_pending(collector);
+        }
     }
 
-    fn flush(&mut self, _collector: &mut Vec<RWEvent<UdtKey>>) {
-        ()
+    fn pending_writes(&self) -> usize {
+        UdtChannel::pending_writes(self)
     }
 }
 
@@ -427,27 +1155,32 @@ impl channel::ChWrite<UdtKey> for UdtChannel {
 impl SocketIo {
     pub fn new(socket: UdtSocket) -> Self {
         SocketIo {
+            id: ChannelId::generate(),
             socket,
-            bytes_sent: 0,
+            metrics: ChannelMetrics::default(),
+            activity: Activity::new(Instant::now()),
         }
     }
 }
 
-impl ChWrite<UdtKey> for SocketIo {
-    fn write(&mut self, data: &Bytes, _collector: &mut Vec<RWEvent<UdtKey>>) {
-        use std;
-        std::io::Write::write(self, data).expect("I/O err on UdtKey ChWrite");
-    }
-
-    fn flush(&mut self, _collector: &mut Vec<RWEvent<UdtKey>>) {
-        unimplemented!()
-    }
-}
 impl Read for SocketIo {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
         let len = buf.len();
-        let len = self.socket.recv(buf, len).expect("TODO UDT error on recv");
-        Ok(len as usize)
+        match self.socket.recv(buf, len) {
+            Ok(len) => {
+                self.metrics.bytes_read += len as u64;
+                self.activity.last_read = Instant::now();
+                Ok(len as usize)
+            }
+            Err(why) => {
+                // Every non-blocking read ends in EASYNCRCV once the socket is drained
+                if why.err_code == udtsys::EASYNCRCV {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                self.metrics.recv_errors += 1;
+                Err(io::Error::other(why.err_msg))
+            }
+        }
     }
 }
 
@@ -457,14 +1190,20 @@ impl Write for SocketIo {
             .send(buf)
             .map(|len| {
                 assert!(len >= 0);
-                self.bytes_sent += len as u64;
+                self.metrics.bytes_written += len as u64;
+                self.activity.last_write = Instant::now();
                 len as usize
-            }).map_err(|why| {
-                println!(
-                    "UDT error {:?} on send after {:?} bytes",
-                    why, self.bytes_sent
+            })
+            .map_err(|why| {
+                if why.err_code == udtsys::EASYNCSND {
+                    return io::ErrorKind::WouldBlock.into();
+                }
+                error!(
+                    "{:?} UDT error {:?} on send after {:?} bytes",
+                    self.id, why, self.metrics.bytes_written
                 );
-                panic!();
+                self.metrics.send_errors += 1;
+                io::Error::other(why.err_msg)
             })
     }
 
@@ -472,3 +1211,254 @@ impl Write for SocketIo {
         Ok(())
     }
 }
+
+/// A task replacing the IP filter of `acceptor` once run on the loop it is registered with.
+///
+/// Peers already accepted are left alone.
+pub fn update_ip_filter<C>(
+    acceptor: ChannelId,
+    filter: IpFilter,
+) -> Work<'static, UdtSelector<C>, UdtKey<C>>
+where
+    C: Codec + 'static,
+{
+    Box::new(move |sys: &mut UdtSelector<C>, _: EventSender<UdtKey<C>>| {
+        match sys.registered.get_mut(&acceptor) {
+            Some(key) if key.ch.kind == ChannelKind::Acceptor => {
+                debug!("{:?} IP filter now {:?}", acceptor, filter);
+                key.ch.set_ip_filter(filter);
+            }
+            _ => warn!(
+                "{:?} is not a registered acceptor; IP filter unchanged",
+                acceptor
+            ),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel::{ChExt, ChWrite};
+    use ev_loop::{events, SelectorEventLoop, Trigger};
+    use futures::Stream;
+    use std::thread;
+
+    // A connected pair over loopback: the server's end with small buffers, so sends to it block
+    // until it reads, and a client channel wrapping the other end
+    fn pair() -> (UdtSocket, UdtChannel) {
+        udt::init();
+        let listener = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).expect("socket");
+        listener
+            .setsockopt(UdtOpts::UDT_RCVBUF, 64 * 1024)
+            .expect("rcvbuf");
+        listener
+            .setsockopt(UdtOpts::UDP_RCVBUF, 64 * 1024)
+            .expect("udp rcvbuf");
+        listener.bind("127.0.0.1:0".parse().unwrap()).expect("bind");
+        listener.listen(1).expect("listen");
+        let addr = listener.getsockname().expect("bound address");
+
+        let socket = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).expect("socket");
+        socket
+            .setsockopt(UdtOpts::UDT_SNDBUF, 64 * 1024)
+            .expect("sndbuf");
+        socket.connect(addr).expect("connect");
+        let (server, _) = listener.accept().expect("accept");
+        server
+            .setsockopt(UdtOpts::UDT_RCVSYN, false)
+            .expect("non-blocking");
+        listener.close().expect("close listener");
+
+        let kind = ChannelKind::Connector { remote: addr };
+        let mut ch = UdtChannel::new(socket, kind).expect("channel");
+        ch.state = ChannelState::Connected;
+        (server, ch)
+    }
+
+    fn write_interest(events: &[RWEvent<UdtKey>]) -> Option<bool> {
+        events.iter().rev().find_map(|ev| match *ev {
+            RWEvent::Registration(RegistrationEvent::Update(_, ops)) => Some(ops.has_write()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn blocked_sends_are_queued_until_flushed() {
+        let (server, mut ch) = pair();
+        let data: Vec<u8> = (0..512 * 1024).map(|i| i as u8).collect();
+        let mut events = Vec::new();
+        ch.write(Bytes::from(&data[..]), &mut events);
+        assert_eq!(ch.pending_writes(), 1);
+        assert_eq!(write_interest(&events), Some(true));
+        ch.write(Bytes::from(&b"tail"[..]), &mut events);
+        assert_eq!(ch.pending_writes(), 2);
+
+        let mut received = Vec::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while received.len() < data.len() + 4 {
+            assert!(
+                Instant::now() < deadline,
+                "received {} bytes",
+                received.len()
+            );
+            if let Ok(len) = server.recv(&mut buf, 64 * 1024) {
+                received.extend_from_slice(&buf[..len as usize]);
+            }
+            ch.flush(&mut events);
+        }
+        assert_eq!(&received[..data.len()], &data[..]);
+        assert_eq!(&received[data.len()..], b"tail");
+        assert_eq!(ch.pending_writes(), 0);
+        assert_eq!(write_interest(&events), Some(false));
+        assert!(!events.iter().any(|ev| matches!(*ev, RWEvent::Error(_))));
+        assert_eq!(ch.stats().io.send_errors, 0);
+        ch.close();
+        server.close().expect("close server");
+    }
+
+    #[test]
+    fn writes_behind_a_blocked_send_follow_the_write_policy() {
+        let (server, mut ch) = pair();
+        ch.options.write_policy = WritePolicy::Buffer { max_messages: 2 };
+        let data = vec![0u8; 512 * 1024];
+        let mut events = Vec::new();
+        ch.write(Bytes::from(data), &mut events);
+        assert_eq!(write_interest(&events), Some(true));
+        ch.write(Bytes::from(&b"queued"[..]), &mut events);
+        assert_eq!(ch.pending_writes(), 2);
+
+        events.clear();
+        ch.write(Bytes::from(&b"over the limit"[..]), &mut events);
+        assert_eq!(ch.pending_writes(), 2);
+        match events.as_slice() {
+            [RWEvent::Error(ErrorEvent::WriteRejected(id))] => assert_eq!(*id, ch.id()),
+            other => panic!("expected the write to be rejected, got {:?}", other),
+        }
+        ch.close();
+        server.close().expect("close server");
+    }
+
+    #[test]
+    fn max_bandwidth_must_be_positive() {
+        let cc = CongestionControl::max_bandwidth(1_000_000).expect("valid cap");
+        assert_eq!(cc.maxbw(), 1_000_000);
+        assert!(CongestionControl::max_bandwidth(0).is_err());
+        assert!(CongestionControl::max_bandwidth(-1).is_err());
+        assert_eq!(CongestionControl::Native.maxbw(), -1);
+    }
+
+    #[test]
+    fn filtered_peers_are_closed_before_registration() {
+        let selector = UdtSelector::new().expect("selector");
+        let listener = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).expect("socket");
+        listener.bind("127.0.0.1:0".parse().unwrap()).expect("bind");
+        listener.listen(1).expect("listen");
+        let addr = listener.getsockname().expect("bound address");
+        let acceptor = UdtChannel::new(listener, ChannelKind::Acceptor).expect("acceptor");
+        let id = acceptor.id();
+        let (mut ev_loop, tasks, events) = SelectorEventLoop::new(selector);
+        ev_loop.register(UdtKey::new(acceptor), Ops::ACCEPT);
+
+        // The filter is swapped on the loop the acceptor is registered with
+        let deny = IpFilter::new().with_deny("127.0.0.0/8".parse().expect("CIDR block"));
+        tasks
+            .send(update_ip_filter(id, deny.clone()))
+            .expect("loop running");
+        ev_loop.run_once(Duration::from_millis(0));
+        assert_eq!(ev_loop.selector().registered[&id].ch.ip_filter(), &deny);
+
+        let client = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).expect("socket");
+        client.connect(addr).expect("connect");
+        let peer = client.getsockname().expect("client address");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ev_loop.selector().snapshot().event_loop.rejected == 0 {
+            assert!(Instant::now() < deadline, "peer not rejected");
+            ev_loop.run_once(Duration::from_millis(1));
+        }
+        // Closed straight away rather than registered
+        assert_eq!(ev_loop.selector().registered.len(), 1);
+        assert_eq!(ev_loop.selector().snapshot().event_loop.accepted, 0);
+        assert_eq!(ev_loop.selector().snapshot().event_loop.closed, 0);
+        drop(ev_loop);
+        let events: Vec<_> = events.wait().map(|ev| ev.expect("event")).collect();
+        match events.as_slice() {
+            [Trigger::State(events::StateEvent::Rejected(acceptor, from, reason))] => {
+                assert_eq!((*acceptor, *from), (id, peer));
+                assert_eq!(*reason, RejectReason::Denied);
+            }
+            other => panic!("expected the peer to be rejected, got {:?}", other),
+        }
+        client.close().expect("close client");
+    }
+
+    // Sends `len` bytes over a fresh connection made with `cc`, returning how long the peer took
+    // to receive them
+    fn paced_transfer(cc: CongestionControl, len: usize) -> Duration {
+        udt::init();
+        let listener = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).expect("socket");
+        listener.bind("127.0.0.1:0".parse().unwrap()).expect("bind");
+        listener.listen(1).expect("listen");
+        let addr = listener.getsockname().expect("bound address");
+
+        let socket = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).expect("socket");
+        let kind = ChannelKind::Connector { remote: addr };
+        let options = ChannelOptions::default().with_congestion_control(cc);
+        let mut ch = UdtChannel::with_options(socket, kind, options).expect("channel");
+        ch.connect().expect("connect");
+        let (server, _) = listener.accept().expect("accept");
+        server
+            .setsockopt(UdtOpts::UDT_RCVSYN, false)
+            .expect("non-blocking");
+        listener.close().expect("close listener");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ch.finish_connect() != ChannelState::Connected {
+            assert!(Instant::now() < deadline, "not connected");
+            thread::sleep(Duration::from_millis(1));
+        }
+        // UDT keeps the controller a socket connected with
+        assert!(ch
+            .set_congestion_control(CongestionControl::Native)
+            .is_err());
+        assert_eq!(ch.congestion_control(), cc);
+
+        let started = Instant::now();
+        let mut events = Vec::new();
+        ch.write(Bytes::from(vec![7u8; len]), &mut events);
+        let mut received = 0;
+        let mut buf = vec![0u8; 64 * 1024];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while received < len {
+            assert!(Instant::now() < deadline, "received {} bytes", received);
+            if let Ok(n) = server.recv(&mut buf, 64 * 1024) {
+                received += n as usize;
+            }
+            ch.flush(&mut events);
+        }
+        let elapsed = started.elapsed();
+        ch.close();
+        server.close().expect("close server");
+        elapsed
+    }
+
+    #[test]
+    fn fixed_rate_paces_sends() {
+        assert!(CongestionControl::fixed_rate(0).is_err());
+        let slow = CongestionControl::fixed_rate(1_000_000).expect("valid rate");
+        let fast = CongestionControl::fixed_rate(4_000_000).expect("valid rate");
+        assert_eq!(slow.maxbw(), -1);
+
+        // 500KB takes about 500ms at 1MB/s and 125ms at 4MB/s, on top of the same start-up cost
+        let len = 500 * 1024;
+        let slow = paced_transfer(slow, len);
+        let fast = paced_transfer(fast, len);
+        assert!(slow >= Duration::from_millis(400), "took {:?}", slow);
+        assert!(
+            slow >= fast + Duration::from_millis(250),
+            "{:?} at 1MB/s, {:?} at 4MB/s",
+            slow,
+            fast
+        );
+    }
+}
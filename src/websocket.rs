@@ -0,0 +1,150 @@
+use async_io::close_when_flushed;
+use ev_loop::events;
+use ev_loop::{EventReceiver, EventSender, TaskSender, Trigger};
+use futures::Stream;
+use http::write;
+use selector::{Selector, SelectorKey};
+
+pub use codec::websocket::{
+    CloseFrame, Message, Role, WebSocketCodec, WebSocketEvent, WebSocketServerCodec,
+};
+
+/// Produces the reply, if any, to every text or binary message.
+pub trait Handler {
+    fn handle(&mut self, msg: &Message) -> Option<Message>;
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(&Message) -> Option<Message>,
+{
+    fn handle(&mut self, msg: &Message) -> Option<Message> {
+        self(msg)
+    }
+}
+
+/// Accepts upgrades and answers messages arriving on `events` with `handler` until the event
+/// loop goes away.
+///
+/// Meant for channels created with a `WebSocketServerCodec`. Pings are answered with pongs, and a
+/// close frame is echoed before the channel is closed. Requests that aren't upgrades and frames
+/// the codec can't decode are answered with an error response or close frame, then the channel
+/// is closed.
+pub fn serve<S, K, H>(events: EventReceiver<K>, tasks: &TaskSender<S, K>, mut handler: H)
+where
+    S: Selector<K> + 'static,
+    K: SelectorKey<Inbound = WebSocketEvent, Outbound = WebSocketEvent> + 'static,
+    K::Resource: Send,
+    H: Handler,
+{
+    for ev in events.wait() {
+        let (resource, reply, closing) = match ev {
+            Ok(Trigger::Read(events::ReadEvent::Data(resource, event))) => match event {
+                WebSocketEvent::Handshake(request) => {
+                    trace!("{:?} upgrading {}", resource, request.uri);
+                    (resource, Some(WebSocketEvent::Handshake(request)), false)
+                }
+                WebSocketEvent::Message(msg) => {
+                    let (reply, closing) = match msg {
+                        Message::Ping(_) => (msg.pong_reply(), false),
+                        Message::Close(_) => (msg.close_reply(), true),
+                        Message::Pong(_) => continue,
+                        _ => (handler.handle(&msg), false),
+                    };
+                    (resource, reply.map(WebSocketEvent::Message), closing)
+                }
+                WebSocketEvent::Fail(_) => continue,
+            },
+            Ok(Trigger::Error(events::ErrorEvent::Codec(resource, why))) => {
+                debug!("{:?} failing WebSocket connection: {}", resource, why);
+                (resource, Some(WebSocketEvent::Fail(why)), true)
+            }
+            Ok(_) => continue,
+            Err(()) => break,
+        };
+        if reply.is_none() && !closing {
+            continue;
+        }
+        let task = Box::new(move |sys: &mut S, events: EventSender<K>| {
+            let written = reply.is_none_or(|reply| write(sys, events.clone(), &resource, reply));
+            if !written || closing {
+                close_when_flushed(sys, &events, &resource);
+            }
+        });
+        if tasks.send(task).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use channel::ChannelId;
+    use codec::http::{HttpServerCodec, Response};
+    use codec::{CodecError, Encoder};
+    use ev_loop::SelectorEventLoop;
+    use ops::Ops;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use transport::embedded::EmbeddedChannel;
+    use transport::mock::MockSelector;
+
+    type Key = EmbeddedChannel<WebSocketServerCodec>;
+    type Loop = SelectorEventLoop<MockSelector<Key>, Key>;
+
+    // Serves `inbound` on a fresh channel until the reply task has run, then hands the loop and
+    // channel to `check`
+    fn exchange<F>(inbound: &'static [u8], check: F)
+    where
+        F: FnOnce(&mut Loop, ChannelId),
+    {
+        let (mut ev_loop, tasks, events) = SelectorEventLoop::new(MockSelector::new());
+        let mut ch = EmbeddedChannel::new(WebSocketServerCodec::new(256, 64));
+        ch.push_inbound(inbound);
+        let id = ch.id();
+        ev_loop.register(ch, Ops::READ);
+        ev_loop.selector_mut().push_ready(vec![(id, Ops::READ)]);
+        let server =
+            thread::spawn(move || serve(events, &tasks, |msg: &Message| Some(msg.clone())));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ev_loop.run_once(Duration::from_millis(1)).tasks == 0 {
+            assert!(Instant::now() < deadline, "no reply");
+        }
+        check(&mut ev_loop, id);
+        drop(ev_loop);
+        server.join().expect("server thread");
+    }
+
+    #[test]
+    fn accepts_upgrade_requests() {
+        let request = b"GET /chat HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n";
+        exchange(request, |ev_loop, id| {
+            let key = ev_loop
+                .selector_mut()
+                .key_mut(&id)
+                .expect("still registered");
+            let wire = key.read_outbound().expect("response");
+            assert!(wire.starts_with(b"HTTP/1.1 101 "));
+        });
+    }
+
+    #[test]
+    fn refused_upgrades_are_answered_and_closed() {
+        exchange(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n", |ev_loop, id| {
+            assert!(ev_loop.selector().key(&id).is_none());
+            assert_eq!(ev_loop.selector_mut().metrics().closed, 1);
+            let why = CodecError::MalformedHttp("not a WebSocket upgrade request");
+            let mut expected = BytesMut::new();
+            let mut codec = HttpServerCodec::new(64, 64);
+            codec
+                .encode(Response::for_error(&why), &mut expected)
+                .expect("encodable");
+            let written = ev_loop.selector().snapshot().bytes_written();
+            assert_eq!(written, expected.len() as u64);
+        });
+    }
+}